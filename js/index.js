@@ -3,10 +3,23 @@
 import {
     Scene as IScene,
     Raytracer as IRaytracer,
+    Camera as ICamera,
+    ProjectionKind as IProjectionKind,
     Color as IColor,
     Material as IMaterial,
     Primitive as IPrimitive,
+    Mesh as IMesh,
+    TileOrder as ITileOrder,
+    SamplePattern as ISamplePattern,
+    PixelFilter as IPixelFilter,
+    Integrator as IIntegrator,
+    Texture as ITexture,
+    WrapMode as IWrapMode,
+    TextureFilter as ITextureFilter,
+    TextureSpace as ITextureSpace,
+    JobState,
 } from '../lucis/pkg'
+import { RenderPool } from './pool'
 import * as ace from 'brace'
 import 'brace/mode/javascript'
 import 'brace/theme/tomorrow_night_eighties'
@@ -20,7 +33,7 @@ editor.session.addGutterDecoration
 let iScene = new IScene()
 let ia = iScene.create_node('a')
 let ir = new IRaytracer(iScene)
-ir.render(1, 1)
+ir.render(1, 1).free()
 ia.free()
 ir.free()
 
@@ -41,18 +54,122 @@ function getLineNumber(err) {
     }
 }
 
+// Time spent tracing tiles before handing control back to the browser
+const FRAME_BUDGET_MS = 30
+
+// Workers to trace tiles on, rendering stays on the main thread without them
+const pool = (window.Worker && navigator.hardwareConcurrency > 1)
+    ? new RenderPool(navigator.hardwareConcurrency)
+    : null
+
+// The render job currently being drawn to the canvas, if any
+let currentJob = null
+// The script currently being run, workers build their copy of the scene from it
+let currentSource = ""
+// Files picked with the upload button by name, scripts read them like
+// `Mesh.from_obj(files['teapot.obj'])`, `Mesh.from_ply(files['scan.ply'])` or
+// `scene.create_gltf_node(files['model.glb'], 'model')`
+let files = {}
+// Models read as bytes rather than text
+const BINARY_FILE = /\.(ply|stl|glb|gltf)$/i
+
+function cancelCurrentJob() {
+    if (currentJob) {
+        currentJob.cancel()
+    }
+}
+
+// Render a job a few tiles per animation frame, drawing the partial image as it goes.
+// Tiles are traced by the worker pool when there is one, otherwise on this thread
+function show(job, canvasId = 'canvas') {
+    cancelCurrentJob()
+    currentJob = job
+    if (pool) {
+        pool.start(job, currentSource, files)
+    }
+    let progressNode = document.getElementById("progress")
+    let pauseBtn = document.getElementById("btn-pause")
+    let frame = () => {
+        // Workers that can't build the scene are retired, without any left the tiles are
+        // traced here
+        if (pool && pool.usable) {
+            pool.pump()
+        } else {
+            let start = performance.now()
+            while (job.state === JobState.Running && performance.now() - start < FRAME_BUDGET_MS) {
+                let tile = job.step()
+                if (tile) {
+                    tile.free()
+                }
+            }
+        }
+        job.present_to(canvasId)
+        progressNode.value = job.progress()
+        pauseBtn.innerText = job.state === JobState.Paused ? "Resume" : "Pause"
+        if (job.is_finished()) {
+            if (pool) {
+                pool.stop(job)
+            }
+            if (currentJob === job) {
+                currentJob = null
+            }
+            job.free()
+        } else {
+            requestAnimationFrame(frame)
+        }
+    }
+    requestAnimationFrame(frame)
+}
+
+document.getElementById("btn-pause").addEventListener('click', () => {
+    if (!currentJob) {
+        return
+    }
+    if (currentJob.state === JobState.Paused) {
+        currentJob.resume()
+    } else {
+        currentJob.pause()
+    }
+})
+document.getElementById("btn-cancel").addEventListener('click', cancelCurrentJob)
+
+document.getElementById("file-upload").addEventListener('change', (e) => {
+    for (let file of e.target.files) {
+        if (BINARY_FILE.test(file.name)) {
+            file.arrayBuffer().then((buffer) => { files[file.name] = new Uint8Array(buffer) })
+        } else {
+            file.text().then((text) => { files[file.name] = text })
+        }
+    }
+})
+
+// Editing the scene makes the image on screen stale, so stop rendering it
+editor.on('change', cancelCurrentJob)
+
 let renderBtn = document.getElementById("btn-render")
 renderBtn.addEventListener('click', () => {
     let Scene = IScene;
     let Raytracer = IRaytracer;
+    let Camera = ICamera;
+    let ProjectionKind = IProjectionKind;
     let Color = IColor;
     let Material = IMaterial;
     let Primitive = IPrimitive;
+    let Mesh = IMesh;
+    let TileOrder = ITileOrder;
+    let SamplePattern = ISamplePattern;
+    let PixelFilter = IPixelFilter;
+    let Integrator = IIntegrator;
+    let Texture = ITexture;
+    let WrapMode = IWrapMode;
+    let TextureFilter = ITextureFilter;
+    let TextureSpace = ITextureSpace;
     let errorTextNode = document.getElementById("error-text")
     try {
         errorTextNode.innerText = ""
         editor.getSession().clearAnnotations();
-        eval(editor.getSession().getValue())
+        currentSource = editor.getSession().getValue()
+        eval(currentSource)
     } catch(err) {
         let lineNumber = getLineNumber(err)
         if (lineNumber) {
@@ -83,4 +200,4 @@ renderBtn.addEventListener('click', () => {
 // // b.add_child(c)
 
 // let raytracer = new Raytracer(test_scene)
-// raytracer.render(4000, 4000)
+// raytracer.render(4000, 4000).present_to('canvas')
@@ -0,0 +1,123 @@
+import RenderWorker from 'worker-loader!./worker.js'
+import { Tile } from '../lucis/pkg'
+
+// A pool of web workers that trace the tiles of a RenderJob in parallel.
+//
+// wasm instances can't share a scene, so every worker runs the editor script to build
+// its own copy and the main thread only hands out tiles and collects the pixels. Pixels
+// come back as transferred buffers rather than through a SharedArrayBuffer, since that
+// needs cross-origin isolation headers that GitHub pages can't serve.
+export class RenderPool {
+    constructor(size) {
+        this.workers = []
+        for (let i = 0; i < size; i++) {
+            let w = { worker: new RenderWorker(), ready: false, busy: null, failed: false }
+            w.worker.onmessage = (e) => this.onMessage(w, e.data)
+            w.worker.onerror = (e) => this.onCrash(w, e.message)
+            this.workers.push(w)
+        }
+        this.run = null
+        this.nextRunId = 0
+    }
+
+    get size() {
+        return this.workers.length
+    }
+
+    // Whether any worker can still trace tiles for the current job, the main thread has to
+    // render it itself once none can
+    get usable() {
+        return this.workers.some((w) => !w.failed)
+    }
+
+    // Start tracing tiles for `job`, `source` is the script the job was built from and
+    // `files` the uploaded files it can read
+    start(job, source, files) {
+        this.run = { id: ++this.nextRunId, job }
+        for (let w of this.workers) {
+            w.ready = false
+            w.busy = null
+            w.failed = false
+            w.worker.postMessage({ type: 'scene', run: this.run.id, source, files })
+        }
+    }
+
+    // Stop handing out tiles, results still in flight are dropped
+    stop(job) {
+        if (this.run && this.run.job === job) {
+            this.run = null
+        }
+    }
+
+    // Hand a tile to every idle worker. Paused jobs don't hand out tiles, so this is also
+    // called every frame to pick a job back up once it is resumed
+    pump() {
+        if (!this.run) {
+            return
+        }
+        for (let w of this.workers) {
+            if (!w.ready || w.busy || w.failed) {
+                continue
+            }
+            let tile = this.run.job.take_tile()
+            if (!tile) {
+                return
+            }
+            // Keep the tile's bounds so it can be traced again if the worker dies
+            w.busy = { x: tile.x, y: tile.y, width: tile.width, height: tile.height }
+            w.worker.postMessage({
+                type: 'tile',
+                run: this.run.id,
+                x: tile.x,
+                y: tile.y,
+                width: tile.width,
+                height: tile.height,
+            })
+            tile.free()
+        }
+    }
+
+    onMessage(w, msg) {
+        // Anything from an older run belongs to a job that has been cancelled or freed
+        if (!this.run || msg.run !== this.run.id) {
+            return
+        }
+        switch (msg.type) {
+        case 'ready':
+            w.ready = true
+            break
+        case 'tile':
+            let tile = new Tile(msg.x, msg.y, msg.width, msg.height)
+            try {
+                this.run.job.complete_tile(tile, new Float32Array(msg.pixels))
+                w.busy = null
+            } catch (e) {
+                // The job refuses tiles that don't fit, the worker is retired like one that
+                // crashed and its tile traced here
+                this.onCrash(w, e)
+            } finally {
+                tile.free()
+            }
+            break
+        case 'error':
+            console.error("Render worker failed to build the scene:", msg.message)
+            w.failed = true
+            break
+        }
+        this.pump()
+    }
+
+    // A worker that throws while tracing is retired, the tile it had is traced here instead
+    // so the job can still finish
+    onCrash(w, message) {
+        console.error("Render worker crashed:", message)
+        w.failed = true
+        let lost = w.busy
+        w.busy = null
+        if (lost && this.run) {
+            let tile = new Tile(lost.x, lost.y, lost.width, lost.height)
+            this.run.job.complete_tile(tile, this.run.job.trace_tile(tile))
+            tile.free()
+        }
+    }
+}
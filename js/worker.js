@@ -0,0 +1,72 @@
+// Render worker, builds its own copy of the scene from the editor script and traces
+// the tiles the main thread hands it.
+
+const lucis = import('../lucis/pkg')
+
+// The job built from the most recent script, tagged with the id of its run
+let current = null
+
+function buildJob(lucis, source, files) {
+    let Scene = lucis.Scene;
+    let Raytracer = lucis.Raytracer;
+    let Camera = lucis.Camera;
+    let ProjectionKind = lucis.ProjectionKind;
+    let Color = lucis.Color;
+    let Material = lucis.Material;
+    let Primitive = lucis.Primitive;
+    let Mesh = lucis.Mesh;
+    let TileOrder = lucis.TileOrder;
+    let SamplePattern = lucis.SamplePattern;
+    let PixelFilter = lucis.PixelFilter;
+    let Integrator = lucis.Integrator;
+    let Texture = lucis.Texture;
+    let WrapMode = lucis.WrapMode;
+    let TextureFilter = lucis.TextureFilter;
+    let TextureSpace = lucis.TextureSpace;
+    let job = null
+    // The script hands its job to `show`, keep it instead of drawing it
+    let show = (j) => { job = j }
+    eval(source)
+    if (!job) {
+        throw new Error("script did not call show()")
+    }
+    return job
+}
+
+function handle(lucis, msg) {
+    switch (msg.type) {
+    case 'scene':
+        if (current) {
+            current.job.free()
+            current = null
+        }
+        try {
+            current = { run: msg.run, job: buildJob(lucis, msg.source, msg.files) }
+            postMessage({ type: 'ready', run: msg.run })
+        } catch (err) {
+            postMessage({ type: 'error', run: msg.run, message: err.toString() })
+        }
+        break
+    case 'tile':
+        if (!current || current.run !== msg.run) {
+            return
+        }
+        let tile = new lucis.Tile(msg.x, msg.y, msg.width, msg.height)
+        let pixels = current.job.trace_tile(tile)
+        tile.free()
+        postMessage({
+            type: 'tile',
+            run: msg.run,
+            x: msg.x,
+            y: msg.y,
+            width: msg.width,
+            height: msg.height,
+            pixels: pixels.buffer,
+        }, [pixels.buffer])
+        break
+    }
+}
+
+onmessage = (e) => {
+    lucis.then((lucis) => handle(lucis, e.data))
+}
@@ -0,0 +1,113 @@
+use lucis::description::SceneDescription;
+use lucis::Framebuffer;
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+const USAGE: &str =
+    "usage: lucis <scene.json> <output.png|output.ppm> [--size WIDTHxHEIGHT] [--threads N]";
+const TILE_SIZE: u32 = 32;
+
+struct Options {
+    scene_file: String,
+    output_file: String,
+    size: Option<(u32, u32)>,
+    // Defaults to one thread per core
+    threads: Option<usize>,
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), Box<dyn Error>> {
+    let mut parts = s.split('x');
+    let width = parts.next().ok_or("Incorrect size format")?.parse()?;
+    let height = parts.next().ok_or("Incorrect size format")?.parse()?;
+    if parts.next().is_some() {
+        return Err("Incorrect size format".into());
+    }
+    if width == 0 || height == 0 {
+        return Err(format!("the image can't be {}x{}, both sides need at least one pixel", width, height).into());
+    }
+    Ok((width, height))
+}
+
+fn parse_args() -> Result<Options, Box<dyn Error>> {
+    let mut positional = vec![];
+    let mut size = None;
+    let mut threads = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--size" => size = Some(parse_size(&args.next().ok_or("--size needs a value")?)?),
+            "--threads" => threads = Some(args.next().ok_or("--threads needs a value")?.parse()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        return Err(USAGE.into());
+    }
+    let output_file = positional.pop().unwrap();
+    let scene_file = positional.pop().unwrap();
+    Ok(Options {
+        scene_file,
+        output_file,
+        size,
+        threads,
+    })
+}
+
+fn write_ppm(framebuffer: &Framebuffer, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write!(w, "P6\n{} {}\n255\n", framebuffer.width(), framebuffer.height())?;
+    for pixel in framebuffer.as_bytes().chunks(4) {
+        w.write_all(&pixel[..3])?;
+    }
+    Ok(())
+}
+
+fn write_png(framebuffer: &Framebuffer, path: &Path) -> Result<(), Box<dyn Error>> {
+    let w = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(w, framebuffer.width(), framebuffer.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()?
+        .write_image_data(framebuffer.as_bytes())?;
+    Ok(())
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let options = parse_args()?;
+    if let Some(threads) = options.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()?;
+    }
+    let contents = fs::read_to_string(&options.scene_file)?;
+    let description = SceneDescription::from_json(&contents)?;
+    let (width, height) = options
+        .size
+        .unwrap_or((description.width, description.height));
+
+    let raytracer = description.build()?;
+    let framebuffer = raytracer.render_parallel(width, height, TILE_SIZE)?;
+
+    let path = Path::new(&options.output_file);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => write_png(&framebuffer, path),
+        Some("ppm") => write_ppm(&framebuffer, path),
+        _ => Err(format!("unsupported output format for '{}', use .png or .ppm", path.display()).into()),
+    }
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("lucis: {}", e);
+        process::exit(1);
+    }
+}
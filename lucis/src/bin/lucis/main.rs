@@ -0,0 +1,16 @@
+// Native command line renderer for lucis scenes
+//
+// Usage: lucis <scene.json> <output.png|output.ppm> [--size WIDTHxHEIGHT] [--threads N]
+
+// Scenes are read from and written to files and rendered on a thread pool, which the
+// browser build has neither of
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    cli::main()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
@@ -0,0 +1,322 @@
+use crate::geometry::Ray;
+use crate::projection::{Equirectangular, Fisheye, Orthographic, Perspective, Projection, ProjectionKind};
+use nalgebra::{Point3, Rotation3, Unit, Vector3};
+use std::f32;
+use wasm_bindgen::prelude::*;
+
+// Closest the camera can get to looking straight along its up vector while orbiting
+const MIN_POLAR_ANGLE: f32 = 0.01;
+// Closest the camera can be dollied towards its target
+const MIN_TARGET_DISTANCE: f32 = 0.01;
+const FOCUS_EPS: f32 = 0.0001;
+// Below this the up vector is taken to be parallel to the view direction
+const PARALLEL_EPS: f32 = 0.000001;
+
+// A camera looking from `eye` towards `target`, perspective by default
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Camera {
+    pub(crate) eye: Point3<f32>,
+    pub(crate) target: Point3<f32>,
+    pub(crate) up: Vector3<f32>,
+    // Vertical field of view, in degrees
+    pub(crate) fov_y: f32,
+    // Width / height of the image plane, when None the aspect of the rendered image is used
+    pub(crate) aspect: Option<f32>,
+    pub(crate) projection: ProjectionKind,
+    // Height of the visible area for orthographic projections, in world units
+    pub(crate) ortho_height: f32,
+    // Field of view across the image circle of fisheye projections, in degrees
+    pub(crate) fisheye_fov: f32,
+    // Radius of the lens, a zero aperture is a pinhole camera with everything in focus
+    pub(crate) aperture: f32,
+    // Distance from the eye to the plane in perfect focus, when None the target is in focus
+    pub(crate) focal_distance: Option<f32>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::new()
+    }
+}
+
+fn vector_from_slice(v: &[f32], name: &str) -> Result<Vector3<f32>, JsValue> {
+    match v {
+        [x, y, z] => Ok(Vector3::new(*x, *y, *z)),
+        _ => Err(JsValue::from_str(&format!(
+            "expected {} to have 3 components, got {}",
+            name,
+            v.len()
+        ))),
+    }
+}
+
+#[wasm_bindgen]
+impl Camera {
+    // A camera at the origin looking down -Z with a 30 degree field of view
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Camera {
+        Camera {
+            eye: Point3::new(0.0, 0.0, 0.0),
+            target: Point3::new(0.0, 0.0, -1.0),
+            up: Vector3::new(0.0, 1.0, 0.0),
+            fov_y: 30.0,
+            aspect: None,
+            projection: ProjectionKind::Perspective,
+            ortho_height: 2.0,
+            fisheye_fov: 180.0,
+            aperture: 0.0,
+            focal_distance: None,
+        }
+    }
+
+    // Point the camera, each argument is an [x, y, z] array
+    pub fn look_at(&mut self, eye: &[f32], target: &[f32], up: &[f32]) -> Result<(), JsValue> {
+        self.eye = Point3::from(vector_from_slice(eye, "eye")?);
+        self.target = Point3::from(vector_from_slice(target, "target")?);
+        self.up = vector_from_slice(up, "up")?;
+        Ok(())
+    }
+
+    // Set the vertical field of view, in degrees
+    pub fn set_fov(&mut self, fov_y: f32) {
+        self.fov_y = fov_y;
+    }
+
+    // Set the width / height ratio of the image plane, or leave it out to match the image
+    pub fn set_aspect(&mut self, aspect: Option<f32>) {
+        self.aspect = aspect;
+    }
+
+    pub fn set_projection(&mut self, projection: ProjectionKind) {
+        self.projection = projection;
+    }
+
+    pub fn set_ortho_height(&mut self, height: f32) {
+        self.ortho_height = height;
+    }
+
+    pub fn set_fisheye_fov(&mut self, fov: f32) {
+        self.fisheye_fov = fov;
+    }
+
+    // Set the radius of the lens, larger apertures give a shallower depth of field. With one
+    // sample per pixel the blur is noisy until enough progressive passes are averaged
+    pub fn set_aperture(&mut self, aperture: f32) {
+        self.aperture = aperture.max(0.0);
+    }
+
+    // Set the distance to the plane in focus, or leave it out to focus on the target
+    pub fn set_focal_distance(&mut self, distance: Option<f32>) {
+        self.focal_distance = distance;
+    }
+
+    // Rotate the eye around the target, yaw turns around the up vector and a positive
+    // pitch raises the eye. Both are in degrees. An eye sitting on the target stays put
+    pub fn orbit(&mut self, yaw: f32, pitch: f32) {
+        if (self.eye - self.target).try_normalize(0.0).is_none() {
+            return;
+        }
+        let up = Unit::new_normalize(self.up);
+        let offset = Rotation3::from_axis_angle(&up, yaw.to_radians()) * (self.eye - self.target);
+
+        // Stop short of the poles, the view is undefined when looking along the up vector
+        let polar = offset.angle(&up);
+        let new_polar = (polar - pitch.to_radians())
+            .clamp(MIN_POLAR_ANGLE, f32::consts::PI - MIN_POLAR_ANGLE);
+        // An eye sitting right on a pole tips over towards the camera's right
+        let axis = offset.cross(&up);
+        let axis = if axis.norm_squared() > PARALLEL_EPS {
+            Unit::new_normalize(axis)
+        } else {
+            -Unit::new_normalize(self.basis().0)
+        };
+        let offset = Rotation3::from_axis_angle(&axis, polar - new_polar) * offset;
+
+        self.eye = self.target + offset;
+    }
+
+    // Move the eye towards the target, or away from it for negative distances. An eye
+    // sitting on the target has no direction to move in and stays put
+    pub fn dolly(&mut self, distance: f32) {
+        let offset = self.eye - self.target;
+        let direction = match offset.try_normalize(0.0) {
+            Some(direction) => direction,
+            None => return,
+        };
+        let new_distance = (offset.norm() - distance).max(MIN_TARGET_DISTANCE);
+        self.eye = self.target + direction * new_distance;
+    }
+
+    // Slide the eye and target together, across and up the image plane
+    pub fn pan(&mut self, dx: f32, dy: f32) {
+        let (right, up, _) = self.basis();
+        let delta = right * dx + up * dy;
+        self.eye += delta;
+        self.target += delta;
+    }
+
+    pub fn eye(&self) -> Vec<f32> {
+        self.eye.coords.iter().cloned().collect()
+    }
+
+    pub fn target(&self) -> Vec<f32> {
+        self.target.coords.iter().cloned().collect()
+    }
+
+    pub fn up(&self) -> Vec<f32> {
+        self.up.iter().cloned().collect()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn fov(&self) -> f32 {
+        self.fov_y
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn aspect(&self) -> Option<f32> {
+        self.aspect
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn projection(&self) -> ProjectionKind {
+        self.projection
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn aperture(&self) -> f32 {
+        self.aperture
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn focal_distance(&self) -> f32 {
+        self.focal_distance
+            .unwrap_or_else(|| (self.target - self.eye).norm())
+    }
+}
+
+impl Camera {
+    pub fn look_at_points(eye: Point3<f32>, target: Point3<f32>, up: Vector3<f32>) -> Camera {
+        Camera {
+            eye,
+            target,
+            up,
+            ..Camera::new()
+        }
+    }
+
+    // Thin lens model, move the ray's origin to a point on the lens and aim it at the point
+    // where the original ray crosses the plane in focus
+    fn apply_lens(&self, src: Point3<f32>, dir: Vector3<f32>, lens: (f32, f32)) -> (Point3<f32>, Vector3<f32>) {
+        if self.aperture <= 0.0 {
+            return (src, dir);
+        }
+
+        let focal_distance = self.focal_distance();
+        // Rays that don't head forward (wide fisheyes and panoramas) focus on a sphere instead
+        let focus = if dir.z < -FOCUS_EPS {
+            src + dir * (focal_distance / -dir.z)
+        } else {
+            src + dir.normalize() * focal_distance
+        };
+        let (lx, ly) = concentric_disk(lens.0, lens.1);
+        let lens_point = src + Vector3::new(lx * self.aperture, ly * self.aperture, 0.0);
+        (lens_point, focus - lens_point)
+    }
+
+    // The right, up and backwards unit vectors of the camera in world space
+    fn basis(&self) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        // An eye on the target looks down -Z, like a new camera
+        let w = (self.eye - self.target)
+            .try_normalize(0.0)
+            .unwrap_or_else(Vector3::z);
+        // Looking straight along the up vector leaves the roll undefined, so any axis across
+        // the view will do
+        let u = self.up.cross(&w);
+        let u = if u.norm_squared() > PARALLEL_EPS {
+            u.normalize()
+        } else {
+            let axis = if w.x.abs() < 0.9 { Vector3::x() } else { Vector3::y() };
+            axis.cross(&w).normalize()
+        };
+        let v = w.cross(&u);
+        (u, v, w)
+    }
+
+    // Build the ray through the point (x, y) of a width x height image, in pixels, leaving
+    // from the point on the lens picked by `lens`, a sample in [0, 1) x [0, 1). Returns None
+    // if the projection doesn't cover that point
+    pub(crate) fn primary_ray(&self, x: f32, y: f32, width: u32, height: u32, lens: (f32, f32)) -> Option<Ray> {
+        let fw = width as f32;
+        let fh = height as f32;
+        let aspect = self.aspect.unwrap_or(fw / fh);
+        let px = (x / fw) - 0.5;
+        let py = 0.5 - (y / fh);
+
+        let (src, dir) = match self.projection {
+            ProjectionKind::Perspective => Perspective { fov_y: self.fov_y }.project(px, py, aspect),
+            ProjectionKind::Orthographic => Orthographic {
+                height: self.ortho_height,
+            }
+            .project(px, py, aspect),
+            ProjectionKind::Fisheye => Fisheye {
+                fov: self.fisheye_fov,
+            }
+            .project(px, py, aspect),
+            ProjectionKind::Equirectangular => Equirectangular.project(px, py, aspect),
+        }?;
+        let (src, dir) = self.apply_lens(src, dir, lens);
+
+        // Move from camera space into world space
+        let (u, v, w) = self.basis();
+        Some(Ray::new(
+            self.eye + u * src.x + v * src.y + w * src.z,
+            u * dir.x + v * dir.y + w * dir.z,
+        ))
+    }
+}
+
+// Map a point in the unit square onto the unit disk, keeping stratified samples evenly spread
+// (Shirley and Chiu's concentric mapping)
+pub(crate) fn concentric_disk(u: f32, v: f32) -> (f32, f32) {
+    let a = 2.0 * u - 1.0;
+    let b = 2.0 * v - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finite(camera: &Camera) -> bool {
+        camera.eye.coords.iter().chain(camera.target.coords.iter()).all(|c| c.is_finite())
+    }
+
+    #[test]
+    fn eye_on_the_target_stays_defined() {
+        let point = Point3::new(1.0, 2.0, 3.0);
+        let mut camera = Camera::look_at_points(point, point, Vector3::y());
+        camera.orbit(30.0, 10.0);
+        camera.dolly(1.0);
+        camera.pan(0.5, 0.5);
+        assert!(finite(&camera));
+        let ray = camera.primary_ray(5.0, 5.0, 10, 10, (0.5, 0.5)).unwrap();
+        assert!(ray.dir.iter().all(|c| c.is_finite()));
+    }
+
+    #[test]
+    fn orbits_over_the_pole() {
+        let mut camera = Camera::look_at_points(Point3::new(0.0, 5.0, 0.0), Point3::origin(), Vector3::y());
+        camera.orbit(45.0, -30.0);
+        assert!(finite(&camera));
+        assert!(((camera.eye - camera.target).norm() - 5.0).abs() < 0.001);
+    }
+}
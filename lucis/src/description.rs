@@ -0,0 +1,1116 @@
+// The scene file format, a whole render described in JSON so it can be saved, shared and
+// rendered without any scripts
+//
+// {
+//   "width": 600, "height": 600, "samples_per_pixel": 16, "sample_pattern": "halton", "filter": "mitchell",
+//   "camera": { "eye": [0, 0, 0], "view": [0, 0, -1], "up": [0, 1, 0], "fov_y": 30 },
+//   "lights": [{ "color": [0.5, 0.5, 0.5], "position": [4, 5, 0] }],
+//   "volumes": [{ "position": [-2, -2, -14], "size": [4, 4, 4], "effect": { "fog": [0.8, 0.8, 0.9] } }],
+//   "root": {
+//     "name": "root",
+//     "children": [{
+//       "name": "face", "primitive": "sphere",
+//       "material": { "kd": [0.98, 0.9, 0.12], "ks": [1, 1, 1], "shininess": 20 },
+//       "transforms": [{ "scale": [2, 2, 2] }, { "rotate": ["x", 90] }, { "translate": [0, 0, -12] }]
+//     }]
+//   }
+// }
+
+use crate::geometry::volume::{BoxParams, ConeParams, Volume, VolumeEffect, VolumetricSolid};
+use crate::geometry::{Mesh, Primitive, DEFAULT_CREASE_ANGLE};
+use crate::scene::{
+    parse_mtl, Color, Light, Material, MaterialWrapper, Pattern, Scene, SceneNode, SceneNodeRef, Texture,
+    TextureFilter, TextureKind, TextureSpace, WrapMode,
+};
+use crate::raytrace::DEFAULT_IMAGE_SIZE;
+use crate::{Camera, Integrator, PixelFilter, ProjectionKind, Raytracer, SamplePattern};
+use nalgebra::{Affine3, Matrix4, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Soft lights place their samples on a square grid at least two points wide
+const MIN_SOFT_LIGHT_SAMPLES: u32 = 4;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SceneDescription {
+    #[serde(default = "default_size")]
+    pub width: u32,
+    #[serde(default = "default_size")]
+    pub height: u32,
+    #[serde(default)]
+    pub camera: CameraDescription,
+    #[serde(default = "default_ambient")]
+    pub ambient: [f32; 3],
+    #[serde(default = "default_samples")]
+    pub samples_per_pixel: u32,
+    #[serde(default)]
+    pub sample_pattern: SamplePattern,
+    #[serde(default)]
+    pub filter: PixelFilter,
+    // Only supersample pixels that differ from their neighbours by more than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive_threshold: Option<f32>,
+    #[serde(default = "default_max_depth")]
+    pub max_depth: u32,
+    #[serde(default = "default_min_contribution")]
+    pub min_contribution: f32,
+    #[serde(default)]
+    pub integrator: Integrator,
+    // Renders averaged together, on top of the samples per pixel
+    #[serde(default = "default_samples")]
+    pub passes: u32,
+    // When missing the raytracer's default light is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lights: Option<Vec<LightDescription>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<VolumeDescription>,
+    pub root: NodeDescription,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CameraDescription {
+    pub eye: [f32; 3],
+    pub view: [f32; 3],
+    pub up: [f32; 3],
+    pub fov_y: f32,
+    // Width / height of the image plane, defaults to the aspect of the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aspect: Option<f32>,
+    #[serde(default)]
+    pub projection: ProjectionKind,
+    #[serde(default = "default_ortho_height")]
+    pub ortho_height: f32,
+    #[serde(default = "default_fisheye_fov")]
+    pub fisheye_fov: f32,
+    // Lens radius for depth of field, zero keeps everything in focus
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub aperture: f32,
+    // Defaults to the distance from the eye to the view point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_distance: Option<f32>,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        CameraDescription::from(&Camera::new())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LightDescription {
+    pub color: [f32; 3],
+    pub position: [f32; 3],
+    #[serde(default = "default_falloff")]
+    pub falloff: [f32; 3],
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub radius: f32,
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+}
+
+// A box or cone that changes the color of rays passing through it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VolumeDescription {
+    #[serde(flatten)]
+    pub shape: VolumeShapeDescription,
+    pub effect: VolumeEffectDescription,
+}
+
+// { "position": [-2, -2, -14], "size": [4, 4, 4] } or { "cone": [{ "scale": [1, 15, 1] }] }
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum VolumeShapeDescription {
+    // The corner with the smallest coordinates
+    Box { position: [f32; 3], size: [f32; 3] },
+    // The cone x² + z² = y² between y = 0 and 3, moved into place like a node
+    Cone { cone: Vec<TransformDescription> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeEffectDescription {
+    // Fades what is behind towards the color, the longer the ray is inside the thicker
+    Fog([f32; 3]),
+    // Brightens rays passing through, like light shining through dust
+    Light([f32; 3]),
+    Solid([f32; 3]),
+    None,
+}
+
+// A Phong material, a metallic/roughness one when `base_color` is given or a dielectric
+// when `ior` is
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MaterialDescription {
+    #[serde(default, skip_serializing_if = "is_black")]
+    pub kd: [f32; 3],
+    #[serde(default, skip_serializing_if = "is_black")]
+    pub ks: [f32; 3],
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub shininess: f32,
+    #[serde(default, skip_serializing_if = "is_black")]
+    pub reflectivity: [f32; 3],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_color: Option<[f32; 3]>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub metallic: f32,
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ior: Option<f32>,
+    #[serde(default, skip_serializing_if = "is_black")]
+    pub absorption: [f32; 3],
+    // Light given off by the surface, makes the node an area light
+    #[serde(default, skip_serializing_if = "is_black")]
+    pub emission: [f32; 3],
+    // Replaces kd, or the base color
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diffuse_texture: Option<TextureDescription>,
+    // Replaces ks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub specular_texture: Option<TextureDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reflectivity_texture: Option<TextureDescription>,
+    // The brightness of these replaces the PBR values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metallic_texture: Option<TextureDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roughness_texture: Option<TextureDescription>,
+    // A height map, white is `bump_strength` world units above black
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bump_texture: Option<TextureDescription>,
+    #[serde(default = "default_bump_strength")]
+    pub bump_strength: f32,
+    // A tangent space normal map
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_texture: Option<TextureDescription>,
+    // Multiplies the emission
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emission_texture: Option<TextureDescription>,
+}
+
+// One of the kinds below, optionally moved around. For example
+//
+// { "marble": { "a": [0.9, 0.9, 0.85], "b": [0.2, 0.2, 0.3] }, "scale": [2, 2, 2] }
+// { "masked": { "a": { "solid": [1, 0, 0] }, "b": { "image": { "path": "wood.jpg" } },
+//               "mask": { "checker": { "even": [0, 0, 0], "odd": [1, 1, 1] } } } }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextureDescription {
+    #[serde(flatten)]
+    pub kind: TextureKindDescription,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<[f32; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<[f32; 3]>,
+    // Overrides where a procedural texture is evaluated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub space: Option<TextureSpace>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureKindDescription {
+    Solid([f32; 3]),
+    // A PNG or JPEG file relative to the working directory, or the pixels themselves as 4
+    // bytes per pixel. Only embedded pixels can be loaded in the browser
+    Image {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        #[serde(default, skip_serializing_if = "is_zero_size")]
+        width: u32,
+        #[serde(default, skip_serializing_if = "is_zero_size")]
+        height: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rgba: Option<Vec<u8>>,
+        #[serde(default)]
+        wrap: WrapMode,
+        #[serde(default)]
+        filter: TextureFilter,
+    },
+    Checker {
+        even: [f32; 3],
+        odd: [f32; 3],
+        #[serde(default = "default_checker_scale")]
+        scale: f32,
+    },
+    Stripes(PatternDescription),
+    Gradient(PatternDescription),
+    Noise(PatternDescription),
+    Fbm(PatternDescription),
+    Turbulence(PatternDescription),
+    Marble(PatternDescription),
+    Wood(PatternDescription),
+    Blend {
+        a: Box<TextureDescription>,
+        b: Box<TextureDescription>,
+        amount: f32,
+    },
+    Masked {
+        a: Box<TextureDescription>,
+        b: Box<TextureDescription>,
+        mask: Box<TextureDescription>,
+    },
+}
+
+// The two colors a procedural texture goes between
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatternDescription {
+    pub a: [f32; 3],
+    pub b: [f32; 3],
+    // Only used by fbm and turbulence
+    #[serde(default = "default_octaves")]
+    pub octaves: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransformDescription {
+    Scale([f32; 3]),
+    Rotate(String, f32),
+    Translate([f32; 3]),
+    // A whole transform, given as its rows
+    Matrix([[f32; 4]; 4]),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeDescription {
+    pub name: String,
+    #[serde(default)]
+    pub primitive: PrimitiveDescription,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub material: Option<MaterialDescription>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<TransformDescription>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<NodeDescription>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrimitiveDescription {
+    Sphere,
+    Cube,
+    Cylinder,
+    Cone,
+    Mesh(MeshDescription),
+    #[default]
+    None,
+}
+
+// An OBJ, PLY or STL file relative to the working directory, or the text of an OBJ file. Only
+// the text can be loaded in the browser. The MTL files an OBJ file names are read from next to
+// it, or the text of one can be given with mtl. Each of its materials then goes on a child
+// node. Faces are smoothed into each other unless they meet at more than crease_angle degrees,
+// 60 by default.
+// A .gltf or .glb file adds its whole node tree, with its own materials, under the node
+//
+// { "mesh": { "path": "teapot.obj", "crease_angle": 30 } }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MeshDescription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obj: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtl: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crease_angle: Option<f32>,
+}
+
+impl<'a> From<&'a Primitive> for PrimitiveDescription {
+    fn from(p: &'a Primitive) -> PrimitiveDescription {
+        match p {
+            Primitive::Sphere => PrimitiveDescription::Sphere,
+            Primitive::Cube => PrimitiveDescription::Cube,
+            Primitive::Cylinder => PrimitiveDescription::Cylinder,
+            Primitive::Cone => PrimitiveDescription::Cone,
+            Primitive::Mesh(mesh) => PrimitiveDescription::Mesh(match &mesh.path {
+                Some(path) => MeshDescription {
+                    path: Some(path.clone()),
+                    obj: None,
+                    mtl: None,
+                    crease_angle: describe_crease_angle(mesh.crease_angle),
+                },
+                // Meshes made from text carry it along
+                None => MeshDescription {
+                    path: None,
+                    obj: Some(mesh.to_obj_string()),
+                    mtl: None,
+                    crease_angle: describe_crease_angle(mesh.crease_angle),
+                },
+            }),
+            Primitive::None => PrimitiveDescription::None,
+        }
+    }
+}
+
+impl PrimitiveDescription {
+    fn build(&self) -> Result<Primitive, String> {
+        Ok(match self {
+            PrimitiveDescription::Sphere => Primitive::Sphere,
+            PrimitiveDescription::Cube => Primitive::Cube,
+            PrimitiveDescription::Cylinder => Primitive::Cylinder,
+            PrimitiveDescription::Cone => Primitive::Cone,
+            PrimitiveDescription::Mesh(m) => Primitive::Mesh(Arc::new(m.build()?)),
+            PrimitiveDescription::None => Primitive::None,
+        })
+    }
+}
+
+impl MeshDescription {
+    fn build(&self) -> Result<Mesh, String> {
+        let mut mesh = match (&self.obj, &self.path) {
+            (Some(obj), _) => Mesh::from_obj_str(obj)?,
+            (None, Some(path)) => open_mesh(path)?,
+            (None, None) => return Err("meshes need a path or obj text".to_string()),
+        };
+        if self.crease_angle.is_some() {
+            mesh.set_crease_angle(self.crease_angle);
+        }
+        Ok(mesh)
+    }
+
+    // The materials of the mesh by name, from mtl or else the MTL files next to the OBJ file
+    fn materials(&self, mesh: &Mesh) -> Result<Vec<(String, MaterialWrapper)>, String> {
+        if let Some(mtl) = &self.mtl {
+            return parse_mtl(mtl, |file| open_mtl_texture("", file)).map_err(|e| format!("mtl {}", e));
+        }
+        let dir = match &self.path {
+            Some(path) => parent_dir(path),
+            None => return Ok(vec![]),
+        };
+        let mut materials = vec![];
+        for library in mesh.material_libraries.iter() {
+            let path = format!("{}{}", dir, library);
+            let text = open_mtl(&path)?;
+            let texture_dir = parent_dir(&path);
+            let parsed = parse_mtl(&text, |file| open_mtl_texture(&texture_dir, file))
+                .map_err(|e| format!("material library '{}': {}", path, e))?;
+            materials.extend(parsed);
+        }
+        Ok(materials)
+    }
+}
+
+// Only crease angles other than the default are saved. Smoothing over every edge is the same
+// as a crease angle of 180 degrees
+fn describe_crease_angle(angle: Option<f32>) -> Option<f32> {
+    match angle {
+        Some(angle) if angle == DEFAULT_CREASE_ANGLE => None,
+        Some(angle) => Some(angle),
+        None => Some(180.0),
+    }
+}
+
+// Everything up to and including the last slash, so file names can be added on
+fn parent_dir(path: &str) -> String {
+    match path.rfind(['/', '\\']) {
+        Some(i) => path[..=i].to_string(),
+        None => String::new(),
+    }
+}
+
+fn default_size() -> u32 {
+    DEFAULT_IMAGE_SIZE
+}
+
+fn default_ortho_height() -> f32 {
+    2.0
+}
+
+fn default_fisheye_fov() -> f32 {
+    180.0
+}
+
+fn default_ambient() -> [f32; 3] {
+    [0.3, 0.3, 0.3]
+}
+
+fn default_falloff() -> [f32; 3] {
+    [1.0, 0.0, 0.0]
+}
+
+fn default_samples() -> u32 {
+    1
+}
+
+fn default_roughness() -> f32 {
+    0.5
+}
+
+fn default_checker_scale() -> f32 {
+    8.0
+}
+
+fn default_bump_strength() -> f32 {
+    0.05
+}
+
+fn default_octaves() -> u32 {
+    4
+}
+
+fn default_max_depth() -> u32 {
+    5
+}
+
+fn default_min_contribution() -> f32 {
+    0.01
+}
+
+fn is_zero(x: &f32) -> bool {
+    *x == 0.0
+}
+
+fn is_zero_size(x: &u32) -> bool {
+    *x == 0
+}
+
+fn is_black(c: &[f32; 3]) -> bool {
+    *c == [0.0, 0.0, 0.0]
+}
+
+fn color(c: [f32; 3]) -> Color {
+    Color::new(c[0], c[1], c[2])
+}
+
+fn rgb(c: Color) -> [f32; 3] {
+    [c.r, c.g, c.b]
+}
+
+fn xyz(v: &Vector3<f32>) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn rows(m: &Matrix4<f32>) -> [[f32; 4]; 4] {
+    let mut rows = [[0.0; 4]; 4];
+    for (r, row) in rows.iter_mut().enumerate() {
+        for (c, x) in row.iter_mut().enumerate() {
+            *x = m[(r, c)];
+        }
+    }
+    rows
+}
+
+// The transforms applied one after the other, as a single matrix
+fn transform_matrix(transforms: &[TransformDescription]) -> Result<Matrix4<f32>, String> {
+    let mut matrix = Matrix4::identity();
+    for transform in transforms.iter() {
+        let t = match transform {
+            TransformDescription::Scale(s) => Matrix4::new_nonuniform_scaling(&Vector3::from(*s)),
+            TransformDescription::Translate(t) => Matrix4::new_translation(&Vector3::from(*t)),
+            TransformDescription::Rotate(axis, angle) => {
+                let axis = match axis.as_str() {
+                    "x" | "X" => Vector3::x_axis(),
+                    "y" | "Y" => Vector3::y_axis(),
+                    "z" | "Z" => Vector3::z_axis(),
+                    _ => return Err(format!("unknown rotation axis '{}'", axis)),
+                };
+                Matrix4::from_axis_angle(&axis, angle.to_radians())
+            }
+            TransformDescription::Matrix(rows) => Matrix4::from_fn(|r, c| rows[r][c]),
+        };
+        matrix = t * matrix;
+    }
+    Ok(matrix)
+}
+
+impl SceneDescription {
+    pub fn from_json(json: &str) -> Result<SceneDescription, String> {
+        let description: SceneDescription =
+            serde_json::from_str(json).map_err(|e| format!("invalid scene: {}", e))?;
+        if description.width == 0 || description.height == 0 {
+            return Err(format!(
+                "invalid scene: the image needs at least one pixel, got {}x{}",
+                description.width, description.height
+            ));
+        }
+        Ok(description)
+    }
+
+    pub fn to_json(&self) -> String {
+        // Nothing in a description can fail to serialize
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // Describe a raytracer and the scene it renders
+    pub fn from_raytracer(raytracer: &Raytracer) -> SceneDescription {
+        SceneDescription {
+            width: raytracer.width,
+            height: raytracer.height,
+            camera: CameraDescription::from(&raytracer.camera),
+            ambient: rgb(raytracer.ambient),
+            samples_per_pixel: raytracer.samples_per_pixel,
+            sample_pattern: raytracer.sample_pattern,
+            filter: raytracer.filter,
+            adaptive_threshold: raytracer.adaptive_threshold,
+            max_depth: raytracer.max_depth,
+            min_contribution: raytracer.min_contribution,
+            integrator: raytracer.integrator,
+            passes: raytracer.passes,
+            lights: Some(raytracer.lights.iter().map(LightDescription::from).collect()),
+            volumes: raytracer.volumes.iter().map(VolumeDescription::from_volume).collect(),
+            root: NodeDescription::from_scene(&raytracer.scene),
+        }
+    }
+
+    // Describe only the scene graph, everything else is left at its defaults
+    pub fn from_scene(scene: &Scene) -> SceneDescription {
+        let mut description = SceneDescription::from_raytracer(&Raytracer::new(scene.clone()));
+        description.lights = None;
+        description
+    }
+
+    // Build the scene graph and a raytracer configured to render it
+    pub fn build(&self) -> Result<Raytracer, String> {
+        let mut raytracer = Raytracer::new(self.build_scene()?);
+        raytracer.set_camera(&self.camera.build());
+        raytracer.set_size(self.width, self.height);
+        raytracer.set_samples_per_pixel(self.samples_per_pixel);
+        raytracer.set_sample_pattern(self.sample_pattern);
+        raytracer.set_filter(self.filter);
+        raytracer.set_adaptive(self.adaptive_threshold);
+        raytracer.set_max_depth(self.max_depth);
+        raytracer.set_min_contribution(self.min_contribution);
+        raytracer.set_integrator(self.integrator);
+        raytracer.set_passes(self.passes);
+        raytracer.set_ambient(color(self.ambient));
+        if let Some(lights) = &self.lights {
+            let lights = lights
+                .iter()
+                .enumerate()
+                .map(|(i, light)| light.build().map_err(|e| format!("light {}: {}", i, e)))
+                .collect::<Result<Vec<Light>, String>>()?;
+            raytracer.set_lights(lights);
+        }
+        let volumes = self
+            .volumes
+            .iter()
+            .enumerate()
+            .map(|(i, volume)| volume.build().map_err(|e| format!("volume {}: {}", i, e)))
+            .collect::<Result<Vec<VolumetricSolid>, String>>()?;
+        raytracer.set_volumes(volumes);
+        Ok(raytracer)
+    }
+
+    // Build just the scene graph
+    pub fn build_scene(&self) -> Result<Scene, String> {
+        let mut scene = Scene::new();
+        self.root.build(&mut scene)?;
+        Ok(scene)
+    }
+}
+
+impl<'a> From<&'a Camera> for CameraDescription {
+    fn from(c: &'a Camera) -> CameraDescription {
+        CameraDescription {
+            eye: xyz(&c.eye.coords),
+            view: xyz(&c.target.coords),
+            up: xyz(&c.up),
+            fov_y: c.fov_y,
+            aspect: c.aspect,
+            projection: c.projection,
+            ortho_height: c.ortho_height,
+            fisheye_fov: c.fisheye_fov,
+            aperture: c.aperture,
+            focal_distance: c.focal_distance,
+        }
+    }
+}
+
+impl CameraDescription {
+    fn build(&self) -> Camera {
+        let mut camera = Camera::look_at_points(
+            Point3::from(Vector3::from(self.eye)),
+            Point3::from(Vector3::from(self.view)),
+            Vector3::from(self.up),
+        );
+        camera.set_fov(self.fov_y);
+        camera.set_aspect(self.aspect);
+        camera.set_projection(self.projection);
+        camera.set_ortho_height(self.ortho_height);
+        camera.set_fisheye_fov(self.fisheye_fov);
+        camera.set_aperture(self.aperture);
+        camera.set_focal_distance(self.focal_distance);
+        camera
+    }
+}
+
+impl<'a> From<&'a Light> for LightDescription {
+    fn from(l: &'a Light) -> LightDescription {
+        LightDescription {
+            color: rgb(l.color),
+            position: xyz(&l.position.coords),
+            falloff: l.falloff,
+            radius: l.radius,
+            samples: l.num_samples,
+        }
+    }
+}
+
+impl LightDescription {
+    fn build(&self) -> Result<Light, String> {
+        if self.radius != 0.0 && self.samples != 1 && self.samples < MIN_SOFT_LIGHT_SAMPLES {
+            return Err(format!(
+                "lights with a radius need 1 or at least {} samples, got {}",
+                MIN_SOFT_LIGHT_SAMPLES, self.samples
+            ));
+        }
+        Ok(Light::new(
+            color(self.color),
+            Point3::from(Vector3::from(self.position)),
+            self.falloff,
+            self.radius,
+            self.samples,
+        ))
+    }
+}
+
+impl VolumeDescription {
+    fn from_volume(v: &VolumetricSolid) -> VolumeDescription {
+        let shape = match &v.volume {
+            Volume::Box(b) => VolumeShapeDescription::Box {
+                position: xyz(&b.pos),
+                size: xyz(&b.size),
+            },
+            Volume::Cone(c) => VolumeShapeDescription::Cone {
+                cone: vec![TransformDescription::Matrix(rows(c.transform.matrix()))],
+            },
+        };
+        let effect = match v.effect {
+            VolumeEffect::Fog(c) => VolumeEffectDescription::Fog(rgb(c)),
+            VolumeEffect::Light(c) => VolumeEffectDescription::Light(rgb(c)),
+            VolumeEffect::Solid(c) => VolumeEffectDescription::Solid(rgb(c)),
+            VolumeEffect::None => VolumeEffectDescription::None,
+        };
+        VolumeDescription { shape, effect }
+    }
+
+    fn build(&self) -> Result<VolumetricSolid, String> {
+        let effect = match self.effect {
+            VolumeEffectDescription::Fog(c) => VolumeEffect::Fog(color(c)),
+            VolumeEffectDescription::Light(c) => VolumeEffect::Light(color(c)),
+            VolumeEffectDescription::Solid(c) => VolumeEffect::Solid(color(c)),
+            VolumeEffectDescription::None => VolumeEffect::None,
+        };
+        let volume = match &self.shape {
+            VolumeShapeDescription::Box { position, size } => Volume::Box(BoxParams {
+                pos: Vector3::from(*position),
+                size: Vector3::from(*size),
+            }),
+            VolumeShapeDescription::Cone { cone } => Volume::Cone(ConeParams::from_matrix(transform_matrix(cone)?)),
+        };
+        Ok(VolumetricSolid::new(volume, effect))
+    }
+}
+
+impl MaterialDescription {
+    // None for nodes without a material of their own
+    fn from_node(node: &SceneNode) -> Option<MaterialDescription> {
+        let t = &node.textures;
+        let mut m = MaterialDescription {
+            kd: [0.0; 3],
+            ks: [0.0; 3],
+            shininess: 0.0,
+            reflectivity: [0.0; 3],
+            base_color: None,
+            metallic: 0.0,
+            roughness: default_roughness(),
+            ior: None,
+            absorption: [0.0; 3],
+            emission: rgb(node.emission),
+            diffuse_texture: t.diffuse.as_ref().map(TextureDescription::from),
+            specular_texture: t.specular.as_ref().map(TextureDescription::from),
+            reflectivity_texture: t.reflectivity.as_ref().map(TextureDescription::from),
+            metallic_texture: t.metallic.as_ref().map(TextureDescription::from),
+            roughness_texture: t.roughness.as_ref().map(TextureDescription::from),
+            bump_texture: t.bump.as_ref().map(|(b, _)| TextureDescription::from(b)),
+            bump_strength: t.bump.as_ref().map_or(default_bump_strength(), |(_, s)| *s),
+            normal_texture: t.normal.as_ref().map(TextureDescription::from),
+            emission_texture: t.emission.as_ref().map(TextureDescription::from),
+        };
+        match node.material {
+            Material::PhongMaterial {
+                kd,
+                ks,
+                shininess,
+                reflectivity,
+            } => {
+                m.kd = rgb(kd);
+                m.ks = rgb(ks);
+                m.shininess = shininess;
+                m.reflectivity = rgb(reflectivity);
+            }
+            Material::Pbr {
+                base_color,
+                metallic,
+                roughness,
+            } => {
+                m.base_color = Some(rgb(base_color));
+                m.metallic = metallic;
+                m.roughness = roughness;
+            }
+            Material::Dielectric { ior, absorption } => {
+                m.ior = Some(ior);
+                m.absorption = rgb(absorption);
+            }
+            Material::None => return None,
+        }
+        Some(m)
+    }
+
+    fn build(&self) -> Result<MaterialWrapper, String> {
+        let mut material = match (self.ior, self.base_color) {
+            (Some(ior), _) => MaterialWrapper::dielectric(ior, &color(self.absorption)),
+            (None, Some(base_color)) => MaterialWrapper::pbr(&color(base_color), self.metallic, self.roughness),
+            (None, None) => {
+                let mut material = MaterialWrapper::new(&color(self.kd), &color(self.ks), self.shininess);
+                material.set_reflectivity(&color(self.reflectivity));
+                material
+            }
+        };
+        material.set_emission(&color(self.emission));
+        if let Some(texture) = &self.diffuse_texture {
+            material.set_diffuse_texture(&texture.build()?);
+        }
+        if let Some(texture) = &self.specular_texture {
+            material.set_specular_texture(&texture.build()?);
+        }
+        if let Some(texture) = &self.reflectivity_texture {
+            material.set_reflectivity_texture(&texture.build()?);
+        }
+        if let Some(texture) = &self.metallic_texture {
+            material.set_metallic_texture(&texture.build()?);
+        }
+        if let Some(texture) = &self.roughness_texture {
+            material.set_roughness_texture(&texture.build()?);
+        }
+        if let Some(texture) = &self.bump_texture {
+            material.set_bump_texture(&texture.build()?, self.bump_strength);
+        }
+        if let Some(texture) = &self.normal_texture {
+            material.set_normal_texture(&texture.build()?);
+        }
+        if let Some(texture) = &self.emission_texture {
+            material.set_emission_texture(&texture.build()?);
+        }
+        Ok(material)
+    }
+}
+
+impl<'a> From<&'a Texture> for TextureDescription {
+    fn from(texture: &'a Texture) -> TextureDescription {
+        let mut space = None;
+        let kind = match &texture.kind {
+            TextureKind::Solid(c) => TextureKindDescription::Solid(rgb(*c)),
+            TextureKind::Image { image, wrap, filter } => match &image.path {
+                Some(path) => TextureKindDescription::Image {
+                    path: Some(path.clone()),
+                    width: 0,
+                    height: 0,
+                    rgba: None,
+                    wrap: *wrap,
+                    filter: *filter,
+                },
+                // Textures made from pixels in memory carry them along
+                None => TextureKindDescription::Image {
+                    path: None,
+                    width: image.width,
+                    height: image.height,
+                    rgba: Some(image.to_rgba()),
+                    wrap: *wrap,
+                    filter: *filter,
+                },
+            },
+            TextureKind::Pattern {
+                pattern,
+                space: s,
+                a,
+                b,
+            } => {
+                space = Some(*s);
+                let p = |octaves| PatternDescription {
+                    a: rgb(*a),
+                    b: rgb(*b),
+                    octaves,
+                };
+                match pattern {
+                    // The number of squares is kept in the scale below
+                    Pattern::Checker => TextureKindDescription::Checker {
+                        even: rgb(*a),
+                        odd: rgb(*b),
+                        scale: 1.0,
+                    },
+                    Pattern::Stripes => TextureKindDescription::Stripes(p(default_octaves())),
+                    Pattern::Gradient => TextureKindDescription::Gradient(p(default_octaves())),
+                    Pattern::Noise => TextureKindDescription::Noise(p(default_octaves())),
+                    Pattern::Fbm(octaves) => TextureKindDescription::Fbm(p(*octaves)),
+                    Pattern::Turbulence(octaves) => TextureKindDescription::Turbulence(p(*octaves)),
+                    Pattern::Marble => TextureKindDescription::Marble(p(default_octaves())),
+                    Pattern::Wood => TextureKindDescription::Wood(p(default_octaves())),
+                }
+            }
+            TextureKind::Mix { a, b, mask } => TextureKindDescription::Masked {
+                a: Box::new(TextureDescription::from(&**a)),
+                b: Box::new(TextureDescription::from(&**b)),
+                mask: Box::new(TextureDescription::from(&**mask)),
+            },
+        };
+        let scale = xyz(&texture.scale);
+        let offset = xyz(&texture.offset);
+        TextureDescription {
+            kind,
+            scale: if scale == [1.0; 3] { None } else { Some(scale) },
+            offset: if is_black(&offset) { None } else { Some(offset) },
+            space,
+        }
+    }
+}
+
+impl TextureDescription {
+    fn build(&self) -> Result<Texture, String> {
+        let mut texture = match &self.kind {
+            TextureKindDescription::Solid(c) => Texture::solid(&color(*c)),
+            TextureKindDescription::Image {
+                path,
+                width,
+                height,
+                rgba,
+                wrap,
+                filter,
+            } => {
+                let mut texture = match (rgba, path) {
+                    (Some(rgba), _) => Texture::from_rgba(*width, *height, rgba)?,
+                    (None, Some(path)) => open_texture(path)?,
+                    (None, None) => return Err("image textures need a path or rgba pixels".to_string()),
+                };
+                texture.set_wrap(*wrap);
+                texture.set_filter(*filter);
+                texture
+            }
+            TextureKindDescription::Checker { even, odd, scale } => {
+                Texture::checker(&color(*even), &color(*odd), *scale)
+            }
+            TextureKindDescription::Stripes(p) => Texture::stripes(&color(p.a), &color(p.b)),
+            TextureKindDescription::Gradient(p) => Texture::gradient(&color(p.a), &color(p.b)),
+            TextureKindDescription::Noise(p) => Texture::noise(&color(p.a), &color(p.b)),
+            TextureKindDescription::Fbm(p) => Texture::fbm(&color(p.a), &color(p.b), p.octaves),
+            TextureKindDescription::Turbulence(p) => Texture::turbulence(&color(p.a), &color(p.b), p.octaves),
+            TextureKindDescription::Marble(p) => Texture::marble(&color(p.a), &color(p.b)),
+            TextureKindDescription::Wood(p) => Texture::wood(&color(p.a), &color(p.b)),
+            TextureKindDescription::Blend { a, b, amount } => Texture::blend(&a.build()?, &b.build()?, *amount),
+            TextureKindDescription::Masked { a, b, mask } => Texture::masked(&a.build()?, &b.build()?, &mask.build()?),
+        };
+        if let Some([x, y, z]) = self.scale {
+            texture.set_scale(x, y, z);
+        }
+        if let Some([x, y, z]) = self.offset {
+            texture.set_offset(x, y, z);
+        }
+        if let Some(space) = self.space {
+            texture.set_space(space);
+        }
+        Ok(texture)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn open_texture(path: &str) -> Result<Texture, String> {
+    Texture::open(path)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn open_texture(path: &str) -> Result<Texture, String> {
+    Err(format!(
+        "can't read texture '{}' in the browser, give its pixels with width, height and rgba instead",
+        path
+    ))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn open_mesh(path: &str) -> Result<Mesh, String> {
+    Mesh::from_file(path)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn open_mesh(path: &str) -> Result<Mesh, String> {
+    Err(format!(
+        "can't read mesh '{}' in the browser, give the text of the OBJ file with obj instead",
+        path
+    ))
+}
+
+fn is_gltf(path: &str) -> bool {
+    let path = path.to_ascii_lowercase();
+    path.ends_with(".gltf") || path.ends_with(".glb")
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn open_gltf(scene: &mut Scene, path: &str, name: String) -> Result<SceneNodeRef, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("couldn't read model '{}': {}", path, e))?;
+    let dir = parent_dir(path);
+    let resolve = |uri: &str| {
+        let file = format!("{}{}", dir, uri);
+        std::fs::read(&file).map_err(|e| format!("couldn't read '{}': {}", file, e))
+    };
+    crate::scene::load_gltf(scene, &bytes, resolve, name).map_err(|e| format!("model '{}': {}", path, e))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn open_gltf(_scene: &mut Scene, path: &str, _name: String) -> Result<SceneNodeRef, String> {
+    Err(format!(
+        "can't read model '{}' in the browser, load it with Scene.create_gltf_node instead",
+        path
+    ))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn open_mtl(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|e| format!("couldn't read material library '{}': {}", path, e))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn open_mtl(path: &str) -> Result<String, String> {
+    Err(format!(
+        "can't read material library '{}' in the browser, give the text of the MTL file with mtl instead",
+        path
+    ))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn open_mtl_texture(dir: &str, file: &str) -> Result<Option<Texture>, String> {
+    Texture::open(&format!("{}{}", dir, file)).map(Some)
+}
+
+// There are no files to read the maps from in the browser, so materials go without them
+#[cfg(target_arch = "wasm32")]
+fn open_mtl_texture(_dir: &str, _file: &str) -> Result<Option<Texture>, String> {
+    Ok(None)
+}
+
+impl NodeDescription {
+    fn from_scene(scene: &Scene) -> NodeDescription {
+        let nodes = scene.nodes.read().unwrap();
+        if nodes.is_empty() {
+            return NodeDescription {
+                name: "root".to_string(),
+                primitive: PrimitiveDescription::None,
+                material: None,
+                transforms: vec![],
+                children: vec![],
+            };
+        }
+        NodeDescription::from_node(&nodes, scene.root_node)
+    }
+
+    fn from_node(nodes: &[SceneNode], id: usize) -> NodeDescription {
+        let n = &nodes[id];
+        let transforms = if n.transform == Affine3::identity() {
+            vec![]
+        } else {
+            vec![TransformDescription::Matrix(rows(n.transform.matrix()))]
+        };
+        NodeDescription {
+            name: n.name.clone(),
+            primitive: PrimitiveDescription::from(&n.primitive),
+            material: MaterialDescription::from_node(n),
+            transforms,
+            children: n.children.iter().map(|c| NodeDescription::from_node(nodes, *c)).collect(),
+        }
+    }
+
+    fn build(&self, scene: &mut Scene) -> Result<SceneNodeRef, String> {
+        let material = match &self.material {
+            Some(m) => Some(m.build()?),
+            None => None,
+        };
+        let error = |e: String| format!("node '{}': {}", self.name, e);
+        // Meshes with materials are split up so each part can have its own, the node's material
+        // goes on the parts the material libraries don't cover
+        let (mut node, material) = match &self.primitive {
+            PrimitiveDescription::Mesh(MeshDescription { path: Some(path), .. }) if is_gltf(path) => {
+                (open_gltf(scene, path, self.name.clone()).map_err(error)?, None)
+            }
+            PrimitiveDescription::Mesh(m) => {
+                let mesh = m.build().map_err(error)?;
+                let materials = m.materials(&mesh).map_err(error)?;
+                if materials.is_empty() {
+                    (scene.add_node(Primitive::Mesh(Arc::new(mesh)), self.name.clone()), material)
+                } else {
+                    let node = scene.add_mesh_nodes(mesh, &materials, material.as_ref(), self.name.clone());
+                    (node, None)
+                }
+            }
+            _ => (scene.add_node(self.primitive.build().map_err(error)?, self.name.clone()), material),
+        };
+        if let Some(m) = &material {
+            node.set_material(m);
+        }
+        if !self.transforms.is_empty() {
+            node.apply_matrix(transform_matrix(&self.transforms).map_err(error)?);
+        }
+        for child in self.children.iter() {
+            let child_node = child.build(scene)?;
+            node.add_child(&child_node);
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"{
+        "width": 320, "height": 240, "samples_per_pixel": 4, "sample_pattern": "sobol", "filter": "tent",
+        "max_depth": 3, "integrator": "path_tracer", "ambient": [0.1, 0.1, 0.2],
+        "camera": { "eye": [0, 1, 5], "view": [0, 0, 0], "up": [0, 1, 0], "fov_y": 40, "aperture": 0.1 },
+        "lights": [{ "color": [0.5, 0.5, 0.5], "position": [4, 5, 0], "radius": 0.5, "samples": 4 }],
+        "volumes": [
+            { "position": [-2, -2, -14], "size": [4, 4, 4], "effect": { "fog": [0.8, 0.8, 0.9] } },
+            { "cone": [{ "scale": [1, 15, 1] }, { "rotate": ["x", -90] }], "effect": { "light": [0.2, 0.2, 0.1] } }
+        ],
+        "root": {
+            "name": "root",
+            "children": [
+                {
+                    "name": "ball", "primitive": "sphere",
+                    "material": { "kd": [0.9, 0.2, 0.1], "ks": [1, 1, 1], "shininess": 20 },
+                    "transforms": [{ "scale": [2, 2, 2] }, { "rotate": ["x", 90] }, { "translate": [0, 0, -12] }]
+                },
+                {
+                    "name": "lamp", "primitive": "cube",
+                    "material": {
+                        "emission": [1, 0.9, 0.8],
+                        "emission_texture": { "checker": { "even": [0, 0, 0], "odd": [1, 1, 1] } }
+                    },
+                    "children": [{ "name": "glass", "primitive": "cylinder", "material": { "ior": 1.5 } }]
+                }
+            ]
+        }
+    }"#;
+
+    #[test]
+    fn saved_scenes_load_the_same() {
+        let description = SceneDescription::from_json(SCENE).unwrap();
+        let json = description.to_json();
+        assert_eq!(SceneDescription::from_json(&json).unwrap().to_json(), json);
+
+        // Through a raytracer too, where the nodes are saved as they were built
+        let saved = SceneDescription::from_raytracer(&SceneDescription::from_json(SCENE).unwrap().build().unwrap());
+        let json = saved.to_json();
+        assert!(json.contains("emission_texture"));
+        let loaded = SceneDescription::from_json(&json).unwrap().build().unwrap();
+        assert_eq!((loaded.width, loaded.height), (320, 240));
+        assert!(matches!(loaded.volumes[1].volume, Volume::Cone(_)));
+        assert_eq!(SceneDescription::from_raytracer(&loaded).to_json(), json);
+    }
+
+    #[test]
+    fn refuses_empty_images() {
+        for size in [r#""width": 0"#, r#""height": 0"#] {
+            let json = format!(r#"{{ {}, "root": {{ "name": "root" }} }}"#, size);
+            assert!(SceneDescription::from_json(&json).is_err(), "{}", size);
+        }
+        assert!(SceneDescription::from_json(r#"{ "width": 1, "height": 1, "root": { "name": "root" } }"#).is_ok());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::scene::Color;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+
+// An RGBA image produced by the raytracer. Pixels are stored row by row with four
+// bytes per pixel, which is the same layout used by the canvas `ImageData` type.
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    // A black image, or an error when it has more bytes than can be addressed
+    pub fn new(width: u32, height: u32) -> Result<Framebuffer, String> {
+        let len = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(4))
+            .ok_or_else(|| format!("a {}x{} image is too large", width, height))?;
+        Ok(Framebuffer {
+            width,
+            height,
+            pixels: vec![0; len],
+        })
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
+        let c = color.clamp();
+        let i = 4 * (y as usize * self.width as usize + x as usize);
+        self.pixels[i] = (c.r * 255.0).round() as u8;
+        self.pixels[i + 1] = (c.g * 255.0).round() as u8;
+        self.pixels[i + 2] = (c.b * 255.0).round() as u8;
+        self.pixels[i + 3] = 255u8;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.pixels
+    }
+}
+
+#[wasm_bindgen]
+impl Framebuffer {
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Copy the RGBA bytes out, this shows up in JS as a Uint8Array
+    pub fn pixels(&self) -> Vec<u8> {
+        self.pixels.clone()
+    }
+
+    pub fn to_image_data(&self) -> Result<ImageData, JsValue> {
+        ImageData::new_with_u8_clamped_array_and_sh(
+            Clamped(self.pixels.as_slice()),
+            self.width,
+            self.height,
+        )
+    }
+
+    // Draw the image into the given canvas, resizing the canvas to fit
+    pub fn present(&self, canvas: &HtmlCanvasElement) -> Result<(), JsValue> {
+        let context = canvas
+            .get_context("2d")?
+            .ok_or_else(|| JsValue::from_str("canvas has no 2d context"))?
+            .dyn_into::<CanvasRenderingContext2d>()?;
+
+        canvas.set_width(self.width);
+        canvas.set_height(self.height);
+        context.put_image_data(&self.to_image_data()?, 0.0, 0.0)
+    }
+
+    // Draw the image into the canvas element with the given id
+    pub fn present_to(&self, canvas_id: &str) -> Result<(), JsValue> {
+        let document = web_sys::window()
+            .and_then(|w| w.document())
+            .ok_or_else(|| JsValue::from_str("no document available"))?;
+        let canvas = document
+            .get_element_by_id(canvas_id)
+            .ok_or_else(|| JsValue::from_str(&format!("no element with id '{}'", canvas_id)))?
+            .dyn_into::<HtmlCanvasElement>()?;
+        self.present(&canvas)
+    }
+}
@@ -0,0 +1,360 @@
+// Bounding volume hierarchy, a tree of boxes for finding what a ray might hit without
+// testing every item. Used over the faces of meshes and the nodes of the scene
+//
+// Built with the surface area heuristic: items are split where the chance of a ray
+// entering each side, times the number of items on that side, is lowest
+
+use crate::geometry::Ray;
+use nalgebra::Vector3;
+use std::f32;
+
+// Buckets along each axis that split positions are picked from
+const SAH_BINS: usize = 12;
+// Cost of visiting a node relative to testing an item
+const TRAVERSAL_COST: f32 = 1.0;
+// Leaves are split until they hold at most this many, even when splitting doesn't look cheaper
+const MAX_LEAF_SIZE: usize = 8;
+// Boxes are made this much longer along the ray, so rounding can't miss flat ones like the
+// box around a face lying in an axis plane
+const BOX_SLACK: f32 = 1.000_01;
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+struct BvhNode {
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+    // For leaves the first of `count` items in the item order, otherwise the second child.
+    // The first child always comes right after its parent
+    offset: usize,
+    count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub(crate) struct Bvh {
+    nodes: Vec<BvhNode>,
+    // Indices of the items, grouped by the leaf they are in
+    items: Vec<usize>,
+}
+
+// A box that grows to hold whatever is added to it
+#[derive(Clone, Copy)]
+struct Bounds {
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+}
+
+impl Bounds {
+    fn empty() -> Bounds {
+        Bounds {
+            min: Vector3::repeat(f32::MAX),
+            max: Vector3::repeat(f32::MIN),
+        }
+    }
+
+    fn grow(&mut self, min: &Vector3<f32>, max: &Vector3<f32>) {
+        self.min = self.min.zip_map(min, f32::min);
+        self.max = self.max.zip_map(max, f32::max);
+    }
+
+    fn surface_area(&self) -> f32 {
+        if self.min.x > self.max.x {
+            return 0.0;
+        }
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+}
+
+impl Bvh {
+    // A tree over items with these (min, max) boxes, items are referred to by index
+    pub(crate) fn new(bounds: &[(Vector3<f32>, Vector3<f32>)]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            items: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            let centroids: Vec<Vector3<f32>> = bounds.iter().map(|(min, max)| (min + max) / 2.0).collect();
+            bvh.build(bounds, &centroids, 0, bounds.len());
+        }
+        bvh
+    }
+
+    fn build(&mut self, bounds: &[(Vector3<f32>, Vector3<f32>)], centroids: &[Vector3<f32>], start: usize, end: usize) {
+        let mut node_bounds = Bounds::empty();
+        let mut centroid_bounds = Bounds::empty();
+        for i in self.items[start..end].iter() {
+            node_bounds.grow(&bounds[*i].0, &bounds[*i].1);
+            centroid_bounds.grow(&centroids[*i], &centroids[*i]);
+        }
+        let index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            min: node_bounds.min,
+            max: node_bounds.max,
+            offset: start,
+            count: end - start,
+        });
+
+        let count = end - start;
+        if count <= 1 {
+            return;
+        }
+        let split = match self.find_split(bounds, centroids, start, end, &node_bounds, &centroid_bounds) {
+            Some((axis, bin, cost)) if cost < count as f32 || count > MAX_LEAF_SIZE => (axis, bin),
+            _ => return,
+        };
+
+        let (axis, bin) = split;
+        let (lo, extent) = (centroid_bounds.min[axis], centroid_bounds.max[axis] - centroid_bounds.min[axis]);
+        let bin_of = |c: &Vector3<f32>| (((c[axis] - lo) / extent * SAH_BINS as f32) as usize).min(SAH_BINS - 1);
+        // Move the items left of the split to the front
+        let mut mid = start;
+        for i in start..end {
+            if bin_of(&centroids[self.items[i]]) <= bin {
+                self.items.swap(i, mid);
+                mid += 1;
+            }
+        }
+        if mid == start || mid == end {
+            return;
+        }
+
+        self.nodes[index].count = 0;
+        self.build(bounds, centroids, start, mid);
+        self.nodes[index].offset = self.nodes.len();
+        self.build(bounds, centroids, mid, end);
+    }
+
+    // The axis and last bin of the left side of the cheapest split, with its cost
+    fn find_split(
+        &self,
+        bounds: &[(Vector3<f32>, Vector3<f32>)],
+        centroids: &[Vector3<f32>],
+        start: usize,
+        end: usize,
+        node_bounds: &Bounds,
+        centroid_bounds: &Bounds,
+    ) -> Option<(usize, usize, f32)> {
+        let parent_area = node_bounds.surface_area().max(f32::MIN_POSITIVE);
+        let mut best: Option<(usize, usize, f32)> = None;
+        let axes = centroid_bounds.min.iter().zip(centroid_bounds.max.iter()).enumerate();
+        for (axis, (lo, hi)) in axes {
+            let (lo, extent) = (*lo, hi - lo);
+            if extent <= 0.0 {
+                continue;
+            }
+            let mut bins = [(Bounds::empty(), 0usize); SAH_BINS];
+            for i in self.items[start..end].iter() {
+                let b = (((centroids[*i][axis] - lo) / extent * SAH_BINS as f32) as usize).min(SAH_BINS - 1);
+                bins[b].0.grow(&bounds[*i].0, &bounds[*i].1);
+                bins[b].1 += 1;
+            }
+            // Sweep from the right to get the cost of everything past each bin
+            let mut right_costs = [0.0; SAH_BINS];
+            let (mut right, mut right_count) = (Bounds::empty(), 0);
+            for b in (1..SAH_BINS).rev() {
+                right.grow(&bins[b].0.min, &bins[b].0.max);
+                right_count += bins[b].1;
+                right_costs[b - 1] = right.surface_area() * right_count as f32;
+            }
+            let (mut left, mut left_count) = (Bounds::empty(), 0);
+            for b in 0..SAH_BINS - 1 {
+                left.grow(&bins[b].0.min, &bins[b].0.max);
+                left_count += bins[b].1;
+                if left_count == 0 || left_count == end - start {
+                    continue;
+                }
+                let cost = TRAVERSAL_COST + (left.surface_area() * left_count as f32 + right_costs[b]) / parent_area;
+                if best.is_none_or(|(_, _, c)| cost < c) {
+                    best = Some((axis, b, cost));
+                }
+            }
+        }
+        best
+    }
+
+    // Call `hit` with each item whose box the ray passes through closer than `max_t`, nearest
+    // boxes first. `hit` gives back the distance along the ray when it hits something closer,
+    // which stops boxes past it from being visited
+    pub(crate) fn traverse<F: FnMut(usize) -> Option<f32>>(&self, ray: &Ray, mut max_t: f32, mut hit: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = ray.dir.map(|d| 1.0 / d);
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if box_entry(ray, &inv_dir, node).is_none_or(|t| t > max_t) {
+                continue;
+            }
+            if node.count > 0 {
+                for i in self.items[node.offset..node.offset + node.count].iter() {
+                    if let Some(t) = hit(*i) {
+                        max_t = max_t.min(t);
+                    }
+                }
+                continue;
+            }
+            // Push the further child first so the nearer one is visited first
+            let (first, second) = (index + 1, node.offset);
+            let t_first = box_entry(ray, &inv_dir, &self.nodes[first]).unwrap_or(f32::MAX);
+            let t_second = box_entry(ray, &inv_dir, &self.nodes[second]).unwrap_or(f32::MAX);
+            if t_first <= t_second {
+                stack.push(second);
+                stack.push(first);
+            } else {
+                stack.push(first);
+                stack.push(second);
+            }
+        }
+    }
+}
+
+// Distance along the ray to where it enters the box, or 0 when it starts inside
+fn box_entry(ray: &Ray, inv_dir: &Vector3<f32>, node: &BvhNode) -> Option<f32> {
+    let mut t_min = 0.0f32;
+    let mut t_max = f32::MAX;
+    for axis in 0..3 {
+        let t1 = (node.min[axis] - ray.src[axis]) * inv_dir[axis];
+        let t2 = (node.max[axis] - ray.src[axis]) * inv_dir[axis];
+        // min and max skip the NaN from rays lying in the plane of a side
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+    }
+    if t_min <= t_max * BOX_SLACK {
+        Some(t_min)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Mesh;
+    use nalgebra::Point3;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    // Distance along the ray to a triangle, hit from either side
+    fn hit_triangle(ray: &Ray, [a, b, c]: [Vector3<f32>; 3]) -> Option<f32> {
+        let (edge1, edge2) = (b - a, c - a);
+        let q = ray.dir.cross(&edge2);
+        let det = edge1.dot(&q);
+        if det.abs() < 1e-9 {
+            return None;
+        }
+        let s = ray.src.coords - a;
+        let u = s.dot(&q) / det;
+        let r = s.cross(&edge1);
+        let v = ray.dir.dot(&r) / det;
+        let t = edge2.dot(&r) / det;
+        if u < 0.0 || v < 0.0 || u + v > 1.0 || t <= 0.0 {
+            return None;
+        }
+        Some(t)
+    }
+
+    fn nearest_in_bvh(mesh: &Mesh, ray: &Ray) -> Option<(usize, f32)> {
+        let mut nearest: Option<(usize, f32)> = None;
+        mesh.bvh.traverse(ray, f32::MAX, |face| {
+            let t = hit_triangle(ray, mesh.triangle(face))?;
+            if nearest.is_some_and(|(_, nearest_t)| nearest_t <= t) {
+                return None;
+            }
+            nearest = Some((face, t));
+            Some(t)
+        });
+        nearest
+    }
+
+    fn nearest_in_every_face(mesh: &Mesh, ray: &Ray) -> Option<(usize, f32)> {
+        (0..mesh.faces.len())
+            .filter_map(|face| Some((face, hit_triangle(ray, mesh.triangle(face))?)))
+            .fold(None, |nearest, (face, t)| match nearest {
+                Some((_, nearest_t)) if nearest_t <= t => nearest,
+                _ => Some((face, t)),
+            })
+    }
+
+    fn assert_same_hits(mesh: &Mesh, rays: &[Ray]) {
+        for ray in rays {
+            assert_eq!(nearest_in_bvh(mesh, ray), nearest_in_every_face(mesh, ray), "{:?}", ray);
+        }
+    }
+
+    fn point<R: Rng>(rng: &mut R, size: f32) -> Vector3<f32> {
+        Vector3::new(rng.gen(), rng.gen(), rng.gen()) * size
+    }
+
+    #[test]
+    fn finds_the_nearest_face() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let mut vertices = vec![];
+        let mut faces = vec![];
+        for _ in 0..200 {
+            let corner = point(&mut rng, 10.0);
+            faces.push([vertices.len(), vertices.len() + 1, vertices.len() + 2]);
+            vertices.push(corner);
+            vertices.push(corner + point(&mut rng, 1.5));
+            vertices.push(corner + point(&mut rng, 1.5));
+        }
+        let mesh = Mesh::new(vertices, faces).unwrap();
+        assert!(mesh.bvh.nodes.len() > 1);
+
+        let rays: Vec<Ray> = (0..500)
+            .map(|_| {
+                let from = point(&mut rng, 30.0) - Vector3::repeat(10.0);
+                Ray::new(Point3::from(from), point(&mut rng, 10.0) - from)
+            })
+            .collect();
+        assert_same_hits(&mesh, &rays);
+        assert!(rays.iter().filter(|ray| nearest_in_bvh(&mesh, ray).is_some()).count() > 100);
+    }
+
+    #[test]
+    fn finds_faces_that_share_a_centroid() {
+        // Every box is centered on the origin, so there is nothing to split them on
+        let mut vertices = vec![];
+        let mut faces = vec![];
+        for i in 0..20 {
+            let (s, z) = (1.0 + i as f32 * 0.1, i as f32 * 0.05);
+            faces.push([vertices.len(), vertices.len() + 1, vertices.len() + 2]);
+            vertices.push(Vector3::new(-s, -s, -z));
+            vertices.push(Vector3::new(s, -s, -z));
+            vertices.push(Vector3::new(0.0, s, z));
+        }
+        let mesh = Mesh::new(vertices, faces).unwrap();
+        assert_eq!(mesh.bvh.nodes.len(), 1);
+
+        let rays: Vec<Ray> = [(0.0, 0.0), (0.5, -0.5), (-1.5, -1.8), (2.5, 2.5)]
+            .iter()
+            .map(|(x, y)| Ray::new(Point3::new(*x, *y, 5.0), Vector3::new(0.0, 0.0, -1.0)))
+            .collect();
+        assert_same_hits(&mesh, &rays);
+        assert!(nearest_in_bvh(&mesh, &rays[0]).is_some());
+    }
+
+    #[test]
+    fn rays_along_a_box_side_still_hit() {
+        // A flat floor in the y = 0 plane, with a wall standing on it
+        let vertices = vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(4.0, 0.0, 0.0),
+            Vector3::new(4.0, 0.0, 4.0),
+            Vector3::new(0.0, 0.0, 4.0),
+            Vector3::new(5.0, -1.0, 0.0),
+            Vector3::new(5.0, 1.0, 0.0),
+            Vector3::new(5.0, 1.0, 4.0),
+            Vector3::new(5.0, -1.0, 4.0),
+        ];
+        let mesh = Mesh::new(vertices, vec![[0, 1, 2], [0, 2, 3], [4, 5, 6], [4, 6, 7]]).unwrap();
+
+        // In the plane of the floor's box, and of the wall's box
+        let along_floor = Ray::new(Point3::new(-1.0, 0.0, 2.0), Vector3::new(1.0, 0.0, 0.0));
+        let along_wall = Ray::new(Point3::new(5.0, 0.0, -2.0), Vector3::new(0.0, 0.0, 1.0));
+        let above = Ray::new(Point3::new(-1.0, 2.0, 2.0), Vector3::new(1.0, 0.0, 0.0));
+        assert_same_hits(&mesh, &[along_floor, along_wall, above]);
+        assert_eq!(nearest_in_bvh(&mesh, &along_floor).map(|(_, t)| t), Some(6.0));
+        assert_eq!(nearest_in_bvh(&mesh, &above), None);
+    }
+}
@@ -1,24 +1,86 @@
-use nalgebra::Vector3;
-use std::error::Error;
+use crate::geometry::{obj, ply, stl, Bvh};
+use nalgebra::{Point3, Vector3};
+use std::collections::HashMap;
 use std::f32;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
 use std::iter::Iterator;
+use wasm_bindgen::prelude::*;
 
+// Smallest area of the triangle in texture space that tangents are worked out from
+const UV_AREA_EPS: f32 = 0.000_000_1;
+// Slack in the crease angle test, so faces in the same plane always blend
+const CREASE_EPS: f32 = 0.000_01;
+// Crease angle of new meshes, in degrees. Curved surfaces blend while boxes keep their edges
+pub(crate) const DEFAULT_CREASE_ANGLE: f32 = 60.0;
+
+// A triangle mesh, loaded from an OBJ, PLY or STL file
+#[wasm_bindgen]
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct Mesh {
-    pub vertices: Vec<Vector3<f32>>,
-    // Each face represents three indexes in the vertices vec
-    pub faces: Vec<[usize; 3]>,
+    pub(crate) vertices: Vec<Vector3<f32>>,
+    // Vertex normals and texture coordinates, faces refer to these by index
+    pub(crate) normals: Vec<Vector3<f32>>,
+    pub(crate) uvs: Vec<[f32; 2]>,
+    pub(crate) faces: Vec<Face>,
+    // Normal at each corner of each face, from the file or else worked out from the faces
+    // around the corner, shading blends between them
+    pub(crate) shading_normals: Vec<[Vector3<f32>; 3]>,
+    // Faces meeting at more than this many degrees keep a hard edge between them when the
+    // normals are worked out, None smooths over every edge
+    pub(crate) crease_angle: Option<f32>,
+    // Names of the groups and materials faces belong to
+    pub(crate) groups: Vec<String>,
+    pub(crate) materials: Vec<String>,
+    // MTL files the materials are defined in, relative to the OBJ file
+    pub(crate) material_libraries: Vec<String>,
+    // Total area of the faces up to and including each one, for picking faces by area
+    pub(crate) cumulative_areas: Vec<f32>,
 
     // Track a bounding box to improve performance
-    pub aabb_corner: Vector3<f32>,
-    pub aabb_size: Vector3<f32>,
+    pub(crate) aabb_corner: Vector3<f32>,
+    pub(crate) aabb_size: Vector3<f32>,
+    // Boxes around the faces, so rays only test the ones they pass near
+    pub(crate) bvh: Bvh,
+    // The file the mesh was read from, so scenes using it can be saved
+    pub(crate) path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub(crate) struct Face {
+    // Indexes in the vertices vec
+    pub(crate) vertices: [usize; 3],
+    pub(crate) normals: Option<[usize; 3]>,
+    pub(crate) uvs: Option<[usize; 3]>,
+    // Indexes in the groups and materials vecs
+    pub(crate) group: usize,
+    pub(crate) material: Option<usize>,
+}
+
+impl Face {
+    pub(crate) fn new(vertices: [usize; 3]) -> Face {
+        Face {
+            vertices,
+            normals: None,
+            uvs: None,
+            group: 0,
+            material: None,
+        }
+    }
+}
+
+// Part of a mesh with a single group and material, nodes are made from these when the
+// materials of a mesh are loaded too
+pub(crate) struct MeshPart {
+    pub(crate) group: String,
+    pub(crate) material: Option<String>,
+    pub(crate) mesh: Mesh,
 }
 
 // Generate a bounding box for a set of vertices
 fn generate_bounding_box(vertices: &[Vector3<f32>]) -> (Vector3<f32>, Vector3<f32>) {
+    if vertices.is_empty() {
+        return (Vector3::zeros(), Vector3::zeros());
+    }
+
     let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
     let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
 
@@ -46,58 +108,393 @@ fn generate_bounding_box(vertices: &[Vector3<f32>]) -> (Vector3<f32>, Vector3<f3
     (min, max - min)
 }
 
-impl Mesh {
-    // Load a mesh from a file
-    pub fn from_file(file_name: &str) -> Result<Mesh, Box<Error>> {
-        let mut vertices = vec![];
-        let mut faces = vec![];
-        let file_reader = BufReader::new(File::open(file_name)?);
-
-        for l in file_reader.lines() {
-            let line = l?;
-            let mut parts = line.split_whitespace();
-            let first = parts.next();
-            if first.is_some() {
-                match first.unwrap() {
-                    "v" => {
-                        let x: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        let y: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        let z: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        vertices.push(Vector3::new(x, y, z));
-                    }
-                    "f" => {
-                        let av: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let bv: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let cv: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let a: usize = av[0].parse()?;
-                        let b: usize = bv[0].parse()?;
-                        let c: usize = cv[0].parse()?;
-                        faces.push([a - 1, b - 1, c - 1]);
-                    }
-                    // Ignore all other lines
-                    _ => {}
+// The normal at each corner of each face. Corners without one in the file get the average
+// of the faces meeting at that position, weighted by the angle each makes there
+fn corner_normals(
+    vertices: &[Vector3<f32>],
+    normals: &[Vector3<f32>],
+    faces: &[Face],
+    crease_angle: Option<f32>,
+) -> Vec<[Vector3<f32>; 3]> {
+    let face_normals: Vec<Vector3<f32>> = faces
+        .iter()
+        .map(|f| {
+            let [a, b, c] = f.vertices;
+            (vertices[b] - vertices[a])
+                .cross(&(vertices[c] - vertices[a]))
+                .try_normalize(0.0)
+                .unwrap_or_else(Vector3::zeros)
+        })
+        .collect();
+
+    // Corners are matched by position rather than index, so faces that were saved with
+    // their own copies of each vertex still blend. Adding 0 turns -0 into 0
+    let key = |v: &Vector3<f32>| [(v.x + 0.0).to_bits(), (v.y + 0.0).to_bits(), (v.z + 0.0).to_bits()];
+    let mut corners: HashMap<[u32; 3], Vec<(usize, f32)>> = HashMap::new();
+    for (i, f) in faces.iter().enumerate() {
+        for k in 0..3 {
+            let v = vertices[f.vertices[k]];
+            let e1 = vertices[f.vertices[(k + 1) % 3]] - v;
+            let e2 = vertices[f.vertices[(k + 2) % 3]] - v;
+            let angle = e1.cross(&e2).norm().atan2(e1.dot(&e2));
+            corners.entry(key(&v)).or_default().push((i, angle));
+        }
+    }
+
+    let min_cos = crease_angle.map_or(-1.0, |a| a.to_radians().cos());
+    faces
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            let mut out = [face_normals[i]; 3];
+            for (k, n) in out.iter_mut().enumerate() {
+                if let Some(file) = f.normals.and_then(|ns| normals[ns[k]].try_normalize(0.0)) {
+                    *n = file;
+                    continue;
+                }
+                let sum: Vector3<f32> = corners[&key(&vertices[f.vertices[k]])]
+                    .iter()
+                    .filter(|(j, _)| face_normals[*j].dot(&face_normals[i]) >= min_cos - CREASE_EPS)
+                    .map(|(j, angle)| face_normals[*j] * *angle)
+                    .sum();
+                if let Some(sum) = sum.try_normalize(0.0) {
+                    *n = sum;
                 }
             }
+            out
+        })
+        .collect()
+}
+
+#[wasm_bindgen]
+impl Mesh {
+    // Parse the text of an OBJ file, like one pasted into the editor or read from an upload
+    pub fn from_obj(text: &str) -> Result<Mesh, JsValue> {
+        Mesh::from_obj_str(text).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Read the bytes of an ASCII or binary PLY file
+    pub fn from_ply(bytes: &[u8]) -> Result<Mesh, JsValue> {
+        Mesh::from_ply_bytes(bytes).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Read the bytes of an ASCII or binary STL file
+    pub fn from_stl(bytes: &[u8]) -> Result<Mesh, JsValue> {
+        Mesh::from_stl_bytes(bytes).map_err(|e| JsValue::from_str(&e))
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn vertex_count(&self) -> usize {
+        self.vertices.len()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn face_count(&self) -> usize {
+        self.faces.len()
+    }
+
+    // Names of the groups and objects in the file, in the order they first appear
+    pub fn group_names(&self) -> Vec<JsValue> {
+        self.groups.iter().map(|g| JsValue::from_str(g)).collect()
+    }
+
+    // Names of the materials faces use, see `Scene.create_obj_node` to load them
+    pub fn material_names(&self) -> Vec<JsValue> {
+        self.materials.iter().map(|m| JsValue::from_str(m)).collect()
+    }
+
+    // Keep hard edges between faces that meet at more than this many degrees, 60 to start
+    // with, or smooth over all of them with undefined. Normals given in the file are left alone
+    pub fn set_crease_angle(&mut self, degrees: Option<f32>) {
+        self.crease_angle = degrees;
+        self.shading_normals = corner_normals(&self.vertices, &self.normals, &self.faces, degrees);
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn crease_angle(&self) -> Option<f32> {
+        self.crease_angle
+    }
+
+    // Just the faces of one group or object
+    pub fn group(&self, name: &str) -> Result<Mesh, JsValue> {
+        let group = self
+            .groups
+            .iter()
+            .position(|g| g == name)
+            .ok_or_else(|| JsValue::from_str(&format!("the mesh has no group named '{}'", name)))?;
+        Ok(self.subset(|f| f.group == group))
+    }
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vector3<f32>>, faces: Vec<[usize; 3]>) -> Result<Mesh, String> {
+        let faces = faces.into_iter().map(Face::new).collect();
+        Mesh::from_parts(vertices, vec![], vec![], faces)
+    }
+
+    // Build a mesh from faces refering to vertices, normals and texture coordinates by index.
+    // The groups and materials of the faces are left unnamed
+    pub(crate) fn from_parts(
+        vertices: Vec<Vector3<f32>>,
+        normals: Vec<Vector3<f32>>,
+        uvs: Vec<[f32; 2]>,
+        faces: Vec<Face>,
+    ) -> Result<Mesh, String> {
+        let missing = |indices: Option<[usize; 3]>, count: usize| indices.is_some_and(|i| i.iter().any(|i| *i >= count));
+        if let Some(face) = faces.iter().find(|f| {
+            missing(Some(f.vertices), vertices.len()) || missing(f.normals, normals.len()) || missing(f.uvs, uvs.len())
+        }) {
+            return Err(format!("face {:?} refers to a missing vertex", face.vertices));
         }
 
+        let mut total = 0.0;
+        let cumulative_areas = faces
+            .iter()
+            .map(|f| {
+                let [a, b, c] = f.vertices;
+                let (a, b, c) = (vertices[a], vertices[b], vertices[c]);
+                total += (b - a).cross(&(c - a)).norm() / 2.0;
+                total
+            })
+            .collect();
         let (aabb_corner, aabb_size) = generate_bounding_box(&vertices);
+        let shading_normals = corner_normals(&vertices, &normals, &faces, Some(DEFAULT_CREASE_ANGLE));
+        let face_bounds: Vec<(Vector3<f32>, Vector3<f32>)> = faces
+            .iter()
+            .map(|f| {
+                let [a, b, c] = f.vertices;
+                let (a, b, c) = (vertices[a], vertices[b], vertices[c]);
+                (
+                    a.zip_map(&b, f32::min).zip_map(&c, f32::min),
+                    a.zip_map(&b, f32::max).zip_map(&c, f32::max),
+                )
+            })
+            .collect();
+        let bvh = Bvh::new(&face_bounds);
+        let groups = vec!["default".to_string(); faces.iter().map(|f| f.group + 1).max().unwrap_or(1)];
+        let materials = (0..faces.iter().filter_map(|f| f.material).map(|m| m + 1).max().unwrap_or(0))
+            .map(|m| format!("material{}", m))
+            .collect();
         Ok(Mesh {
             vertices,
+            normals,
+            uvs,
             faces,
+            shading_normals,
+            crease_angle: Some(DEFAULT_CREASE_ANGLE),
+            groups,
+            materials,
+            material_libraries: vec![],
+            cumulative_areas,
             aabb_corner,
             aabb_size,
+            bvh,
+            path: None,
         })
     }
+
+    // Load a mesh from the contents of an OBJ file
+    pub fn from_obj_str(text: &str) -> Result<Mesh, String> {
+        obj::parse(text)
+    }
+
+    pub fn from_ply_bytes(bytes: &[u8]) -> Result<Mesh, String> {
+        ply::parse(bytes)
+    }
+
+    pub fn from_stl_bytes(bytes: &[u8]) -> Result<Mesh, String> {
+        stl::parse(bytes)
+    }
+
+    // Load a mesh from a PLY or STL file by its extension, or else an OBJ file
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_file(file_name: &str) -> Result<Mesh, String> {
+        let bytes = std::fs::read(file_name).map_err(|e| format!("couldn't read mesh '{}': {}", file_name, e))?;
+        let extension = file_name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+        let mesh = match extension.as_str() {
+            "ply" => Mesh::from_ply_bytes(&bytes),
+            "stl" => Mesh::from_stl_bytes(&bytes),
+            _ => String::from_utf8(bytes)
+                .map_err(|_| "OBJ files are text".to_string())
+                .and_then(|text| Mesh::from_obj_str(&text)),
+        };
+        let mut mesh = mesh.map_err(|e| format!("mesh '{}': {}", file_name, e))?;
+        mesh.path = Some(file_name.to_string());
+        Ok(mesh)
+    }
+
+    // The mesh written back out as an OBJ file
+    pub fn to_obj_string(&self) -> String {
+        let mut obj = String::new();
+        for library in self.material_libraries.iter() {
+            obj.push_str(&format!("mtllib {}\n", library));
+        }
+        for v in self.vertices.iter() {
+            obj.push_str(&format!("v {} {} {}\n", v.x, v.y, v.z));
+        }
+        for n in self.normals.iter() {
+            obj.push_str(&format!("vn {} {} {}\n", n.x, n.y, n.z));
+        }
+        for uv in self.uvs.iter() {
+            obj.push_str(&format!("vt {} {}\n", uv[0], uv[1]));
+        }
+        let (mut group, mut material) = (None, None);
+        // OBJ can't go back to no material after a usemtl, so faces without one come first
+        let faces = self.faces.iter().filter(|f| f.material.is_none());
+        for f in faces.chain(self.faces.iter().filter(|f| f.material.is_some())) {
+            if group != Some(f.group) {
+                group = Some(f.group);
+                obj.push_str(&format!("g {}\n", self.groups[f.group]));
+            }
+            if let Some(m) = f.material.filter(|_| material != f.material) {
+                material = f.material;
+                obj.push_str(&format!("usemtl {}\n", self.materials[m]));
+            }
+            obj.push('f');
+            for k in 0..3 {
+                obj.push_str(&format!(" {}", f.vertices[k] + 1));
+                match (f.uvs, f.normals) {
+                    (Some(t), Some(n)) => obj.push_str(&format!("/{}/{}", t[k] + 1, n[k] + 1)),
+                    (Some(t), None) => obj.push_str(&format!("/{}", t[k] + 1)),
+                    (None, Some(n)) => obj.push_str(&format!("//{}", n[k] + 1)),
+                    (None, None) => {}
+                }
+            }
+            obj.push('\n');
+        }
+        obj
+    }
+
+    // A new mesh with the faces that pass `keep` and only the vertices they use
+    pub(crate) fn subset<F: Fn(&Face) -> bool>(&self, keep: F) -> Mesh {
+        // Old index to new index, for each of the vertices, normals and texture coordinates
+        let mut remap: [HashMap<usize, usize>; 3] = [HashMap::new(), HashMap::new(), HashMap::new()];
+        let mut take = |kind: usize, indices: [usize; 3]| {
+            let map = &mut remap[kind];
+            let mut out = [0; 3];
+            for (o, i) in out.iter_mut().zip(indices.iter()) {
+                let next = map.len();
+                *o = *map.entry(*i).or_insert(next);
+            }
+            out
+        };
+        let faces: Vec<Face> = self
+            .faces
+            .iter()
+            .filter(|f| keep(f))
+            .map(|f| Face {
+                vertices: take(0, f.vertices),
+                normals: f.normals.map(|n| take(1, n)),
+                uvs: f.uvs.map(|t| take(2, t)),
+                group: 0,
+                material: None,
+            })
+            .collect();
+        fn gather<T: Copy>(map: &HashMap<usize, usize>, from: &[T]) -> Vec<T> {
+            let mut pairs: Vec<(&usize, &usize)> = map.iter().collect();
+            pairs.sort_by_key(|(_, new)| **new);
+            pairs.iter().map(|(old, _)| from[**old]).collect()
+        }
+        let vertices = gather(&remap[0], &self.vertices);
+        let normals = gather(&remap[1], &self.normals);
+        let uvs = gather(&remap[2], &self.uvs);
+        // Every index comes from this mesh, so none are missing
+        let mut mesh = Mesh::from_parts(vertices, normals, uvs, faces).unwrap();
+        mesh.groups = vec![self.first_group_name(&keep)];
+        // Keep the normals of the whole mesh so there are no seams where it was cut
+        mesh.crease_angle = self.crease_angle;
+        mesh.shading_normals = self
+            .faces
+            .iter()
+            .zip(self.shading_normals.iter())
+            .filter(|(f, _)| keep(f))
+            .map(|(_, n)| *n)
+            .collect();
+        mesh
+    }
+
+    fn first_group_name<F: Fn(&Face) -> bool>(&self, keep: &F) -> String {
+        self.faces
+            .iter()
+            .find(|f| keep(f))
+            .map_or("default".to_string(), |f| self.groups[f.group].clone())
+    }
+
+    // Split the mesh into one part for each group and material used together, in the order
+    // they first appear
+    pub(crate) fn parts(&self) -> Vec<MeshPart> {
+        let mut keys = vec![];
+        for f in self.faces.iter() {
+            if !keys.contains(&(f.group, f.material)) {
+                keys.push((f.group, f.material));
+            }
+        }
+        keys.into_iter()
+            .map(|(group, material)| MeshPart {
+                group: self.groups[group].clone(),
+                material: material.map(|m| self.materials[m].clone()),
+                mesh: self.subset(|f| f.group == group && f.material == material),
+            })
+            .collect()
+    }
+
+    pub(crate) fn triangle(&self, face: usize) -> [Vector3<f32>; 3] {
+        let [a, b, c] = self.faces[face].vertices;
+        [self.vertices[a], self.vertices[b], self.vertices[c]]
+    }
+
+    // Texture coordinates at a point on a face, given its barycentric coordinates
+    pub(crate) fn uv_at(&self, face: usize, barycentric: &[f32; 3]) -> Option<[f32; 2]> {
+        let t = self.faces[face].uvs?;
+        let mut uv = [0.0, 0.0];
+        for (i, w) in t.iter().zip(barycentric.iter()) {
+            uv[0] += self.uvs[*i][0] * w;
+            uv[1] += self.uvs[*i][1] * w;
+        }
+        Some(uv)
+    }
+
+    // The shading normal at a point on a face, blended from its corners and turned to the
+    // same side as the face
+    pub(crate) fn normal_at(&self, face: usize, barycentric: &[f32; 3], face_normal: &Vector3<f32>) -> Vector3<f32> {
+        let corners = &self.shading_normals[face];
+        let n: Vector3<f32> = corners.iter().zip(barycentric.iter()).map(|(n, w)| n * *w).sum();
+        match n.try_normalize(0.0) {
+            Some(n) if n.dot(face_normal) < 0.0 => -n,
+            Some(n) => n,
+            None => *face_normal,
+        }
+    }
+
+    // How the surface of a face moves as u and v grow, (dP/du, dP/dv). None when the face
+    // has no texture coordinates or they don't cover any area
+    pub(crate) fn tangents(&self, face: usize) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let t = self.faces[face].uvs?;
+        let [a, b, c] = self.triangle(face);
+        let (ta, tb, tc) = (self.uvs[t[0]], self.uvs[t[1]], self.uvs[t[2]]);
+        let (du1, dv1) = (tb[0] - ta[0], tb[1] - ta[1]);
+        let (du2, dv2) = (tc[0] - ta[0], tc[1] - ta[1]);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < UV_AREA_EPS {
+            return None;
+        }
+        let (dp1, dp2) = (b - a, c - a);
+        Some(((dp1 * dv2 - dp2 * dv1) / det, (dp2 * du1 - dp1 * du2) / det))
+    }
+
+    pub(crate) fn surface_area(&self) -> f32 {
+        self.cumulative_areas.last().cloned().unwrap_or(0.0)
+    }
+
+    // A point spread uniformly over the faces, and the normal of the face it is on
+    pub(crate) fn sample_surface(&self, u: f32, v: f32, w: f32) -> Option<(Point3<f32>, Vector3<f32>)> {
+        let last = self.faces.len().checked_sub(1)?;
+        let target = w * self.surface_area();
+        let face = self.cumulative_areas.partition_point(|a| *a <= target).min(last);
+        let [a, b, c] = self.triangle(face);
+        // Fold the unit square onto the triangle
+        let (u, v) = if u + v > 1.0 { (1.0 - u, 1.0 - v) } else { (u, v) };
+        let normal = (b - a).cross(&(c - a)).try_normalize(0.0)?;
+        Some((Point3::from(a + (b - a) * u + (c - a) * v), normal))
+    }
 }
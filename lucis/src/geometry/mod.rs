@@ -1,10 +1,18 @@
+mod bvh;
+mod bytes;
 mod mesh;
+mod obj;
+mod ply;
 mod primitive;
 mod ray;
+mod stl;
 pub mod volume;
 
+pub(crate) use self::bvh::Bvh;
+pub(crate) use self::bytes::{ByteReader, Endian};
+pub(crate) use self::mesh::{Face, DEFAULT_CREASE_ANGLE};
 pub use self::mesh::Mesh;
-pub use self::primitive::Primitive;
+pub use self::primitive::{Primitive, PrimitiveKind};
 pub use self::ray::Ray;
 
 use nalgebra::Vector3;
@@ -35,9 +43,6 @@ pub fn aabb_collision(ray: &Ray, pos: &Vector3<f32>, size: &Vector3<f32>) -> Roo
     }
 
     if (tmin > tymax) || (tymin > tmax) {
-        if size.x == 1000.0f32 {
-            println!("1");
-        }
         return Roots::No([]);
     }
     if tymin > tmin {
@@ -56,9 +61,6 @@ pub fn aabb_collision(ray: &Ray, pos: &Vector3<f32>, size: &Vector3<f32>) -> Roo
     }
 
     if (tmin > tzmax) || (tzmin > tmax) {
-        if size.x == 1000.0f32 {
-            println!("4");
-        }
         return Roots::No([]);
     }
     if tzmin > tmin {
@@ -70,19 +72,10 @@ pub fn aabb_collision(ray: &Ray, pos: &Vector3<f32>, size: &Vector3<f32>) -> Roo
 
     if tmin <= CUBE_EPS {
         if tmax <= CUBE_EPS {
-            if size.x == 1000.0f32 {
-                println!("2");
-            }
             return Roots::No([]);
         }
-        if size.x == 1000.0f32 {
-            println!("3");
-        }
         Roots::One([tmax])
     } else {
-        if size.x == 1000.0f32 {
-            println!("5");
-        }
         Roots::Two([tmin, tmax])
     }
 }
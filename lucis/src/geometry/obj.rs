@@ -0,0 +1,225 @@
+// Wavefront OBJ files, the text format most modelling tools can export meshes to
+
+use crate::geometry::mesh::{Face, Mesh};
+use nalgebra::Vector3;
+
+// Faces before the first group or object go in this one
+const DEFAULT_GROUP: &str = "default";
+
+// Read the geometry, groups and material names of an OBJ file. Polygons are split into
+// triangles, lines, points and curves are skipped
+pub(crate) fn parse(text: &str) -> Result<Mesh, String> {
+    let mut vertices = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut faces = vec![];
+    let mut groups = vec![DEFAULT_GROUP.to_string()];
+    let mut materials: Vec<String> = vec![];
+    let mut material_libraries = vec![];
+    let mut group = 0;
+    let mut material = None;
+
+    for (n, line) in text.lines().enumerate() {
+        let error = |e: String| format!("line {}: {}", n + 1, e);
+        // Everything after a # is a comment
+        let line = line.split('#').next().unwrap();
+        let mut parts = line.split_whitespace();
+        let first = match parts.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        let rest: Vec<&str> = parts.collect();
+        match first {
+            "v" => vertices.push(vector(&rest, 3, "vertex").map_err(error)?),
+            "vn" => normals.push(vector(&rest, 3, "normal").map_err(error)?),
+            // The v coordinate is optional and w is ignored
+            "vt" => {
+                let u = number(rest.first(), "texture coordinate").map_err(error)?;
+                let v = match rest.get(1) {
+                    Some(v) => number(Some(v), "texture coordinate").map_err(error)?,
+                    None => 0.0,
+                };
+                uvs.push([u, v]);
+            }
+            "f" => {
+                if rest.len() < 3 {
+                    return Err(error(format!("a face needs at least 3 vertices, got {}", rest.len())));
+                }
+                let corners = rest
+                    .iter()
+                    .map(|c| corner(c, vertices.len(), uvs.len(), normals.len()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(error)?;
+                // Texture coordinates and normals are only kept when every corner has them
+                let all_uvs = corners.iter().all(|c| c.1.is_some());
+                let all_normals = corners.iter().all(|c| c.2.is_some());
+                // Fan out from the first corner, fine for the convex polygons files hold
+                for i in 1..corners.len() - 1 {
+                    let tri = [corners[0], corners[i], corners[i + 1]];
+                    faces.push(Face {
+                        vertices: [tri[0].0, tri[1].0, tri[2].0],
+                        uvs: if all_uvs {
+                            Some([tri[0].1.unwrap(), tri[1].1.unwrap(), tri[2].1.unwrap()])
+                        } else {
+                            None
+                        },
+                        normals: if all_normals {
+                            Some([tri[0].2.unwrap(), tri[1].2.unwrap(), tri[2].2.unwrap()])
+                        } else {
+                            None
+                        },
+                        group,
+                        material,
+                    });
+                }
+            }
+            "g" | "o" => {
+                let name = if rest.is_empty() { DEFAULT_GROUP.to_string() } else { rest.join(" ") };
+                group = groups.iter().position(|g| *g == name).unwrap_or_else(|| {
+                    groups.push(name);
+                    groups.len() - 1
+                });
+            }
+            "usemtl" => {
+                let name = rest.join(" ");
+                if name.is_empty() {
+                    return Err(error("usemtl needs a material name".to_string()));
+                }
+                material = Some(materials.iter().position(|m| *m == name).unwrap_or_else(|| {
+                    materials.push(name);
+                    materials.len() - 1
+                }));
+            }
+            "mtllib" => material_libraries.extend(rest.iter().map(|l| l.to_string())),
+            // Smoothing groups, lines, points, curves and anything newer
+            _ => {}
+        }
+    }
+
+    let mut mesh = Mesh::from_parts(vertices, normals, uvs, faces)?;
+    mesh.groups = groups;
+    mesh.materials = materials;
+    mesh.material_libraries = material_libraries;
+    Ok(mesh)
+}
+
+fn number(part: Option<&&str>, what: &str) -> Result<f32, String> {
+    let part = part.ok_or_else(|| format!("missing {}", what))?;
+    part.parse()
+        .map_err(|_| format!("expected a number in the {}, got '{}'", what, part))
+}
+
+fn vector(parts: &[&str], count: usize, what: &str) -> Result<Vector3<f32>, String> {
+    if parts.len() < count {
+        return Err(format!("a {} needs {} coordinates, got {}", what, count, parts.len()));
+    }
+    Ok(Vector3::new(
+        number(parts.first(), what)?,
+        number(parts.get(1), what)?,
+        number(parts.get(2), what)?,
+    ))
+}
+
+// One corner of a face, `v`, `v/vt`, `v//vn` or `v/vt/vn`
+fn corner(
+    part: &str,
+    vertex_count: usize,
+    uv_count: usize,
+    normal_count: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut indices = part.split('/');
+    let vertex = index(indices.next(), vertex_count, "vertex")?.ok_or_else(|| format!("'{}' has no vertex", part))?;
+    let uv = index(indices.next(), uv_count, "texture coordinate")?;
+    let normal = index(indices.next(), normal_count, "normal")?;
+    Ok((vertex, uv, normal))
+}
+
+// OBJ counts from 1, and negative indices count back from the latest element
+fn index(part: Option<&str>, count: usize, what: &str) -> Result<Option<usize>, String> {
+    let part = match part {
+        Some(part) if !part.is_empty() => part,
+        _ => return Ok(None),
+    };
+    let i: i64 = part
+        .parse()
+        .map_err(|_| format!("expected a {} index, got '{}'", what, part))?;
+    let resolved = if i > 0 { i - 1 } else { count as i64 + i };
+    if i == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("{} index {} is out of range, there are {}", what, i, count));
+    }
+    Ok(Some(resolved as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn fans_polygons_into_triangles() {
+        let mesh = parse(&format!("{}f 1 2 3 4", SQUARE)).unwrap();
+        let faces: Vec<[usize; 3]> = mesh.faces.iter().map(|f| f.vertices).collect();
+        assert_eq!(faces, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_latest_vertex() {
+        let mesh = parse(&format!("{}f -4 -3 -2\nv 2 2 0\nf -1 -2 -3", SQUARE)).unwrap();
+        assert_eq!(mesh.faces[0].vertices, [0, 1, 2]);
+        assert_eq!(mesh.faces[1].vertices, [4, 3, 2]);
+    }
+
+    #[test]
+    fn reads_every_corner_format() {
+        let text = format!("{}vt 0 0\nvt 1 0\nvt 1\nvn 0 0 1\n", SQUARE);
+        let mesh = parse(&format!("{}f 1 2 3", text)).unwrap();
+        assert_eq!((mesh.faces[0].uvs, mesh.faces[0].normals), (None, None));
+
+        let mesh = parse(&format!("{}f 1/1 2/2 3/3", text)).unwrap();
+        assert_eq!((mesh.faces[0].uvs, mesh.faces[0].normals), (Some([0, 1, 2]), None));
+        assert_eq!(mesh.uvs[2], [1.0, 0.0]);
+
+        let mesh = parse(&format!("{}f 1//1 2//1 3//1", text)).unwrap();
+        assert_eq!((mesh.faces[0].uvs, mesh.faces[0].normals), (None, Some([0, 0, 0])));
+
+        let mesh = parse(&format!("{}f 1/1/1 2/2/1 3/3/1", text)).unwrap();
+        assert_eq!((mesh.faces[0].uvs, mesh.faces[0].normals), (Some([0, 1, 2]), Some([0, 0, 0])));
+
+        // Dropped when only some corners have them
+        let mesh = parse(&format!("{}f 1/1/1 2 3/3/1", text)).unwrap();
+        assert_eq!((mesh.faces[0].uvs, mesh.faces[0].normals), (None, None));
+    }
+
+    #[test]
+    fn keeps_groups_and_materials() {
+        let text = format!(
+            "mtllib a.mtl b.mtl\n{}f 1 2 3\ng top\nusemtl red\nf 1 2 3\no side\nusemtl blue\nf 2 3 4\ng top\nusemtl red\nf 1 3 4",
+            SQUARE
+        );
+        let mesh = parse(&text).unwrap();
+        assert_eq!(mesh.material_libraries, vec!["a.mtl", "b.mtl"]);
+        assert_eq!(mesh.groups, vec!["default", "top", "side"]);
+        assert_eq!(mesh.materials, vec!["red", "blue"]);
+        let faces: Vec<(usize, Option<usize>)> = mesh.faces.iter().map(|f| (f.group, f.material)).collect();
+        assert_eq!(faces, vec![(0, None), (1, Some(0)), (2, Some(1)), (1, Some(0))]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let errors = [
+            ("v 0 0\n", "line 1"),
+            (&format!("{}f 1 2", SQUARE), "line 5"),
+            (&format!("{}f 1 2 5", SQUARE), "line 5"),
+            (&format!("{}f 0 1 2", SQUARE), "line 5"),
+            (&format!("{}f 1 2 -5", SQUARE), "line 5"),
+            (&format!("{}f 1/1 2/1 3/1", SQUARE), "line 5"),
+            (&format!("{}\nf 1 x 3", SQUARE), "line 6"),
+            ("usemtl\n", "line 1"),
+        ];
+        for (text, line) in errors.iter() {
+            let error = parse(text).unwrap_err();
+            assert!(error.starts_with(&format!("{}:", line)), "{:?} gave '{}'", text, error);
+        }
+    }
+}
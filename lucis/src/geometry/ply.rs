@@ -0,0 +1,308 @@
+// Stanford PLY files, the format most 3D scanners save to. ASCII and both binary layouts are
+// read, only the vertex and face elements are used
+
+use crate::geometry::bytes::{ByteReader, Endian};
+use crate::geometry::mesh::{Face, Mesh};
+use nalgebra::Vector3;
+use std::str::SplitAsciiWhitespace;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    Binary(Endian),
+}
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    // The type of the length, then of the items
+    List(String, Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+// Where the values of the body come from
+enum Body<'a> {
+    Ascii(SplitAsciiWhitespace<'a>),
+    Binary(ByteReader<'a>),
+}
+
+impl<'a> Body<'a> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, String> {
+        match self {
+            Body::Ascii(words) => {
+                let word = words.next().ok_or("the file ends early")?;
+                word.parse().map_err(|_| format!("expected a number, got '{}'", word))
+            }
+            Body::Binary(reader) => Ok(match scalar {
+                Scalar::I8 => reader.i8()? as f64,
+                Scalar::U8 => reader.u8()? as f64,
+                Scalar::I16 => reader.i16()? as f64,
+                Scalar::U16 => reader.u16()? as f64,
+                Scalar::I32 => reader.i32()? as f64,
+                Scalar::U32 => reader.u32()? as f64,
+                Scalar::F32 => reader.f32()? as f64,
+                Scalar::F64 => reader.f64()?,
+            }),
+        }
+    }
+}
+
+pub(crate) fn parse(bytes: &[u8]) -> Result<Mesh, String> {
+    let (format, elements, body_start) = parse_header(bytes)?;
+    let mut body = match format {
+        Format::Ascii => {
+            let text = std::str::from_utf8(&bytes[body_start..]).map_err(|_| "the body isn't text".to_string())?;
+            Body::Ascii(text.split_ascii_whitespace())
+        }
+        Format::Binary(endian) => Body::Binary(ByteReader::new(&bytes[body_start..], endian)),
+    };
+
+    let mut vertices = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut polygons = vec![];
+    for element in elements.iter() {
+        for row in 0..element.count {
+            let error = |e: String| format!("{} {}: {}", element.name, row, e);
+            let mut scalars = vec![];
+            let mut lists = vec![];
+            for property in element.properties.iter() {
+                match property {
+                    Property::Scalar(name, scalar) => scalars.push((name.as_str(), body.read(*scalar).map_err(error)?)),
+                    Property::List(name, length, item) => {
+                        let length = body.read(*length).map_err(error)? as usize;
+                        let items = (0..length)
+                            .map(|_| body.read(*item))
+                            .collect::<Result<Vec<f64>, String>>()
+                            .map_err(error)?;
+                        lists.push((name.as_str(), items));
+                    }
+                }
+            }
+            let get = |names: &[&str]| scalars.iter().find(|(n, _)| names.contains(n)).map(|(_, v)| *v as f32);
+            match element.name.as_str() {
+                "vertex" => {
+                    let (x, y, z) = match (get(&["x"]), get(&["y"]), get(&["z"])) {
+                        (Some(x), Some(y), Some(z)) => (x, y, z),
+                        _ => return Err(error("vertices need x, y and z".to_string())),
+                    };
+                    vertices.push(Vector3::new(x, y, z));
+                    if let (Some(x), Some(y), Some(z)) = (get(&["nx"]), get(&["ny"]), get(&["nz"])) {
+                        normals.push(Vector3::new(x, y, z));
+                    }
+                    let u = get(&["u", "s", "texture_u", "texture_s"]);
+                    let v = get(&["v", "t", "texture_v", "texture_t"]);
+                    if let (Some(u), Some(v)) = (u, v) {
+                        uvs.push([u, v]);
+                    }
+                }
+                "face" => {
+                    let indices = lists
+                        .iter()
+                        .find(|(n, _)| *n == "vertex_indices" || *n == "vertex_index")
+                        .ok_or_else(|| error("faces need a vertex_indices list".to_string()))?;
+                    if indices.1.len() < 3 {
+                        return Err(error(format!("a face needs at least 3 vertices, got {}", indices.1.len())));
+                    }
+                    polygons.push((row, indices.1.iter().map(|i| *i as i64).collect::<Vec<i64>>()));
+                }
+                // Edges, materials and anything else
+                _ => {}
+            }
+        }
+    }
+
+    // Normals and texture coordinates only count when every vertex has them
+    let has_normals = !normals.is_empty() && normals.len() == vertices.len();
+    let has_uvs = !uvs.is_empty() && uvs.len() == vertices.len();
+    let mut faces = vec![];
+    for (row, polygon) in polygons {
+        if let Some(i) = polygon.iter().find(|i| **i < 0 || **i >= vertices.len() as i64) {
+            return Err(format!("face {}: vertex index {} is out of range, there are {}", row, i, vertices.len()));
+        }
+        let polygon: Vec<usize> = polygon.iter().map(|i| *i as usize).collect();
+        // Fan out from the first corner, like OBJ polygons
+        for i in 1..polygon.len() - 1 {
+            let corners = [polygon[0], polygon[i], polygon[i + 1]];
+            let mut face = Face::new(corners);
+            face.normals = if has_normals { Some(corners) } else { None };
+            face.uvs = if has_uvs { Some(corners) } else { None };
+            faces.push(face);
+        }
+    }
+    if !has_normals {
+        normals.clear();
+    }
+    if !has_uvs {
+        uvs.clear();
+    }
+    Mesh::from_parts(vertices, normals, uvs, faces)
+}
+
+fn parse_header(bytes: &[u8]) -> Result<(Format, Vec<Element>, usize), String> {
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    let mut pos = 0;
+    let mut n = 0;
+    loop {
+        n += 1;
+        let end = bytes[pos..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or("the header has no end_header line")?;
+        let line = std::str::from_utf8(&bytes[pos..pos + end])
+            .map_err(|_| format!("header line {}: isn't text", n))?
+            .trim();
+        pos += end + 1;
+        let error = |e: String| format!("header line {}: {}", n, e);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if n == 1 {
+            if line != "ply" {
+                return Err("not a PLY file, it doesn't start with 'ply'".to_string());
+            }
+            continue;
+        }
+        match parts.first() {
+            Some(&"format") => {
+                format = Some(match parts.get(1) {
+                    Some(&"ascii") => Format::Ascii,
+                    Some(&"binary_little_endian") => Format::Binary(Endian::Little),
+                    Some(&"binary_big_endian") => Format::Binary(Endian::Big),
+                    _ => return Err(error(format!("unknown format '{}'", parts[1..].join(" ")))),
+                })
+            }
+            Some(&"element") => {
+                if parts.len() < 3 {
+                    return Err(error("elements need a name and a count".to_string()));
+                }
+                let count = parts[2]
+                    .parse()
+                    .map_err(|_| error(format!("expected a count, got '{}'", parts[2])))?;
+                elements.push(Element {
+                    name: parts[1].to_string(),
+                    count,
+                    properties: vec![],
+                });
+            }
+            Some(&"property") => {
+                let element = elements
+                    .last_mut()
+                    .ok_or_else(|| error("a property comes before any element".to_string()))?;
+                let property = match parts.get(1) {
+                    Some(&"list") if parts.len() >= 5 => Property::List(
+                        parts[4].to_string(),
+                        scalar(parts[2]).map_err(error)?,
+                        scalar(parts[3]).map_err(error)?,
+                    ),
+                    Some(ty) if parts.len() >= 3 => Property::Scalar(parts[2].to_string(), scalar(ty).map_err(error)?),
+                    _ => return Err(error("properties need a type and a name".to_string())),
+                };
+                element.properties.push(property);
+            }
+            Some(&"end_header") => break,
+            // Comments, obj_info and blank lines
+            _ => {}
+        }
+    }
+    let format = format.ok_or("the header has no format line")?;
+    Ok((format, elements, pos))
+}
+
+fn scalar(name: &str) -> Result<Scalar, String> {
+    Ok(match name {
+        "char" | "int8" => Scalar::I8,
+        "uchar" | "uint8" => Scalar::U8,
+        "short" | "int16" => Scalar::I16,
+        "ushort" | "uint16" => Scalar::U16,
+        "int" | "int32" => Scalar::I32,
+        "uint" | "uint32" => Scalar::U32,
+        "float" | "float32" => Scalar::F32,
+        "double" | "float64" => Scalar::F64,
+        _ => return Err(format!("unknown property type '{}'", name)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(format: &str) -> String {
+        format!(
+            "ply
+format {} 1.0
+comment a unit square
+element vertex 4
+property float x
+property float y
+property float z
+element face 1
+property list uchar int vertex_indices
+end_header
+",
+            format
+        )
+    }
+
+    const CORNERS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+
+    fn binary(corners: &[[f32; 3]], face: &[i32]) -> Vec<u8> {
+        let mut bytes = header("binary_little_endian").into_bytes();
+        for corner in corners {
+            for x in corner {
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        bytes.push(face.len() as u8);
+        for i in face {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_ascii() {
+        let text = header("ascii") + "0 0 0\n1 0 0\n1 1 0\n0 1 0\n4 0 1 2 3\n";
+        let mesh = parse(text.as_bytes()).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        // The quad is split into two triangles
+        assert_eq!(mesh.faces.len(), 2);
+        assert_eq!(mesh.faces[0].vertices, [0, 1, 2]);
+        assert_eq!(mesh.faces[1].vertices, [0, 2, 3]);
+    }
+
+    #[test]
+    fn parses_binary_little_endian() {
+        let mesh = parse(&binary(&CORNERS, &[0, 1, 2, 3])).unwrap();
+        assert_eq!(mesh.vertices[2], Vector3::new(1.0, 1.0, 0.0));
+        assert_eq!(mesh.faces.len(), 2);
+        assert_eq!(mesh.faces[1].vertices, [0, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let bytes = binary(&CORNERS, &[0, 1, 2, 3]);
+        assert!(parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(parse(&binary(&CORNERS, &[0, 1, 4])).is_err());
+        assert!(parse(&binary(&CORNERS, &[0, 1])).is_err());
+        assert!(parse(b"not a ply file\n").is_err());
+        assert!(parse(header("ascii").replace("end_header\n", "").as_bytes()).is_err());
+        let text = header("ascii") + "0 0 0\n1 0 0\n1 1 0\n";
+        assert!(parse(text.as_bytes()).is_err());
+    }
+}
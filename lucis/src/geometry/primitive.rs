@@ -1,42 +1,195 @@
+use crate::camera::concentric_disk;
 use crate::geometry::{aabb_collision, Mesh, Ray};
-use nalgebra::{Matrix, Unit, Vector3};
+use crate::scene::tangent_frame;
+use nalgebra::{Matrix, Point3, Unit, Vector3};
 use roots::find_roots_quadratic;
 use roots::Roots;
 use std::f32;
+use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 
 const SPHERE_EPS: f32 = 0.0001;
 const CYLINDER_EPS: f32 = 0.0001;
 const CONE_EPS: f32 = 0.001;
 const CLOSE_EPS: f32 = 0.001;
+const TANGENT_EPS: f32 = 0.0001;
 const TRIANGLE_EPS: f32 = 0.0000001;
 
-#[wasm_bindgen]
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Primitive {
     Sphere,
     Cube,
     Cylinder,
     Cone,
-    // Mesh(Mesh),
+    // Shared, cloning a node shouldn't copy its triangles
+    Mesh(Arc<Mesh>),
     None,
 }
 
+// The primitives scripts can create nodes from by name, meshes are made with
+// `Scene.create_mesh_node` instead
+#[wasm_bindgen(js_name = Primitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimitiveKind {
+    Sphere,
+    Cube,
+    Cylinder,
+    Cone,
+    None,
+}
+
+impl From<PrimitiveKind> for Primitive {
+    fn from(kind: PrimitiveKind) -> Primitive {
+        match kind {
+            PrimitiveKind::Sphere => Primitive::Sphere,
+            PrimitiveKind::Cube => Primitive::Cube,
+            PrimitiveKind::Cylinder => Primitive::Cylinder,
+            PrimitiveKind::Cone => Primitive::Cone,
+            PrimitiveKind::None => Primitive::None,
+        }
+    }
+}
+
 impl Primitive {
+    // Meshes also fill in `tangents` when their faces have texture coordinates, for the others
+    // see `tangents` below
     pub fn collides(
         &self,
         ray: &Ray,
         t_value: &mut f32,
         normal: &mut Vector3<f32>,
         uv: &mut [f32; 2],
+        tangents: &mut Option<(Vector3<f32>, Vector3<f32>)>,
     ) -> bool {
         match self {
-            Primitive::Sphere => sphere_collides(ray, t_value, normal),
+            Primitive::Sphere => sphere_collides(ray, t_value, normal, uv),
             Primitive::Cylinder => cylinder_collides(ray, t_value, normal, uv),
-            Primitive::Cone => cone_collides(ray, t_value, normal),
-            Primitive::Cube => cube_collides(ray, t_value, normal),
-            // Primitive::Mesh(mesh) => mesh_collides(ray, mesh, t_value, normal, uv),
-            _ => false,
+            Primitive::Cone => cone_collides(ray, t_value, normal, uv),
+            Primitive::Cube => cube_collides(ray, t_value, normal, uv),
+            Primitive::Mesh(mesh) => mesh_collides(ray, mesh, t_value, normal, uv, tangents),
+            Primitive::None => false,
+        }
+    }
+
+    // Box around the primitive in object space as (min, max), None when there is nothing to hit
+    pub(crate) fn bounds(&self) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        match self {
+            Primitive::Sphere => Some((Vector3::repeat(-1.0), Vector3::repeat(1.0))),
+            Primitive::Cube => Some((Vector3::zeros(), Vector3::repeat(1.0))),
+            // The cone widens to a radius of 1 at the top
+            Primitive::Cylinder | Primitive::Cone => Some((Vector3::new(-1.0, 0.0, -1.0), Vector3::repeat(1.0))),
+            Primitive::Mesh(mesh) if mesh.faces.is_empty() => None,
+            Primitive::Mesh(mesh) => Some((mesh.aabb_corner, mesh.aabb_corner + mesh.aabb_size)),
+            Primitive::None => None,
+        }
+    }
+
+    // Surface area in object space
+    pub fn surface_area(&self) -> f32 {
+        match self {
+            Primitive::Sphere => 4.0 * f32::consts::PI,
+            Primitive::Cube => 6.0,
+            // Side and both caps
+            Primitive::Cylinder => 4.0 * f32::consts::PI,
+            // The side only, the cone has no cap
+            Primitive::Cone => f32::consts::PI * f32::consts::SQRT_2,
+            Primitive::Mesh(mesh) => mesh.surface_area(),
+            Primitive::None => 0.0,
+        }
+    }
+
+    // How the surface moves as u and v grow at a point on it, (dP/du, dP/dv). Bump and normal
+    // maps are laid along these. Where the texture coordinates pinch, like the poles of the
+    // sphere, any two directions across the surface are returned
+    pub fn tangents(&self, point: &Point3<f32>, normal: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+        let (x, y, z) = (point.x, point.y, point.z);
+        let around = 2.0 * f32::consts::PI * Vector3::new(z, 0.0, -x);
+        let r = (x * x + z * z).sqrt();
+        let tangents = match self {
+            // The point is on the unit sphere
+            Primitive::Sphere if r > TANGENT_EPS => {
+                Some((around, f32::consts::PI * Vector3::new(-y * x / r, r, -y * z / r)))
+            }
+            Primitive::Cube => {
+                // Follows the layout of the faces in cube_collides
+                let (u, v) = if normal.x < -0.5 {
+                    (Vector3::z(), Vector3::y())
+                } else if normal.x > 0.5 {
+                    (-Vector3::z(), Vector3::y())
+                } else if normal.y < -0.5 {
+                    (Vector3::x(), Vector3::z())
+                } else if normal.y > 0.5 {
+                    (Vector3::x(), -Vector3::z())
+                } else if normal.z < -0.5 {
+                    (-Vector3::x(), Vector3::y())
+                } else {
+                    (Vector3::x(), Vector3::y())
+                };
+                Some((u, v))
+            }
+            // The caps
+            Primitive::Cylinder if normal.y.abs() > 0.5 => {
+                Some((Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 2.0)))
+            }
+            Primitive::Cylinder => Some((around, Vector3::y())),
+            Primitive::Cone if y > TANGENT_EPS => Some((around, Vector3::new(x / y, 1.0, z / y))),
+            _ => None,
+        };
+        tangents.unwrap_or_else(|| tangent_frame(&normal.normalize()))
+    }
+
+    // Map three numbers in [0, 1) to a point spread uniformly over the surface, along with
+    // the outward normal there. Used to sample emissive primitives as area lights
+    pub fn sample_surface(&self, u: f32, v: f32, w: f32) -> Option<(Point3<f32>, Vector3<f32>)> {
+        match self {
+            Primitive::Sphere => {
+                let z = 1.0 - 2.0 * u;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * f32::consts::PI * v;
+                let p = Vector3::new(r * phi.cos(), r * phi.sin(), z);
+                Some((Point3::from(p), p))
+            }
+            Primitive::Cube => {
+                // Pick a face, then a point on it
+                let face = ((w * 6.0) as usize).min(5);
+                let axis = face % 3;
+                let side = if face < 3 { 0.0 } else { 1.0 };
+                let mut p = Vector3::new(0.0, 0.0, 0.0);
+                let mut normal = Vector3::new(0.0, 0.0, 0.0);
+                p[axis] = side;
+                p[(axis + 1) % 3] = u;
+                p[(axis + 2) % 3] = v;
+                normal[axis] = if face < 3 { -1.0 } else { 1.0 };
+                Some((Point3::from(p), normal))
+            }
+            Primitive::Cylinder => {
+                // The side has half the area
+                if w < 0.5 {
+                    let phi = 2.0 * f32::consts::PI * u;
+                    Some((
+                        Point3::new(phi.cos(), v, phi.sin()),
+                        Vector3::new(phi.cos(), 0.0, phi.sin()),
+                    ))
+                } else {
+                    let (x, z) = concentric_disk(u, v);
+                    if w < 0.75 {
+                        Some((Point3::new(x, 0.0, z), Vector3::new(0.0, -1.0, 0.0)))
+                    } else {
+                        Some((Point3::new(x, 1.0, z), Vector3::new(0.0, 1.0, 0.0)))
+                    }
+                }
+            }
+            Primitive::Cone => {
+                // The radius grows with y, and so does the area
+                let y = u.sqrt();
+                let phi = 2.0 * f32::consts::PI * v;
+                Some((
+                    Point3::new(y * phi.cos(), y, y * phi.sin()),
+                    Vector3::new(phi.cos(), -1.0, phi.sin()) / f32::consts::SQRT_2,
+                ))
+            }
+            Primitive::Mesh(mesh) => mesh.sample_surface(u, v, w),
+            Primitive::None => None,
         }
     }
 }
@@ -46,7 +199,12 @@ fn close(a: f32, b: f32) -> bool {
     diff < CLOSE_EPS
 }
 
-fn cube_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> bool {
+// Texture coordinate around the y axis, 0.5 faces +z and the seam is at the back
+fn around_y(x: f32, z: f32) -> f32 {
+    x.atan2(z) / (2.0 * f32::consts::PI) + 0.5
+}
+
+fn cube_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>, uv: &mut [f32; 2]) -> bool {
     let roots = aabb_collision(
         ray,
         &Vector3::new(0.0, 0.0, 0.0),
@@ -59,26 +217,33 @@ fn cube_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> boo
         _ => return false,
     };
 
-    let collision_point = ray.src + (*t_value * ray.dir);
-    // decide which side the point is on
-    if close(collision_point.x, 0.0) {
+    let p = ray.src + (*t_value * ray.dir);
+    // decide which side the point is on, each face is mapped to the whole texture the right
+    // way up when seen from outside
+    if close(p.x, 0.0) {
         *normal = Vector3::new(-1.0, 0.0, 0.0);
-    } else if close(collision_point.x, 1.0) {
+        *uv = [p.z, p.y];
+    } else if close(p.x, 1.0) {
         *normal = Vector3::new(1.0, 0.0, 0.0);
-    } else if close(collision_point.y, 0.0) {
+        *uv = [1.0 - p.z, p.y];
+    } else if close(p.y, 0.0) {
         *normal = Vector3::new(0.0, -1.0, 0.0);
-    } else if close(collision_point.y, 1.0) {
+        *uv = [p.x, p.z];
+    } else if close(p.y, 1.0) {
         *normal = Vector3::new(0.0, 1.0, 0.0);
-    } else if close(collision_point.z, 0.0) {
+        *uv = [p.x, 1.0 - p.z];
+    } else if close(p.z, 0.0) {
         *normal = Vector3::new(0.0, 0.0, -1.0);
+        *uv = [1.0 - p.x, p.y];
     } else {
         *normal = Vector3::new(0.0, 0.0, 1.0);
+        *uv = [p.x, p.y];
     }
 
     true
 }
 
-fn sphere_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> bool {
+fn sphere_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>, uv: &mut [f32; 2]) -> bool {
     // Check if circle collides with unit sphere
     let l = &ray.src.coords;
     let udir: Unit<Vector3<f32>> = ray.unit_dir();
@@ -89,6 +254,8 @@ fn sphere_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> b
 
     let closest_root = match find_roots_quadratic(a, b, c) {
         Roots::One([r1]) => r1,
+        // Rays starting inside the sphere, like refracted rays, leave through the far side
+        Roots::Two([r1, r2]) if r1 <= SPHERE_EPS => r2,
         Roots::Two([r1, _]) => r1,
         _ => return false,
     };
@@ -96,31 +263,34 @@ fn sphere_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> b
     if closest_root > SPHERE_EPS {
         *t_value = closest_root;
         *normal = (ray.src + (closest_root * ray.dir)).coords;
+        // Longitude and latitude
+        let n = normal.normalize();
+        *uv = [around_y(n.x, n.z), n.y.clamp(-1.0, 1.0).asin() / f32::consts::PI + 0.5];
         true
     } else {
         false
     }
 }
 
+// Möller–Trumbore, hits from either side so rays can leave closed meshes
 fn triangle_collides(
     ray: &Ray,
     triangle: &[Vector3<f32>; 3],
     t_value: &mut f32,
     normal: &mut Vector3<f32>,
+    barycentric: &mut [f32; 3],
 ) -> bool {
     let edge1 = triangle[1] - triangle[0];
     let edge2 = triangle[2] - triangle[0];
 
-    let face_normal = edge1.cross(&edge2).normalize();
-
     let q = ray.dir.cross(&edge2);
     let a = edge1.dot(&q);
 
-    if (a.abs() <= TRIANGLE_EPS) || face_normal.dot(&ray.dir) >= 0.0 {
+    if a.abs() <= TRIANGLE_EPS {
         return false;
     }
 
-    let s = (ray.src - triangle[0]).coords / a;
+    let s = (ray.src.coords - triangle[0]) / a;
     let r = s.cross(&edge1);
 
     let x = s.dot(&q);
@@ -133,15 +303,17 @@ fn triangle_collides(
 
     *t_value = edge2.dot(&r);
 
-    if *t_value < TRIANGLE_EPS {
+    if *t_value < CLOSE_EPS {
         return false;
     }
 
-    *normal = face_normal;
+    // The winding of the vertices gives the outside
+    *normal = edge1.cross(&edge2).normalize();
+    *barycentric = [z, x, y];
     true
 }
 
-fn cone_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> bool {
+fn cone_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>, uv: &mut [f32; 2]) -> bool {
     let src = &ray.src;
     let dir = &ray.dir;
 
@@ -149,34 +321,24 @@ fn cone_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> boo
     let b = 2.0f32 * ((src.x * dir.x) + (src.z * dir.z) - (src.y * dir.y));
     let c = (src.x * src.x) + (src.z * src.z) - (src.y * src.y);
 
-    let closest_root = match find_roots_quadratic(a, b, c) {
-        Roots::One([r1]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 1.0 {
-                r1
-            } else {
-                return false;
-            }
-        }
-        Roots::Two([r1, _r2]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 1.0 {
-                r1
-            } else {
-                return false;
-            }
-        }
-        _ => return false,
+    // Rays starting inside the cone, like refracted rays, leave through the far wall
+    let on_side = |t: &f32| (0.0..=1.0).contains(&(ray.src.y + t * ray.dir.y));
+    let closest_root = match nearest_root(find_roots_quadratic(a, b, c), CONE_EPS, on_side) {
+        Some(t) => t,
+        None => return false,
     };
 
-    if closest_root > CONE_EPS {
-        let _intersection_point = ray.src + (closest_root * ray.dir);
-        *t_value = closest_root;
-        *normal = Vector3::new(0.0f32, 0.0f32, 1.0f32);
-        true
+    let p = ray.src + (closest_root * ray.dir);
+    *t_value = closest_root;
+    // The gradient of x² + z² - y², pointing away from the axis. The apex has no normal of
+    // its own, so it faces down the axis
+    *normal = if p.y > CONE_EPS {
+        Vector3::new(p.x, -p.y, p.z)
     } else {
-        false
-    }
+        Vector3::new(0.0, -1.0, 0.0)
+    };
+    *uv = [around_y(p.x, p.z), p.y];
+    true
 }
 
 fn cylinder_collides(
@@ -192,109 +354,102 @@ fn cylinder_collides(
     let b = 2.0f32 * ((src.x * dir.x) + (src.z * dir.z));
     let c = (src.x * src.x) + (src.z * src.z) - 1.0f32;
 
-    let mut intercept_cap = false;
-    // closest cap
-    let mut cap_normal = Vector3::new(0.0, 1.0, 0.0);
-    let closest_root = match find_roots_quadratic(a, b, c) {
-        Roots::One([r1]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 1.0 {
-                r1
-            } else {
-                return false;
+    // Rays starting inside the cylinder, like refracted rays, leave through the far wall or cap
+    let on_side = |t: &f32| (0.0..=1.0).contains(&(ray.src.y + t * ray.dir.y));
+    let side = nearest_root(find_roots_quadratic(a, b, c), CYLINDER_EPS, on_side);
+    let mut cap: Option<(f32, f32)> = None;
+    if dir.y != 0.0 {
+        for h in [0.0f32, 1.0].iter() {
+            let t = (h - src.y) / dir.y;
+            let p = ray.src + (t * ray.dir);
+            let in_disk = (p.x * p.x) + (p.z * p.z) <= 1.0;
+            if t > CYLINDER_EPS && in_disk && cap.is_none_or(|(cap_t, _)| t < cap_t) {
+                cap = Some((t, *h));
             }
         }
-        Roots::Two([r1, r2]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            let i_2 = ray.src + (r2 * ray.dir);
-            // Although r1 <= r2, no guarantees about y1 and y2
-            let y1 = i_1.y;
-            let y2 = i_2.y;
-
-            if (y1 < 0.0 && y2 < 0.0) || (y1 > 1.0 && y2 > 1.0) {
-                // Pass over or under the cylinder
-                return false;
-            } else if y1 >= 0.0 && y1 <= 1.0 {
-                // First intercept hits the cylinder
-                r1
-            } else if y1 < 0.0 {
-                // Hit the bottom cap
-                cap_normal = Vector3::new(0.0, -1.0, 0.0);
-                intercept_cap = true;
-                -src.y / dir.y
-            } else if y1 > 1.0 {
-                // Hit the top cap
-                intercept_cap = true;
-                (1.0 - src.y) / dir.y
+    }
+
+    match (side, cap) {
+        (Some(t), cap) if cap.is_none_or(|(cap_t, _)| t <= cap_t) => {
+            let p = ray.src + (t * ray.dir);
+            *t_value = t;
+            *normal = Vector3::new(p.x, 0.0f32, p.z);
+            uv[0] = around_y(p.x, p.z);
+            uv[1] = p.y;
+            true
+        }
+        (_, Some((t, h))) => {
+            let p = ray.src + (t * ray.dir);
+            *t_value = t;
+            // The unit disk of the cap fills the texture
+            uv[0] = (p.x + 1.0) / 2.0;
+            uv[1] = (p.z + 1.0) / 2.0;
+            *normal = if h > 0.5 {
+                Vector3::new(0.0, 1.0, 0.0)
             } else {
-                // Hit both caps
-                // TODO
-                return false;
-            }
+                Vector3::new(0.0, -1.0, 0.0)
+            };
+            true
         }
-        _ => return false,
-    };
+        _ => false,
+    }
+}
 
-    if closest_root > CYLINDER_EPS {
-        let intersection_point = ray.src + (closest_root * ray.dir);
-        *t_value = closest_root;
-        if intercept_cap {
-            uv[0] = 0.0f32;
-            uv[1] = 0.0f32;
-            *normal = cap_normal;
-        } else {
-            *normal = Vector3::new(intersection_point.x, 0.0f32, intersection_point.z);
-            let u = normal.x.atan2(normal.z) / f32::consts::PI + 2.0; //atan2(n.x, n.z) / (2*pi) + 0.5;
-            let v = intersection_point.y; //atan2(n.x, n.z) / (2*pi) + 0.5;
-                                          // println!("u: {}, v: {}", u, v);
-            (*uv)[0] = u;
-            (*uv)[1] = v;
+// The closest root in front of the ray that lands on the shape, roots come sorted
+fn nearest_root<F: Fn(&f32) -> bool>(roots: Roots<f32>, eps: f32, on_shape: F) -> Option<f32> {
+    roots
+        .as_ref()
+        .iter()
+        .cloned()
+        .find(|t| *t > eps && on_shape(t))
+}
+
+fn mesh_collides(
+    ray: &Ray,
+    mesh: &Mesh,
+    t_value: &mut f32,
+    normal: &mut Vector3<f32>,
+    uv: &mut [f32; 2],
+    tangents: &mut Option<(Vector3<f32>, Vector3<f32>)>,
+) -> bool {
+    let mut smallest_t = f32::MAX;
+    let mut smallest_normal = Vector3::new(0.0f32, 0.0f32, 0.0f32);
+    let mut smallest_face = 0;
+    let mut smallest_barycentric = [0.0; 3];
+    let mut t = 0.0;
+    let mut n = smallest_normal;
+    let mut barycentric = [0.0; 3];
+
+    mesh.bvh.traverse(ray, f32::MAX, |face| {
+        if triangle_collides(ray, &mesh.triangle(face), &mut t, &mut n, &mut barycentric) && t < smallest_t {
+            smallest_t = t;
+            smallest_normal = n;
+            smallest_face = face;
+            smallest_barycentric = barycentric;
+            return Some(t);
         }
-        true
-    } else {
-        false
+        None
+    });
+
+    if smallest_t == f32::MAX {
+        return false;
     }
-}
 
-// fn mesh_collides(
-//     ray: &Ray,
-//     mesh: &Mesh,
-//     t_value: &mut f32,
-//     normal: &mut Vector3<f32>,
-//     uv: &mut [f32; 2],
-// ) -> bool {
-//     if aabb_collision(ray, &mesh.aabb_corner, &mesh.aabb_size) == Roots::No([]) {
-//         return false;
-//     }
-
-//     let mut smallest_t = f32::MAX;
-//     let mut smallest_normal = Vector3::new(0.0f32, 0.0f32, 0.0f32);
-//     let mut triangle = [smallest_normal, smallest_normal, smallest_normal];
-
-//     for face in mesh.faces.iter() {
-//         triangle[0] = mesh.vertices[face[0]];
-//         triangle[1] = mesh.vertices[face[1]];
-//         triangle[2] = mesh.vertices[face[2]];
-
-//         if triangle_collides(ray, &triangle, t_value, normal) {
-//             if *t_value < smallest_t {
-//                 smallest_t = *t_value;
-//                 smallest_normal = *normal;
-//             }
-//         }
-//     }
-
-//     if smallest_t < f32::MAX {
-//         let intersect = ray.src + (smallest_t * ray.dir);
-//         if intersect.x < 0.0 {
-//             uv[0] = 1.0 - intersect.x;
-//         } else {
-//             uv[0] = intersect.x;
-//         }
-//         uv[1] = intersect.z;
-//     }
-
-//     *normal = smallest_normal;
-//     *t_value = smallest_t;
-//     smallest_t < f32::MAX
-// }
+    *uv = match mesh.uv_at(smallest_face, &smallest_barycentric) {
+        Some(uv) => uv,
+        None => {
+            // Projected down onto the bounding box from above
+            let intersect = ray.src + (smallest_t * ray.dir);
+            let size = mesh.aabb_size.map(|s| s.max(CLOSE_EPS));
+            [
+                (intersect.x - mesh.aabb_corner.x) / size.x,
+                (intersect.z - mesh.aabb_corner.z) / size.z,
+            ]
+        }
+    };
+    *tangents = mesh.tangents(smallest_face);
+
+    *normal = mesh.normal_at(smallest_face, &smallest_barycentric, &smallest_normal);
+    *t_value = smallest_t;
+    true
+}
@@ -0,0 +1,176 @@
+// STL files, what CAD tools and slicers trade in. Both the ASCII and binary layouts are read,
+// the facet normals they carry are ignored since so many files get them wrong
+
+use crate::geometry::bytes::{ByteReader, Endian};
+use crate::geometry::mesh::{Face, Mesh};
+use nalgebra::Vector3;
+use std::collections::HashMap;
+
+// Binary files start with an 80 byte header and the triangle count
+const HEADER_SIZE: usize = 84;
+// Normal, three corners and two bytes of attributes
+const TRIANGLE_SIZE: usize = 50;
+
+pub(crate) fn parse(bytes: &[u8]) -> Result<Mesh, String> {
+    // Some binary files start with "solid" too, the size tells them apart. In ASCII files the
+    // count is made of text, which can be too big to have a size at all
+    let binary_size = if bytes.len() >= HEADER_SIZE {
+        let count = ByteReader::new(&bytes[80..], Endian::Little).u32()? as usize;
+        count
+            .checked_mul(TRIANGLE_SIZE)
+            .and_then(|n| n.checked_add(HEADER_SIZE))
+    } else {
+        None
+    };
+    let corners = if binary_size == Some(bytes.len()) {
+        parse_binary(bytes)?
+    } else if bytes.starts_with(b"solid") {
+        parse_ascii(bytes)?
+    } else if let Some(size) = binary_size {
+        return Err(format!("the file should be {} bytes for its triangle count, but is {}", size, bytes.len()));
+    } else {
+        return Err("not an STL file, it can't be binary and doesn't start with 'solid'".to_string());
+    };
+
+    // Each triangle has its own copy of its corners, share the ones in the same place
+    let mut vertices = vec![];
+    let mut index: HashMap<[u32; 3], usize> = HashMap::new();
+    let mut faces = vec![];
+    for triangle in corners.chunks(3) {
+        let mut face = [0; 3];
+        for (f, v) in face.iter_mut().zip(triangle.iter()) {
+            let key = [(v.x + 0.0).to_bits(), (v.y + 0.0).to_bits(), (v.z + 0.0).to_bits()];
+            *f = *index.entry(key).or_insert_with(|| {
+                vertices.push(*v);
+                vertices.len() - 1
+            });
+        }
+        faces.push(Face::new(face));
+    }
+    Mesh::from_parts(vertices, vec![], vec![], faces)
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Vec<Vector3<f32>>, String> {
+    let mut reader = ByteReader::new(&bytes[HEADER_SIZE..], Endian::Little);
+    let count = (bytes.len() - HEADER_SIZE) / TRIANGLE_SIZE;
+    let mut corners = Vec::with_capacity(count * 3);
+    let vector = |reader: &mut ByteReader| -> Result<Vector3<f32>, String> {
+        Ok(Vector3::new(reader.f32()?, reader.f32()?, reader.f32()?))
+    };
+    for _ in 0..count {
+        vector(&mut reader)?;
+        for _ in 0..3 {
+            corners.push(vector(&mut reader)?);
+        }
+        reader.u16()?;
+    }
+    Ok(corners)
+}
+
+fn parse_ascii(bytes: &[u8]) -> Result<Vec<Vector3<f32>>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "the file isn't text or binary STL".to_string())?;
+    let mut corners = vec![];
+    let mut in_loop = 0;
+    for (n, line) in text.lines().enumerate() {
+        let error = |e: String| format!("line {}: {}", n + 1, e);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.first() {
+            Some(&"vertex") => {
+                if parts.len() < 4 {
+                    return Err(error(format!("a vertex needs 3 coordinates, got {}", parts.len() - 1)));
+                }
+                let mut v = [0.0; 3];
+                for (x, part) in v.iter_mut().zip(parts[1..].iter()) {
+                    *x = part.parse().map_err(|_| error(format!("expected a number, got '{}'", part)))?;
+                }
+                corners.push(Vector3::new(v[0], v[1], v[2]));
+                in_loop += 1;
+            }
+            Some(&"outer") => in_loop = 0,
+            Some(&"endloop") if in_loop != 3 => {
+                return Err(error(format!("a facet needs 3 vertices, got {}", in_loop)));
+            }
+            // solid, facet, endfacet and endsolid
+            _ => {}
+        }
+    }
+    if corners.len() % 3 != 0 {
+        return Err("the last facet isn't finished".to_string());
+    }
+    Ok(corners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASCII: &str = "solid square
+facet normal 0 0 1
+  outer loop
+    vertex 0 0 0
+    vertex 1 0 0
+    vertex 1 1 0
+  endloop
+endfacet
+facet normal 0 0 1
+  outer loop
+    vertex 0 0 0
+    vertex 1 1 0
+    vertex 0 1 0
+  endloop
+endfacet
+endsolid square
+";
+
+    fn binary(triangles: &[[[f32; 3]; 3]]) -> Vec<u8> {
+        // Starting with "solid" like some exporters do, the size has to tell them apart
+        let mut bytes = b"solid binary".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend_from_slice(&(triangles.len() as u32).to_le_bytes());
+        for triangle in triangles {
+            bytes.extend_from_slice(&[0; 12]);
+            for corner in triangle {
+                for x in corner {
+                    bytes.extend_from_slice(&x.to_le_bytes());
+                }
+            }
+            bytes.extend_from_slice(&[0; 2]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_ascii() {
+        let mesh = parse(ASCII.as_bytes()).unwrap();
+        // The shared corners are merged
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.faces.len(), 2);
+        assert_eq!(mesh.faces[1].vertices, [0, 2, 3]);
+    }
+
+    #[test]
+    fn parses_binary() {
+        let bytes = binary(&[
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+            [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+        ]);
+        let mesh = parse(&bytes).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.faces.len(), 2);
+        assert_eq!(mesh.vertices[3], Vector3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let bytes = binary(&[[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]]);
+        assert!(parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(parse(b"not an stl file").is_err());
+        // A count whose size doesn't fit in a usize on 32-bit targets
+        let mut huge = vec![0; HEADER_SIZE];
+        huge[80..84].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&huge).is_err());
+        let unfinished = ASCII.replace("    vertex 0 1 0\n", "");
+        assert!(parse(unfinished.as_bytes()).is_err());
+        assert!(parse(ASCII.replace("vertex 1 0 0", "vertex 1 x 0").as_bytes()).is_err());
+    }
+}
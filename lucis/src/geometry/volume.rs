@@ -112,8 +112,7 @@ fn light_apply(
             distance * 0.2
         }
     }
-    .max(0.0)
-    .min(0.7);
+    .clamp(0.0, 0.7);
 
     // if intensity > 0.0 {
     //     return Color::new(1.0, 0.0, 0.0);
@@ -164,8 +163,7 @@ fn fog_apply(
             distance * 0.03
         }
     }
-    .max(0.0)
-    .min(1.0);
+    .clamp(0.0, 1.0);
 
     (fog_amount * fog_color) + ((1.0 - fog_amount) * curr_color)
 }
@@ -228,10 +226,10 @@ fn cone_passes_through(cone: &ConeParams, ray: &Ray) -> Option<VolumeIntersectio
     let c = (src.x * src.x) + (src.z * src.z) - (src.y * src.y);
 
     match find_roots_quadratic(a, b, c) {
-        Roots::One(_) => return None,
+        Roots::One(_) => None,
         Roots::Two([r1, r2]) => {
             let i_1 = transformed_ray.src + (r1 * transformed_ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 3.0 {
+            if (0.0..=3.0).contains(&i_1.y) {
                 let i_2 = transformed_ray.src + (r2 * transformed_ray.dir);
                 Some(VolumeIntersection::new(
                     r1,
@@ -280,6 +278,22 @@ impl ConeParams {
         cone_params
     }
 
+    // The cone x² + z² = y² between y = 0 and 3, placed by a transform instead of where `new`
+    // puts it
+    pub fn from_matrix(transform: Matrix4<f32>) -> ConeParams {
+        let mut cone_params = ConeParams {
+            pos: Vector3::new(transform[(0, 3)], transform[(1, 3)], transform[(2, 3)]),
+            scale_y: 1.0,
+            rot_x: 0.0,
+            rot_y: 0.0,
+            rot_z: 0.0,
+            transform: Affine3::identity(),
+            inv_transform: Affine3::identity(),
+        };
+        cone_params.apply_transform(transform);
+        cone_params
+    }
+
     fn scale(&mut self, x: f32, y: f32, z: f32) {
         self.apply_transform(Matrix4::new_nonuniform_scaling(&Vector3::new(x, y, z)));
     }
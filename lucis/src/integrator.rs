@@ -0,0 +1,473 @@
+use crate::camera::concentric_disk;
+use crate::geometry::Ray;
+use crate::raytrace::{fresnel, get_environment_color, SECONDARY_RAY_EPS};
+use crate::scene::{tangent_frame, Color, Intersection, Material, Microfacet};
+use crate::Raytracer;
+use nalgebra::{Point3, Vector3};
+use rand::rngs::ThreadRng;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::f32;
+use wasm_bindgen::prelude::*;
+
+// How the Raytracer works out the light arriving along a ray
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    // Phong shading from the lights plus an ambient term, following mirror reflections and
+    // refractions. Fast and noise free
+    #[default]
+    Direct,
+    // Monte Carlo path tracing with indirect light bouncing between surfaces. Noisy, render
+    // several samples per pixel or passes to let it converge
+    PathTracer,
+}
+
+// Paths are never cut short by Russian roulette before this many bounces
+const ROULETTE_MIN_DEPTH: u32 = 3;
+// Highest chance of a path surviving Russian roulette, so bright paths still end
+const ROULETTE_MAX_SURVIVAL: f32 = 0.95;
+
+impl Raytracer {
+    // Follow a path from its first hit until it leaves the scene or is terminated, adding up
+    // the light reaching each vertex from the lights and the sky
+    pub(crate) fn trace_path(&self, ray: &Ray, first_hit: &Intersection) -> Color {
+        let mut rng = thread_rng();
+        let mut radiance = Vector3::new(0.0f32, 0.0, 0.0);
+        let mut throughput = Vector3::new(1.0f32, 1.0, 1.0);
+        let mut ray = *ray;
+        let mut hit = *first_hit;
+        // Light from emissive surfaces is picked up by sampling them at every bounce, except
+        // after mirrors and glass which can't be sampled that way
+        let mut count_emission = true;
+
+        // The first hit is always shaded, max_depth only limits the bounces after it
+        for depth in 0..=self.max_depth {
+            if count_emission && hit.entering {
+                radiance += throughput.component_mul(&self.scene.emission(&hit).into());
+            }
+            count_emission = false;
+
+            let (material, surface) = self.scene.surface_at(&hit);
+            hit = surface;
+            let transparent = matches!(material, Material::Dielectric { .. });
+            let next = match material {
+                Material::PhongMaterial {
+                    kd,
+                    ks,
+                    shininess,
+                    reflectivity,
+                } => {
+                    let d = ray.dir.normalize();
+                    let n = facing(&hit.normal, &d);
+
+                    // The Direct integrator adds the mirror reflection on top of the local
+                    // shading, pick one of the two with the matching weights
+                    let mirror_chance = max_channel(&reflectivity.into());
+                    if mirror_chance > 0.0 && rng.gen::<f32>() < mirror_chance {
+                        throughput = throughput.component_mul(&reflectivity.into()) / mirror_chance;
+                        count_emission = true;
+                        Some(Ray::new(hit.point + n * SECONDARY_RAY_EPS, reflect(&d, &n)))
+                    } else {
+                        throughput /= 1.0 - mirror_chance;
+                        radiance += throughput.component_mul(&self.direct_light(&hit, &n, &-d, &material, 1));
+                        self.sample_phong(&mut throughput, &hit.point, &n, &-d, kd, ks, shininess, &mut rng)
+                    }
+                }
+                Material::Pbr {
+                    base_color,
+                    metallic,
+                    roughness,
+                } => {
+                    let wo = -ray.dir.normalize();
+                    let n = facing(&hit.normal, &-wo);
+                    let microfacet = Microfacet::new(base_color, metallic, roughness);
+                    radiance += throughput.component_mul(&self.direct_light(&hit, &n, &wo, &material, 1));
+                    sample_microfacet(&mut throughput, &hit.point, &n, &wo, &microfacet, &mut rng)
+                }
+                Material::Dielectric { ior, absorption } => {
+                    // Beer-Lambert over the distance travelled inside
+                    if !hit.entering {
+                        let distance = (hit.point - ray.src).norm();
+                        throughput.x *= (-absorption.r * distance).exp();
+                        throughput.y *= (-absorption.g * distance).exp();
+                        throughput.z *= (-absorption.b * distance).exp();
+                    }
+                    count_emission = true;
+                    Some(sample_dielectric(&ray, &hit, ior, &mut rng))
+                }
+                Material::None => None,
+            };
+            let next = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            if depth == self.max_depth {
+                // Out of bounces, glass shows the sky behind it like with the Direct integrator
+                if transparent {
+                    radiance += throughput.component_mul(&get_environment_color(&next.dir).into());
+                }
+                break;
+            }
+
+            if depth + 1 >= ROULETTE_MIN_DEPTH {
+                let survival = max_channel(&throughput).min(ROULETTE_MAX_SURVIVAL);
+                if rng.gen::<f32>() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+
+            match self.intersects(&next) {
+                Some(next_hit) => hit = next_hit,
+                None => {
+                    radiance += throughput.component_mul(&get_environment_color(&next.dir).into());
+                    break;
+                }
+            }
+            ray = next;
+        }
+
+        Color::from(radiance)
+    }
+
+    // Next event estimation, the light arriving straight from the lights and emissive nodes
+    // at a hit and scattered towards `wo`. `n` is the normal on the side of `wo`, and each
+    // emitter is sampled `emitter_samples` times
+    pub(crate) fn direct_light(
+        &self,
+        hit: &Intersection,
+        n: &Vector3<f32>,
+        wo: &Vector3<f32>,
+        material: &Material,
+        emitter_samples: u32,
+    ) -> Vector3<f32> {
+        self.sample_lights(&hit.point, n, wo, material)
+            + self.sample_emitters(hit, n, wo, material, emitter_samples)
+    }
+
+    fn sample_lights(&self, point: &Point3<f32>, n: &Vector3<f32>, wo: &Vector3<f32>, material: &Material) -> Vector3<f32> {
+        let mut rng = thread_rng();
+        let mut sum = Vector3::new(0.0f32, 0.0, 0.0);
+        for light in self.lights.iter() {
+            // Soft lights are covered by picking one of their sample points each time
+            if light.light_samples.is_empty() {
+                continue;
+            }
+            let light_point = light.light_samples[rng.gen_range(0, light.light_samples.len())];
+            let to_light = light_point - point;
+            let distance = to_light.norm();
+            let l = to_light / distance;
+            let cos_theta = n.dot(&l);
+            if cos_theta <= 0.0 {
+                continue;
+            }
+
+            let shadow_ray = Ray::new(point + n * SECONDARY_RAY_EPS, l);
+            if let Some(blocker) = self.intersects(&shadow_ray) {
+                if (blocker.point - point).norm() < distance {
+                    continue;
+                }
+            }
+
+            // Lights are scaled so a white diffuse surface gets the same light as with the
+            // Direct integrator, which leaves out the 1 / pi of the Lambert BRDF
+            let f = match material.brdf(n, wo, &l) {
+                Some(f) => f * f32::consts::PI,
+                None => continue,
+            };
+            let attenuation =
+                light.falloff[0] + (light.falloff[1] * distance) + (light.falloff[2] * distance * distance);
+            sum += Vector3::from(light.color).component_mul(&f) * (cos_theta / attenuation);
+        }
+        sum
+    }
+
+    // Light from points picked on the surface of every emissive node
+    pub(crate) fn sample_emitters(
+        &self,
+        hit: &Intersection,
+        n: &Vector3<f32>,
+        wo: &Vector3<f32>,
+        material: &Material,
+        samples: u32,
+    ) -> Vector3<f32> {
+        let mut rng = thread_rng();
+        let mut sum = Vector3::new(0.0f32, 0.0, 0.0);
+        for emitter in self.emitters.iter() {
+            // A convex surface can't light itself
+            if emitter.node_id == hit.node_id {
+                continue;
+            }
+            for _ in 0..samples {
+                let sample = match emitter.sample(&mut rng) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+                let to_light = sample.point - hit.point;
+                let distance = to_light.norm();
+                let l = to_light / distance;
+                let cos_theta = n.dot(&l);
+                // Emitters only glow out of their front
+                let cos_light = -sample.normal.dot(&l);
+                if cos_theta <= 0.0 || cos_light <= 0.0 {
+                    continue;
+                }
+
+                // Where the shadow ray lands on the emitter is where its emission texture is read
+                let shadow_ray = Ray::new(hit.point + n * SECONDARY_RAY_EPS, l);
+                let emission = match self.intersects(&shadow_ray) {
+                    Some(blocker) if blocker.node_id == emitter.node_id => Vector3::from(self.scene.emission(&blocker)),
+                    Some(blocker) if (blocker.point - hit.point).norm() < distance => continue,
+                    _ => Vector3::from(emitter.emission),
+                };
+
+                // The emitter's radiance is already scaled like the lights, the BRDF isn't
+                let f = match material.brdf(n, wo, &l) {
+                    Some(f) => f,
+                    None => continue,
+                };
+                // Convert the density over the emitter's area to one over directions
+                let pdf = sample.pdf * distance * distance / cos_light;
+                sum += emission.component_mul(&f) * (cos_theta / pdf);
+            }
+        }
+        sum / samples.max(1) as f32
+    }
+
+    // Pick the next direction off a Phong surface, choosing between the diffuse and glossy
+    // lobes by how bright they are, and update the throughput to match
+    #[allow(clippy::too_many_arguments)]
+    fn sample_phong(
+        &self,
+        throughput: &mut Vector3<f32>,
+        point: &Point3<f32>,
+        n: &Vector3<f32>,
+        wo: &Vector3<f32>,
+        kd: Color,
+        ks: Color,
+        shininess: f32,
+        rng: &mut ThreadRng,
+    ) -> Option<Ray> {
+        let kd = Vector3::from(kd);
+        let ks = Vector3::from(ks);
+        let diffuse_weight = kd.x + kd.y + kd.z;
+        let glossy_weight = ks.x + ks.y + ks.z;
+        if diffuse_weight + glossy_weight <= 0.0 {
+            return None;
+        }
+        let diffuse_chance = diffuse_weight / (diffuse_weight + glossy_weight);
+
+        let dir = if rng.gen::<f32>() < diffuse_chance {
+            // Cosine weighted, which cancels out the cosine and 1 / pi of the Lambert BRDF
+            *throughput = throughput.component_mul(&kd) / diffuse_chance;
+            cosine_hemisphere(n, rng)
+        } else {
+            // Around the mirror direction with the shape of the normalized Phong lobe, all
+            // that's left of the BRDF * cos / pdf is (n + 2) / (n + 1) * cos
+            let r = reflect(&-wo, n);
+            let cos_alpha = rng.gen::<f32>().powf(1.0 / (shininess + 1.0));
+            let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+            let phi = 2.0 * f32::consts::PI * rng.gen::<f32>();
+            let dir = from_local(
+                &r,
+                &Vector3::new(sin_alpha * phi.cos(), sin_alpha * phi.sin(), cos_alpha),
+            );
+            let cos_theta = dir.dot(n);
+            if cos_theta <= 0.0 {
+                // Sampled below the surface
+                return None;
+            }
+            *throughput = throughput.component_mul(&ks) * ((shininess + 2.0) / (shininess + 1.0) * cos_theta)
+                / (1.0 - diffuse_chance);
+            dir
+        };
+        Some(Ray::new(point + n * SECONDARY_RAY_EPS, dir))
+    }
+}
+
+// Pick the next direction off a metallic/roughness surface, from either the GGX lobe or a
+// cosine weighted one, and weigh it by the combined density of both
+fn sample_microfacet(
+    throughput: &mut Vector3<f32>,
+    point: &Point3<f32>,
+    n: &Vector3<f32>,
+    wo: &Vector3<f32>,
+    microfacet: &Microfacet,
+    rng: &mut ThreadRng,
+) -> Option<Ray> {
+    // Metals are all specular, the rest mostly diffuse at normal incidence
+    let specular_chance = 0.5 + 0.5 * microfacet.metallic;
+    let wi = if rng.gen::<f32>() < specular_chance {
+        let h = microfacet.sample_half_vector(n, rng.gen(), rng.gen());
+        reflect(&-wo, &h)
+    } else {
+        cosine_hemisphere(n, rng)
+    };
+
+    let cos_theta = n.dot(&wi);
+    if cos_theta <= 0.0 {
+        return None;
+    }
+    let pdf = specular_chance * microfacet.specular_pdf(n, wo, &wi)
+        + (1.0 - specular_chance) * cos_theta / f32::consts::PI;
+    if pdf <= 0.0 {
+        return None;
+    }
+    *throughput = throughput.component_mul(&microfacet.eval(n, wo, &wi)) * (cos_theta / pdf);
+    Some(Ray::new(point + n * SECONDARY_RAY_EPS, wi))
+}
+
+fn cosine_hemisphere(n: &Vector3<f32>, rng: &mut ThreadRng) -> Vector3<f32> {
+    let (x, y) = concentric_disk(rng.gen(), rng.gen());
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    from_local(n, &Vector3::new(x, y, z))
+}
+
+// Either reflect or refract off a dielectric, with the chance of reflecting given by the
+// Fresnel equations. The throughput is left as is since the choice follows the weights
+fn sample_dielectric(ray: &Ray, hit: &Intersection, ior: f32, rng: &mut ThreadRng) -> Ray {
+    let d = ray.dir.normalize();
+    let (n, eta_i, eta_t) = if hit.entering {
+        (hit.normal.normalize(), 1.0, ior)
+    } else {
+        (-hit.normal.normalize(), ior, 1.0)
+    };
+    let cos_i = (-d.dot(&n)).clamp(0.0, 1.0);
+    let eta = eta_i / eta_t;
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+
+    if sin2_t < 1.0 {
+        let cos_t = (1.0 - sin2_t).sqrt();
+        if rng.gen::<f32>() >= fresnel(cos_i, cos_t, eta_i, eta_t) {
+            return Ray::new(hit.point - n * SECONDARY_RAY_EPS, eta * d + (eta * cos_i - cos_t) * n);
+        }
+    }
+    Ray::new(hit.point + n * SECONDARY_RAY_EPS, d + 2.0 * cos_i * n)
+}
+
+// The normal flipped onto the side of the surface that `d` arrives from
+fn facing(normal: &Vector3<f32>, d: &Vector3<f32>) -> Vector3<f32> {
+    let n = normal.normalize();
+    if n.dot(d) > 0.0 {
+        -n
+    } else {
+        n
+    }
+}
+
+fn reflect(d: &Vector3<f32>, n: &Vector3<f32>) -> Vector3<f32> {
+    d - 2.0 * d.dot(n) * n
+}
+
+// Rotate a direction given around +Z onto the frame around `axis`
+fn from_local(axis: &Vector3<f32>, v: &Vector3<f32>) -> Vector3<f32> {
+    let w = axis.normalize();
+    let (t, b) = tangent_frame(&w);
+    t * v.x + b * v.y + w * v.z
+}
+
+fn max_channel(v: &Vector3<f32>) -> f32 {
+    v.x.max(v.y).max(v.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Primitive;
+    use crate::camera::Camera;
+    use crate::scene::{Light, MaterialWrapper, Scene, SceneNodeRef};
+
+    // A sphere filling the middle of the view, lit from the camera
+    fn raytracer(integrator: Integrator, max_depth: u32, material: &MaterialWrapper) -> Raytracer {
+        let mut scene = Scene::new();
+        let mut root = scene.add_node(Primitive::None, "root".to_string());
+        let mut ball = scene.add_node(Primitive::Sphere, "ball".to_string());
+        ball.set_material(material);
+        ball.translate(0.0, 0.0, -5.0);
+        root.add_child(&ball);
+
+        let mut raytracer = Raytracer::new(scene);
+        raytracer.set_integrator(integrator);
+        raytracer.set_max_depth(max_depth);
+        raytracer.set_ambient(Color::new(0.0, 0.0, 0.0));
+        let white = Color::new(1.0, 1.0, 1.0);
+        raytracer.set_lights(vec![Light::new(white, Point3::origin(), [1.0, 0.0, 0.0], 0.0, 1)]);
+        raytracer
+    }
+
+    fn center(raytracer: &Raytracer) -> [u8; 3] {
+        let framebuffer = raytracer.render(1, 1).unwrap();
+        let bytes = framebuffer.as_bytes();
+        [bytes[0], bytes[1], bytes[2]]
+    }
+
+    #[test]
+    fn first_hit_is_shaded_without_bounces() {
+        let black = Color::new(0.0, 0.0, 0.0);
+        let diffuse = MaterialWrapper::new(&Color::new(0.8, 0.2, 0.2), &black, 1.0);
+        let glass = MaterialWrapper::dielectric(1.5, &black);
+        for integrator in [Integrator::Direct, Integrator::PathTracer] {
+            for material in [&diffuse, &glass] {
+                let color = center(&raytracer(integrator, 0, material));
+                assert_ne!(color, [0, 0, 0], "{:?} with {:?}", integrator, material);
+            }
+        }
+    }
+
+    fn node(scene: &mut Scene, primitive: Primitive, material: &MaterialWrapper, scale: [f32; 3], at: [f32; 3]) -> SceneNodeRef {
+        let mut node = scene.add_node(primitive, "node".to_string());
+        node.set_material(material);
+        node.scale(scale[0], scale[1], scale[2]);
+        node.translate(at[0], at[1], at[2]);
+        node
+    }
+
+    // A patch looked at from above with a glowing ball over it, all inside a black room so
+    // the path tracer doesn't pick up the sky
+    fn under_lamp(integrator: Integrator, material: &MaterialWrapper) -> Raytracer {
+        let black = Color::new(0.0, 0.0, 0.0);
+        let mut lamp = MaterialWrapper::new(&black, &black, 0.0);
+        lamp.set_emission(&Color::new(0.3, 0.3, 0.3));
+
+        let mut scene = Scene::new();
+        let mut root = scene.add_node(Primitive::None, "root".to_string());
+        let nodes = [
+            node(&mut scene, Primitive::Sphere, &lamp, [1.0, 1.0, 1.0], [0.0, 2.0, 0.0]),
+            node(&mut scene, Primitive::Cube, &MaterialWrapper::new(&black, &black, 0.0), [20.0; 3], [-10.0; 3]),
+            node(&mut scene, Primitive::Cube, material, [2.0, 0.01, 2.0], [-1.0, -0.01, -1.0]),
+        ];
+        for node in nodes.iter() {
+            root.add_child(node);
+        }
+
+        let mut raytracer = Raytracer::new(scene);
+        raytracer.set_integrator(integrator);
+        raytracer.set_ambient(black);
+        raytracer.set_lights(Vec::new());
+        raytracer.set_samples_per_pixel(4096);
+        let mut camera = Camera::look_at_points(Point3::new(0.0, 1.0, 0.4), Point3::origin(), Vector3::y());
+        camera.set_fov(5.0);
+        raytracer.set_camera(&camera);
+        raytracer
+    }
+
+    #[test]
+    fn mirrored_emitter_agrees_with_the_light_it_gives() {
+        let black = Color::new(0.0, 0.0, 0.0);
+        let white = MaterialWrapper::new(&Color::new(1.0, 1.0, 1.0), &black, 0.0);
+        let mut mirror = MaterialWrapper::new(&black, &black, 0.0);
+        mirror.set_reflectivity(&Color::new(1.0, 1.0, 1.0));
+        for integrator in [Integrator::Direct, Integrator::PathTracer] {
+            let lit = center(&under_lamp(integrator, &white))[0] as f32;
+            let seen = center(&under_lamp(integrator, &mirror))[0] as f32;
+            // A ball of radius 1 centered 2 above the patch fills a quarter of its
+            // cosine-weighted sky, so a white patch reflects a quarter of the ball's radiance
+            let expected = seen / 4.0;
+            assert!(seen > 0.0, "{:?}", integrator);
+            assert!((lit - expected).abs() < 0.15 * expected, "{:?}: lit {} but mirrored {}", integrator, lit, seen);
+        }
+    }
+}
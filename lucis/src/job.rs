@@ -0,0 +1,174 @@
+use crate::framebuffer::Framebuffer;
+use crate::tile::{Tile, TiledRender};
+use wasm_bindgen::prelude::*;
+use web_sys::HtmlCanvasElement;
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Paused,
+    Cancelled,
+    Done,
+}
+
+// A tiled render that can be paused, resumed or cancelled between tiles
+#[wasm_bindgen]
+pub struct RenderJob {
+    render: TiledRender,
+    state: JobState,
+}
+
+impl RenderJob {
+    pub fn new(render: TiledRender) -> RenderJob {
+        let state = if render.is_done() {
+            JobState::Done
+        } else {
+            JobState::Running
+        };
+        RenderJob { render, state }
+    }
+}
+
+#[wasm_bindgen]
+impl RenderJob {
+    #[wasm_bindgen(getter)]
+    pub fn state(&self) -> JobState {
+        self.state
+    }
+
+    // Stop the job for good, the tiles rendered so far are kept
+    pub fn cancel(&mut self) {
+        if self.state != JobState::Done {
+            self.state = JobState::Cancelled;
+        }
+    }
+
+    pub fn pause(&mut self) {
+        if self.state == JobState::Running {
+            self.state = JobState::Paused;
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.state == JobState::Paused {
+            self.state = JobState::Running;
+        }
+    }
+
+    // True once the job is done or has been cancelled, it will not render anything else
+    pub fn is_finished(&self) -> bool {
+        self.state == JobState::Done || self.state == JobState::Cancelled
+    }
+
+    // Fraction of tiles rendered so far, between 0 and 1
+    pub fn progress(&self) -> f32 {
+        self.render.progress()
+    }
+
+    // Render the next tile if the job is running. Returns the tile that was rendered,
+    // or None if the job is paused, cancelled or has no tiles left
+    pub fn step(&mut self) -> Option<Tile> {
+        if self.state != JobState::Running {
+            return None;
+        }
+        let tile = self.render.step();
+        if self.render.is_done() {
+            self.state = JobState::Done;
+        }
+        tile
+    }
+
+    // Hand out the next tile to be traced elsewhere, e.g. by a pool of web workers running
+    // their own copy of the scene. Nothing is handed out unless the job is running
+    pub fn take_tile(&mut self) -> Option<Tile> {
+        if self.state != JobState::Running {
+            return None;
+        }
+        self.render.take_tile()
+    }
+
+    // Trace a tile and return the RGB radiance of its pixels. This works in any state, so a
+    // worker can trace tiles for a job running on another thread
+    pub fn trace_tile(&self, tile: &Tile) -> Result<Vec<f32>, JsValue> {
+        self.render.trace_tile(tile)
+    }
+
+    // Store the radiance of a tile handed out by `take_tile`
+    pub fn complete_tile(&mut self, tile: &Tile, radiance: Vec<f32>) -> Result<(), JsValue> {
+        self.render.complete_tile(tile, radiance)?;
+        if self.render.is_done() {
+            self.state = JobState::Done;
+        }
+        Ok(())
+    }
+
+    // Copy of the image rendered so far
+    pub fn framebuffer(&self) -> Framebuffer {
+        self.render.framebuffer()
+    }
+
+    pub fn present(&self, canvas: &HtmlCanvasElement) -> Result<(), JsValue> {
+        self.render.present(canvas)
+    }
+
+    pub fn present_to(&self, canvas_id: &str) -> Result<(), JsValue> {
+        self.render.present_to(canvas_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Primitive;
+    use crate::scene::Scene;
+    use crate::tile::TileOrder;
+    use crate::Raytracer;
+
+    // Four tiles of an empty scene
+    fn job() -> RenderJob {
+        let mut scene = Scene::new();
+        scene.add_node(Primitive::None, "root".to_string());
+        RenderJob::new(TiledRender::new(Raytracer::new(scene), 4, 4, 2, TileOrder::Scanline).unwrap())
+    }
+
+    #[test]
+    fn pausing_stops_progress_until_resumed() {
+        let mut job = job();
+        assert!(job.step().is_some());
+        job.pause();
+        assert_eq!(job.state(), JobState::Paused);
+        assert!(job.step().is_none());
+        assert!(job.take_tile().is_none());
+        assert_eq!(job.progress(), 0.25);
+
+        job.resume();
+        assert_eq!(job.state(), JobState::Running);
+        while job.step().is_some() {}
+        assert_eq!(job.progress(), 1.0);
+        assert_eq!(job.state(), JobState::Done);
+        assert!(job.is_finished());
+    }
+
+    #[test]
+    fn cancelling_is_final() {
+        let mut job = job();
+        assert!(job.step().is_some());
+        job.cancel();
+        job.resume();
+        assert_eq!(job.state(), JobState::Cancelled);
+        assert!(job.is_finished());
+        assert!(job.step().is_none());
+        assert!(job.take_tile().is_none());
+        assert_eq!(job.progress(), 0.25);
+    }
+
+    #[test]
+    fn finished_jobs_stay_done() {
+        let mut job = job();
+        while job.step().is_some() {}
+        job.cancel();
+        job.pause();
+        assert_eq!(job.state(), JobState::Done);
+    }
+}
@@ -0,0 +1,106 @@
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::f32;
+use wasm_bindgen::prelude::*;
+
+// Selects which projection a Camera uses, exported so scripts can pick one
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProjectionKind {
+    #[default]
+    Perspective,
+    Orthographic,
+    Fisheye,
+    // A full 360 by 180 degree panorama
+    Equirectangular,
+}
+
+// Maps a point on the image to a ray leaving the camera.
+//
+// `x` and `y` run from -0.5 to 0.5 across the image with y pointing up, and `aspect` is
+// the width / height of the image. The returned origin and direction are in camera
+// space, looking down -Z with +Y up. Points the projection doesn't cover, like the
+// corners of a circular fisheye image, return None.
+pub trait Projection {
+    fn project(&self, x: f32, y: f32, aspect: f32) -> Option<(Point3<f32>, Vector3<f32>)>;
+}
+
+// A pinhole camera with a vertical field of view in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Perspective {
+    pub fov_y: f32,
+}
+
+impl Projection for Perspective {
+    fn project(&self, x: f32, y: f32, aspect: f32) -> Option<(Point3<f32>, Vector3<f32>)> {
+        let side = 2.0f32 * (self.fov_y.to_radians() / 2.0f32).tan();
+        Some((
+            Point3::origin(),
+            Vector3::new(x * side * aspect, y * side, -1.0),
+        ))
+    }
+}
+
+// Parallel rays, `height` is the height of the visible area in world units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Orthographic {
+    pub height: f32,
+}
+
+impl Projection for Orthographic {
+    fn project(&self, x: f32, y: f32, aspect: f32) -> Option<(Point3<f32>, Vector3<f32>)> {
+        Some((
+            Point3::new(x * self.height * aspect, y * self.height, 0.0),
+            Vector3::new(0.0, 0.0, -1.0),
+        ))
+    }
+}
+
+// An equidistant fisheye lens. The image circle fills the shorter side of the image and
+// covers `fov` degrees from edge to edge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fisheye {
+    pub fov: f32,
+}
+
+impl Projection for Fisheye {
+    fn project(&self, x: f32, y: f32, aspect: f32) -> Option<(Point3<f32>, Vector3<f32>)> {
+        let px = x * aspect;
+        let radius = 0.5 * aspect.min(1.0);
+        let r = (px * px + y * y).sqrt() / radius;
+        if r > 1.0 {
+            return None;
+        }
+
+        let theta = r * self.fov.to_radians() / 2.0;
+        let phi = y.atan2(px);
+        Some((
+            Point3::origin(),
+            Vector3::new(
+                theta.sin() * phi.cos(),
+                theta.sin() * phi.sin(),
+                -theta.cos(),
+            ),
+        ))
+    }
+}
+
+// Longitude across the width and latitude down the height, ignoring the aspect
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Equirectangular;
+
+impl Projection for Equirectangular {
+    fn project(&self, x: f32, y: f32, _aspect: f32) -> Option<(Point3<f32>, Vector3<f32>)> {
+        let longitude = x * 2.0 * f32::consts::PI;
+        let latitude = y * f32::consts::PI;
+        Some((
+            Point3::origin(),
+            Vector3::new(
+                longitude.sin() * latitude.cos(),
+                latitude.sin(),
+                -longitude.cos() * latitude.cos(),
+            ),
+        ))
+    }
+}
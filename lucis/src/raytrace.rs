@@ -1,184 +1,597 @@
+use crate::camera::Camera;
+use crate::description::SceneDescription;
+use crate::framebuffer::Framebuffer;
 use crate::geometry::volume::VolumetricSolid;
+use crate::integrator::Integrator;
+use crate::job::RenderJob;
 use crate::geometry::Ray;
-use crate::scene::{Color, Intersect, Light, SceneNode, Scene};
-use nalgebra::{convert, Affine3, Isometry, Point3, Rotation3, Vector3, U3};
-use rand::{thread_rng, Rng};
+use crate::sampler::{pixel_samples, PixelFilter, SamplePattern};
+use crate::scene::{Color, Emitter, Intersection, Light, Material, Scene, SceneBvh};
+use crate::tile::{Tile, TileOrder, TiledRender};
+use js_sys::Function;
+use nalgebra::{Point3, Vector3};
+use std::f32;
+use std::sync::Arc;
+use rand::rngs::SmallRng;
+use rand::{thread_rng, Rng, SeedableRng};
 
-use web_sys::ImageData;
-use wasm_bindgen::{JsCast, Clamped};
 use wasm_bindgen::prelude::*;
 
-type Isometry3<N> = Isometry<N, U3, Rotation3<f32>>;
-
-#[wasm_bindgen]
-extern "C" {
-    // Use `js_namespace` here to bind `console.log(..)` instead of just
-    // `log(..)`
-    #[wasm_bindgen(js_namespace = console)]
-    fn log(s: &str);
-
-    // The `console.log` is quite polymorphic, so we can bind it with multiple
-    // signatures. Note that we need to use `js_name` to ensure we always call
-    // `log` in JS.
-    #[wasm_bindgen(js_namespace = console, js_name = log)]
-    fn log_u32(a: u32);
-
-    // Multiple arguments too!
-    #[wasm_bindgen(js_namespace = console, js_name = log)]
-    fn log_many(a: &str, b: &str);
-}
-
-// Next let's define a macro that's like `println!`, only it works for
-// `console.log`. Note that `println!` doesn't actually work on the wasm target
-// because the standard library currently just eats all output. To get
-// `println!`-like behavior in your app you'll likely want a macro like this.
-
-macro_rules! console_log {
-    // Note that this is using the `log` function imported above during
-    // `bare_bones`
-    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
-}
+// Smallest total filter weight a pixel is normalized by
+const FILTER_WEIGHT_EPS: f32 = 0.0001;
+// How far secondary rays start off of the surface they leave, so they don't hit it again
+pub(crate) const SECONDARY_RAY_EPS: f32 = 0.001;
+// Points picked on each emissive node when shading a hit with the Direct integrator
+const DIRECT_EMITTER_SAMPLES: u32 = 4;
+// Width and height of scenes that don't give an image size
+pub(crate) const DEFAULT_IMAGE_SIZE: u32 = 600;
 
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct Raytracer {
     pub(crate) scene: Scene,
 
-    // Viewing
-    pub(crate) eye: Point3<f32>,
-    pub(crate) view: Point3<f32>,
-    pub(crate) up: Vector3<f32>,
-    pub(crate) fov_y: f32,
+    pub(crate) camera: Camera,
+    // Image size the scene is meant to be rendered at, saved along with it. Renders still
+    // take their own size
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    // Rays traced per pixel for anti-aliasing and depth of field
+    pub(crate) samples_per_pixel: u32,
+    pub(crate) sample_pattern: SamplePattern,
+    pub(crate) filter: PixelFilter,
+    // When set, pixels start with a single ray and only those whose color differs from a
+    // neighbour's by more than this, or that show a different node, get every sample
+    pub(crate) adaptive_threshold: Option<f32>,
+    // Most bounces a ray can take off reflective surfaces
+    pub(crate) max_depth: u32,
+    // Secondary rays that would add less than this to any channel of the pixel are skipped
+    pub(crate) min_contribution: f32,
+    pub(crate) integrator: Integrator,
+    // Number of times tiled renders go over the image, averaging the passes together
+    pub(crate) passes: u32,
 
     pub(crate) ambient: Color,
     pub(crate) lights: Vec<Light>,
+    // Emissive nodes, gathered from the scene when a render starts
+    pub(crate) emitters: Arc<Vec<Emitter>>,
+    // Boxes around the nodes in world space, also built when a render starts. Rays walk the
+    // scene graph when there isn't one
+    pub(crate) scene_bvh: Option<Arc<SceneBvh>>,
     pub(crate) volumes: Vec<VolumetricSolid>,
 }
 
-const Z_NEAR: f32 = -1.0;
-
 #[wasm_bindgen]
 impl Raytracer {
     #[wasm_bindgen(constructor)]
     pub fn new(scene: Scene) -> Raytracer {
         let light = Light::new(Color::new(0.5, 0.5, 0.5), Point3::new(4.0, 5.0, 0.0), [1.0, 0.0005, 0.0], 0.0, 1);
         Raytracer {
-            scene: scene,
-            eye: Point3::new(0.0, 0.0, 0.0),
-            view: Point3::new(0.0, 0.0, -1.0),
-            up: Vector3::new(0.0, 1.0, 0.0),
-            fov_y: 30.,
+            scene,
+            camera: Camera::new(),
+            width: DEFAULT_IMAGE_SIZE,
+            height: DEFAULT_IMAGE_SIZE,
+            samples_per_pixel: 1,
+            sample_pattern: SamplePattern::default(),
+            filter: PixelFilter::default(),
+            adaptive_threshold: None,
+            max_depth: 5,
+            min_contribution: 0.01,
+            integrator: Integrator::default(),
+            passes: 1,
             ambient: Color::new(0.3, 0.3, 0.3),
             // lights: vec![],
             lights: vec![light],
+            emitters: Arc::new(Vec::new()),
+            scene_bvh: None,
             volumes: Vec::new(),
         }
     }
 
-    // Ray trace and save a specific image
-    pub fn render(
+    // Load a scene with its camera, lights and render settings from the JSON scene format
+    pub fn from_json(json: &str) -> Result<Raytracer, JsValue> {
+        SceneDescription::from_json(json)
+            .and_then(|description| description.build())
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Save the scene and everything needed to render it again
+    pub fn to_json(&self) -> String {
+        SceneDescription::from_raytracer(self).to_json()
+    }
+
+    // Ray trace the scene into a new RGBA framebuffer
+    pub fn render(&self, width: u32, height: u32) -> Result<Framebuffer, JsValue> {
+        // A single tile covering the whole image, once for each pass
+        Ok(self
+            .render_tiled(width, height, width.max(height).max(1), TileOrder::Scanline)?
+            .finish())
+    }
+
+    // The camera used for new renders, changes to the returned copy have no effect until
+    // it is passed back with `set_camera`
+    pub fn camera(&self) -> Camera {
+        self.camera
+    }
+
+    pub fn set_camera(&mut self, camera: &Camera) {
+        self.camera = *camera;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    pub fn set_samples_per_pixel(&mut self, samples: u32) {
+        self.samples_per_pixel = samples.max(1);
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn sample_pattern(&self) -> SamplePattern {
+        self.sample_pattern
+    }
+
+    pub fn set_sample_pattern(&mut self, pattern: SamplePattern) {
+        self.sample_pattern = pattern;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn filter(&self) -> PixelFilter {
+        self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: PixelFilter) {
+        self.filter = filter;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn adaptive_threshold(&self) -> Option<f32> {
+        self.adaptive_threshold
+    }
+
+    // Turn on adaptive anti-aliasing, only pixels on edges or next to a change in color of
+    // more than `threshold` (0 to 1, per channel) are traced with `samples_per_pixel` rays.
+    // Leave the threshold out to sample every pixel fully again
+    pub fn set_adaptive(&mut self, threshold: Option<f32>) {
+        self.adaptive_threshold = threshold;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn max_depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    pub fn set_max_depth(&mut self, depth: u32) {
+        self.max_depth = depth;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn min_contribution(&self) -> f32 {
+        self.min_contribution
+    }
+
+    pub fn set_min_contribution(&mut self, contribution: f32) {
+        self.min_contribution = contribution;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn integrator(&self) -> Integrator {
+        self.integrator
+    }
+
+    pub fn set_integrator(&mut self, integrator: Integrator) {
+        self.integrator = integrator;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Set the image size saved with the scene, at least one pixel each way
+    pub fn set_size(&mut self, width: u32, height: u32) {
+        self.width = width.max(1);
+        self.height = height.max(1);
+    }
+
+    // Render the image this many times and show the average, so noisy path traced images
+    // keep getting cleaner while the render job runs
+    pub fn set_passes(&mut self, passes: u32) {
+        self.passes = passes.max(1);
+    }
+
+    // Start a tiled render, each call to `step` on the result traces one more tile
+    pub fn render_tiled(
         &self,
         width: u32,
-        height: u32
-    ) {
-        console_log!("Start!");
-        let view_matrix: Affine3<f32> =
-            convert(Isometry3::look_at_rh(&self.eye, &self.view, &self.up));
-        console_log!("Start!1");
-
-        let side = -2.0f32 * (self.fov_y.to_radians() / 2.0f32).tan();
-        let fw = width as f32;
-        let fh = height as f32;
-
-        let pixel_count = width * height;
-        let mut u8_pixels: Vec<u8> = Vec::with_capacity((4 * pixel_count) as usize);
-
-        console_log!("Done!");
-        (0..pixel_count)
-            .into_iter()
-            .map(|i| {
-                let x = i % width;
-                let y = i / height;
-                let fx = x as f32 + 0.5;
-                let fy = y as f32 + 0.5;
-                let pixel_vec = view_matrix
-                    * Vector3::new(
-                        Z_NEAR * ((fx / fw) - 0.5) * side * fw / fh,
-                        Z_NEAR * -((fy / fh) - 0.5) * side,
-                        Z_NEAR,
-                    );
-                let ray = Ray::new(self.eye, pixel_vec);
-                self.trace_ray(width, height, &ray, x, y)
-            })
-            .for_each(|c| {
-                u8_pixels.push((c.r * 255.0).round() as u8);
-                u8_pixels.push((c.g * 255.0).round() as u8);
-                u8_pixels.push((c.b * 255.0).round() as u8);
-                u8_pixels.push(255u8);
-            });
-        console_log!("Done2!");
-
-        // let mutArr: &mut [u8] = u8_pixels.as_mut();
-        // let clamped: Clamped<&mut [u8]> = Clamped(mutArr);
-
-        // console_log!("Done3!");
-        // ImageData::new_with_u8_clamped_array_and_sh(clamped, 200, 200).unwrap()
-
-        let document = web_sys::window().unwrap().document().unwrap();
-        let canvas = document.get_element_by_id("canvas").unwrap();
-        let canvas: web_sys::HtmlCanvasElement = canvas
-            .dyn_into::<web_sys::HtmlCanvasElement>()
-            .map_err(|_| ())
-            .unwrap();
-
-        let context = canvas
-            .get_context("2d")
-            .unwrap()
-            .unwrap()
-            .dyn_into::<web_sys::CanvasRenderingContext2d>()
-            .unwrap();
-
-        canvas.set_width(width);
-        canvas.set_height(height);
-
-        context.fill_rect(0.0, 0.0, 500.0, 500.0);
-
-        let mutArr: &mut [u8] = u8_pixels.as_mut();
-        let clamped: Clamped<&mut [u8]> = Clamped(mutArr);
-
-        let image_data = web_sys::ImageData::new_with_u8_clamped_array_and_sh(clamped, width, height).unwrap();
-        context.put_image_data(&image_data, 0.0, 0.0).unwrap();
-        // context.put_image_data_with_dirty_x_and_dirty_y_and_dirty_width_and_dirty_height(&image_data, 0.0, 0.0, 0.0, 0.0, width as f64, height as f64).unwrap();
+        height: u32,
+        tile_size: u32,
+        order: TileOrder,
+    ) -> Result<TiledRender, JsValue> {
+        self.start_render(width, height, tile_size, order)
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Start a render job that can be paused, resumed or cancelled between tiles
+    pub fn render_job(
+        &self,
+        width: u32,
+        height: u32,
+        tile_size: u32,
+        order: TileOrder,
+    ) -> Result<RenderJob, JsValue> {
+        Ok(RenderJob::new(self.render_tiled(width, height, tile_size, order)?))
+    }
+
+    // Render the image tile by tile, calling `callback(tile, progress)` after each tile
+    pub fn render_with_callback(
+        &self,
+        width: u32,
+        height: u32,
+        tile_size: u32,
+        order: TileOrder,
+        callback: &Function,
+    ) -> Result<Framebuffer, JsValue> {
+        let mut render = self.render_tiled(width, height, tile_size, order)?;
+        while let Some(tile) = render.step() {
+            callback.call2(&JsValue::NULL, &tile.into(), &render.progress().into())?;
+        }
+        Ok(render.finish())
+    }
+}
+
+impl Raytracer {
+    // Set up a tiled render of a copy of this raytracer. Fails when the image is too large
+    fn start_render(&self, width: u32, height: u32, tile_size: u32, order: TileOrder) -> Result<TiledRender, String> {
+        let mut raytracer = self.clone();
+        // Picks up the emissive nodes as they are now, with any changes made since this
+        // raytracer was created
+        raytracer.emitters = Arc::new(self.scene.emitters());
+        raytracer.scene_bvh = Some(Arc::new(SceneBvh::new(&self.scene)));
+        TiledRender::new(raytracer, width, height, tile_size, order)
+    }
+
+    pub fn set_ambient(&mut self, ambient: Color) {
+        self.ambient = ambient;
+    }
+
+    // Replace the default light with the given set of lights
+    pub fn set_lights(&mut self, lights: Vec<Light>) {
+        self.lights = lights;
+    }
+
+    // Boxes of fog or light that tint the rays passing through them
+    pub fn set_volumes(&mut self, volumes: Vec<VolumetricSolid>) {
+        self.volumes = volumes;
+    }
+
+    // The closest hit along the ray in world space
+    pub(crate) fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+        match &self.scene_bvh {
+            Some(bvh) => bvh.intersects(&self.scene, ray),
+            None => self.scene.intersects(ray),
+        }
+    }
+
+    // Trace the pixels of one tile of a width x height image. Returns their RGB radiance row
+    // by row, unclamped so passes can be averaged before the image is quantized
+    pub(crate) fn trace_tile(&self, tile: &Tile, width: u32, height: u32) -> Vec<f32> {
+        if let Some(threshold) = self.adaptive_threshold {
+            if self.samples_per_pixel > 1 {
+                return self.trace_tile_adaptive(tile, width, height, threshold);
+            }
+        }
+
+        let mut radiance = Vec::with_capacity(3 * tile.width as usize * tile.height as usize);
+        for y in tile.y..(tile.y + tile.height) {
+            for x in tile.x..(tile.x + tile.width) {
+                let color = self.trace_pixel(x, y, width, height, self.samples_per_pixel);
+                radiance.extend_from_slice(&[color.r, color.g, color.b]);
+            }
+        }
+        radiance
+    }
+
+    // Trace one ray through the center of every pixel, then go back over the pixels that
+    // differ from their neighbours with the full number of samples
+    fn trace_tile_adaptive(&self, tile: &Tile, width: u32, height: u32, threshold: f32) -> Vec<f32> {
+        // The first pass covers a one pixel border around the tile, so pixels on the edge of
+        // the tile have neighbours to compare against
+        let x0 = tile.x.saturating_sub(1);
+        let y0 = tile.y.saturating_sub(1);
+        let x1 = (tile.x + tile.width + 1).min(width);
+        let y1 = (tile.y + tile.height + 1).min(height);
+        let stride = (x1 - x0) as usize;
+        let mut first_pass = Vec::with_capacity(stride * (y1 - y0) as usize);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                first_pass.push(self.trace_center(x, y, width, height));
+            }
+        }
+        let at = |x: u32, y: u32| &first_pass[(y - y0) as usize * stride + (x - x0) as usize];
+
+        let mut radiance = Vec::with_capacity(3 * tile.width as usize * tile.height as usize);
+        for y in tile.y..(tile.y + tile.height) {
+            for x in tile.x..(tile.x + tile.width) {
+                let (color, node) = at(x, y);
+                let neighbours = [
+                    (x.wrapping_sub(1), y),
+                    (x + 1, y),
+                    (x, y.wrapping_sub(1)),
+                    (x, y + 1),
+                ];
+                let refine = neighbours
+                    .iter()
+                    .filter(|&&(nx, ny)| nx >= x0 && nx < x1 && ny >= y0 && ny < y1)
+                    .any(|&(nx, ny)| {
+                        let (other_color, other_node) = at(nx, ny);
+                        other_node != node || color_difference(color, other_color) > threshold
+                    });
+
+                let color = if refine {
+                    self.trace_pixel(x, y, width, height, self.samples_per_pixel)
+                } else {
+                    *color
+                };
+                radiance.extend_from_slice(&[color.r, color.g, color.b]);
+            }
+        }
+        radiance
+    }
+
+    // Trace a single ray through the center of a pixel, along with the node it hit. The ray
+    // leaves from a random point of the lens, like a single sample from trace_pixel
+    fn trace_center(&self, x: u32, y: u32, width: u32, height: u32) -> (Color, Option<usize>) {
+        let mut rng = thread_rng();
+        match self
+            .camera
+            .primary_ray(x as f32 + 0.5, y as f32 + 0.5, width, height, (rng.gen(), rng.gen()))
+        {
+            Some(ray) => self.trace_ray_hit(width, height, &ray, x, y),
+            // Outside of the lens
+            None => (Color::new(0.0, 0.0, 0.0), None),
+        }
+    }
+
+    // Trace the samples for a pixel and combine them with the reconstruction filter
+    fn trace_pixel(&self, x: u32, y: u32, width: u32, height: u32, samples: u32) -> Color {
+        let samples = pixel_samples(self.sample_pattern, samples, &mut thread_rng());
+        let radius = self.filter.radius();
+
+        // Color ops clamp, so sum outside of them
+        let mut sum = Vector3::new(0.0f32, 0.0, 0.0);
+        let mut unweighted = Vector3::new(0.0f32, 0.0, 0.0);
+        let mut total_weight = 0.0;
+        for sample in &samples {
+            // Spread the samples over the whole filter, not just the pixel
+            let dx = (sample.film.0 - 0.5) * 2.0 * radius;
+            let dy = (sample.film.1 - 0.5) * 2.0 * radius;
+            let c = match self.camera.primary_ray(
+                x as f32 + 0.5 + dx,
+                y as f32 + 0.5 + dy,
+                width,
+                height,
+                sample.lens,
+            ) {
+                Some(ray) => self.trace_ray(width, height, &ray, x, y),
+                // Outside of the lens
+                None => Color::new(0.0, 0.0, 0.0),
+            };
+            let c = Vector3::from(c);
+            let weight = self.filter.weight(dx, dy);
+            sum += c * weight;
+            unweighted += c;
+            total_weight += weight;
+        }
+
+        // Mitchell's negative lobes can cancel out with only a few samples
+        let c = if total_weight > FILTER_WEIGHT_EPS {
+            sum / total_weight
+        } else {
+            unweighted / samples.len() as f32
+        };
+        Color::from(c)
     }
 
     fn trace_ray(&self, width: u32, height: u32, ray: &Ray, x: u32, y: u32) -> Color {
-        let collision = self.scene.intersects(ray);
+        self.trace_ray_hit(width, height, ray, x, y).0
+    }
+
+    // Trace a ray, returning its color and the id of the node it hit first
+    fn trace_ray_hit(&self, width: u32, height: u32, ray: &Ray, x: u32, y: u32) -> (Color, Option<usize>) {
+        let collision = self.intersects(ray);
         match collision {
             Some(c) => {
-                let mut color = self.scene.nodes.borrow()[c.node_id].material.get_color(ray, self, &c);
-                // for volume in self.volumes.iter() {
-                //     // TODO: don't do this
-                //     color = volume.apply(ray, &collision, color)
-                // }
-                color
+                let color = match self.integrator {
+                    Integrator::Direct => self.shade(ray, &c, 0, Color::new(1.0, 1.0, 1.0)),
+                    Integrator::PathTracer => self.trace_path(ray, &c),
+                };
+                (self.apply_volumes(ray, &collision, color), Some(c.node_id))
             }
             None => {
-                let mut color = get_background_color(x, y, width, height);
-                // for volume in self.volumes.iter() {
-                //     // TODO: don't do this
-                //     color = volume.apply(ray, &collision, color)
-                // }
-                color
+                let color = get_background_color(x, y, width, height);
+                (self.apply_volumes(ray, &collision, color), None)
+            }
+        }
+    }
+
+    fn apply_volumes(&self, ray: &Ray, collision: &Option<Intersection>, color: Color) -> Color {
+        self.volumes
+            .iter()
+            .fold(color, |color, volume| volume.apply(ray, collision, color))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Raytracer {
+    // Render the image using every thread in the rayon thread pool, one tile at a time
+    pub fn render_parallel(&self, width: u32, height: u32, tile_size: u32) -> Result<Framebuffer, String> {
+        use rayon::prelude::*;
+
+        let mut render = self.start_render(width, height, tile_size, TileOrder::Scanline)?;
+        let tiles_per_pass = render.tiles().len();
+        loop {
+            // One pass at a time, so only a pass worth of traced tiles is held at once
+            let tiles: Vec<Tile> = (0..tiles_per_pass).map_while(|_| render.take_tile()).collect();
+            if tiles.is_empty() {
+                break;
+            }
+            let rendered = tiles
+                .par_iter()
+                .map(|tile| render.trace(tile))
+                .collect::<Result<Vec<Vec<f32>>, String>>()?;
+            for (tile, radiance) in tiles.iter().zip(rendered) {
+                render.add_tile(tile, &radiance)?;
+            }
+        }
+        Ok(render.finish())
+    }
+}
+
+impl Raytracer {
+    // Shade a hit and follow the reflections off its material. `depth` is the number of
+    // bounces so far and `weight` how much the hit contributes to the pixel
+    fn shade(&self, ray: &Ray, hit: &Intersection, depth: u32, weight: Color) -> Color {
+        let (material, hit) = self.scene.surface_at(hit);
+        let hit = &hit;
+        if let Material::Dielectric { ior, absorption } = material {
+            return self.shade_dielectric(ray, hit, depth, weight, ior, absorption);
+        }
+
+        let mut color = material.get_color(ray, self, hit);
+        if hit.entering {
+            color = color + self.scene.emission(hit);
+        }
+        if !self.emitters.is_empty() {
+            let wo = -ray.dir.normalize();
+            let mut n = hit.normal.normalize();
+            if n.dot(&wo) < 0.0 {
+                n = -n;
+            }
+            let emitted = self.sample_emitters(hit, &n, &wo, &material, DIRECT_EMITTER_SAMPLES);
+            color = color + Color::from(emitted);
+        }
+
+        let reflectivity = material.reflectivity();
+        let reflected_weight = weight * reflectivity;
+        if depth < self.max_depth && max_channel(&reflected_weight) >= self.min_contribution {
+            let d = ray.dir.normalize();
+            let mut n = hit.normal.normalize();
+            // Reflect off the side of the surface the ray arrived on
+            if d.dot(&n) > 0.0 {
+                n = -n;
+            }
+            let reflected = Ray::new(hit.point + n * SECONDARY_RAY_EPS, d - 2.0 * d.dot(&n) * n);
+            color = color + reflectivity * self.trace_secondary(&reflected, depth + 1, reflected_weight);
+        }
+        color
+    }
+
+    // Split the ray into a reflected and a refracted ray, weighted by the Fresnel equations
+    fn shade_dielectric(
+        &self,
+        ray: &Ray,
+        hit: &Intersection,
+        depth: u32,
+        weight: Color,
+        ior: f32,
+        absorption: Color,
+    ) -> Color {
+        // Out of bounces the reflected and refracted rays aren't traced, they see the sky in
+        // their direction so glass at the last bounce isn't black
+        let follow = |ray: &Ray, weight: Color| {
+            if depth < self.max_depth {
+                self.trace_secondary(ray, depth + 1, weight)
+            } else {
+                get_environment_color(&ray.dir)
             }
+        };
+
+        // Light is absorbed along the way through the inside, so a ray leaving the solid is
+        // dimmed by how far it travelled since it entered (Beer-Lambert)
+        let transmittance = if hit.entering {
+            Color::new(1.0, 1.0, 1.0)
+        } else {
+            let distance = (hit.point - ray.src).norm();
+            Color::new(
+                (-absorption.r * distance).exp(),
+                (-absorption.g * distance).exp(),
+                (-absorption.b * distance).exp(),
+            )
+        };
+        let weight = weight * transmittance;
+
+        let d = ray.dir.normalize();
+        // Work with the normal on the side the ray arrives from
+        let (n, eta_i, eta_t) = if hit.entering {
+            (hit.normal.normalize(), 1.0, ior)
+        } else {
+            (-hit.normal.normalize(), ior, 1.0)
+        };
+        let cos_i = (-d.dot(&n)).clamp(0.0, 1.0);
+        let eta = eta_i / eta_t;
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+
+        let reflected = Ray::new(hit.point + n * SECONDARY_RAY_EPS, d + 2.0 * cos_i * n);
+        // Past the critical angle everything is reflected
+        if sin2_t >= 1.0 {
+            return transmittance * follow(&reflected, weight);
+        }
+
+        let cos_t = (1.0 - sin2_t).sqrt();
+        let kr = fresnel(cos_i, cos_t, eta_i, eta_t);
+        let mut color = Color::new(0.0, 0.0, 0.0);
+        if max_channel(&weight) * kr >= self.min_contribution {
+            color = color + kr * follow(&reflected, kr * weight);
+        }
+        let kt = 1.0 - kr;
+        if max_channel(&weight) * kt >= self.min_contribution {
+            let refracted = Ray::new(hit.point - n * SECONDARY_RAY_EPS, eta * d + (eta * cos_i - cos_t) * n);
+            color = color + kt * follow(&refracted, kt * weight);
+        }
+        transmittance * color
+    }
+
+    // Trace a ray spawned at a surface, these don't belong to a pixel so misses see the sky
+    // in the ray's direction rather than the background of the image
+    fn trace_secondary(&self, ray: &Ray, depth: u32, weight: Color) -> Color {
+        match self.intersects(ray) {
+            Some(hit) => self.shade(ray, &hit, depth, weight),
+            None => get_environment_color(&ray.dir),
         }
     }
 }
 
-fn get_background_color(_x: u32, y: u32, _width: u32, height: u32) -> Color {
+// Fraction of unpolarized light reflected off a dielectric, from the exact Fresnel equations
+pub(crate) fn fresnel(cos_i: f32, cos_t: f32, eta_i: f32, eta_t: f32) -> f32 {
+    let parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+fn max_channel(c: &Color) -> f32 {
+    c.r.max(c.g).max(c.b)
+}
+
+// Largest difference between the channels of two colors
+fn color_difference(a: &Color, b: &Color) -> f32 {
+    (a.r - b.r).abs().max((a.g - b.g).abs()).max((a.b - b.b).abs())
+}
+
+fn get_background_color(x: u32, y: u32, _width: u32, height: u32) -> Color {
     // let fw = width as f32;
     let fh = height as f32;
-    let r_rate = 67.0f32 / 255.;
-    let g_rate = 133.0f32 / 255.;
-    let b_rate = 1.0f32;
     let height_rate = f32::max(0.0f32, (y as f32 / fh) - 0.2f32);
 
     if height_rate <= 0.35 {
@@ -190,7 +603,8 @@ fn get_background_color(_x: u32, y: u32, _width: u32, height: u32) -> Color {
             0.005f32
         };
 
-        let mut rng = thread_rng();
+        // Seed from the pixel so every sample of a pixel agrees on where the stars are
+        let mut rng = SmallRng::seed_from_u64((u64::from(y) << 32) | u64::from(x));
         let render_star: f32 = rng.gen();
         if render_star <= rand_chance {
             // Render a star instead
@@ -202,9 +616,82 @@ fn get_background_color(_x: u32, y: u32, _width: u32, height: u32) -> Color {
         }
     }
 
+    sky_gradient(height_rate)
+}
+
+fn sky_gradient(height_rate: f32) -> Color {
+    let r_rate = 67.0f32 / 255.;
+    let g_rate = 133.0f32 / 255.;
+    let b_rate = 1.0f32;
     Color::new(
         r_rate * height_rate,
         g_rate * height_rate,
         b_rate * height_rate,
     )
 }
+
+// The background's gradient for a direction, straight up is the top of the image and
+// straight down the bottom. Stars are left out, they only make sense per pixel
+pub(crate) fn get_environment_color(dir: &Vector3<f32>) -> Color {
+    let elevation = dir.normalize().y.clamp(-1.0, 1.0).asin();
+    let y = 0.5 - elevation / f32::consts::PI;
+    sky_gradient(f32::max(0.0f32, y - 0.2f32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Primitive;
+
+    // Nothing to hit, every ray leaving the glass sees the sky
+    fn empty(max_depth: u32) -> Raytracer {
+        let mut scene = Scene::new();
+        scene.add_node(Primitive::None, "root".to_string());
+        let mut raytracer = Raytracer::new(scene);
+        raytracer.set_max_depth(max_depth);
+        raytracer
+    }
+
+    // A ray that travelled `distance` inside the glass and is about to leave it through a
+    // surface facing along `normal`
+    fn leaving(d: Vector3<f32>, normal: Vector3<f32>, distance: f32) -> (Ray, Intersection) {
+        let point = Point3::new(0.0, 0.0, 0.0);
+        let ray = Ray::new(point - d * distance, d);
+        (ray, Intersection::new(distance, point, 0, normal, 0.0, 0.0, false))
+    }
+
+    #[test]
+    fn glass_reflects_four_percent_head_on() {
+        assert!((fresnel(1.0, 1.0, 1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert!((fresnel(1.0, 1.0, 1.5, 1.0) - 0.04).abs() < 1e-6);
+    }
+
+    #[test]
+    fn everything_is_reflected_past_the_critical_angle() {
+        let raytracer = empty(0);
+        let black = Color::new(0.0, 0.0, 0.0);
+        // 60 degrees from the normal, the critical angle of glass is about 42
+        let (ray, hit) = leaving(Vector3::new(3.0f32.sqrt() / 2.0, 0.5, 0.0), Vector3::y(), 1.0);
+        let color = raytracer.shade_dielectric(&ray, &hit, 0, Color::new(1.0, 1.0, 1.0), 1.5, black);
+        let reflected = get_environment_color(&Vector3::new(3.0f32.sqrt() / 2.0, -0.5, 0.0));
+        assert_eq!((color.r, color.g, color.b), (reflected.r, reflected.g, reflected.b));
+        let straight_on = get_environment_color(&Vector3::new(3.0f32.sqrt() / 2.0, 0.5, 0.0));
+        assert_ne!((color.r, color.g, color.b), (straight_on.r, straight_on.g, straight_on.b));
+    }
+
+    #[test]
+    fn light_is_absorbed_along_the_way_through() {
+        let raytracer = empty(0);
+        let white = Color::new(1.0, 1.0, 1.0);
+        // An index of 1 doesn't bend or reflect anything, only the absorption is left. Out
+        // towards the horizon, where the sky has some of every channel
+        let (ray, hit) = leaving(Vector3::x(), Vector3::x(), 2.0);
+        let clear = raytracer.shade_dielectric(&ray, &hit, 0, white, 1.0, Color::new(0.0, 0.0, 0.0));
+        let tinted = raytracer.shade_dielectric(&ray, &hit, 0, white, 1.0, Color::new(0.5, 1.0, 0.0));
+        assert!(clear.r > 0.0 && clear.g > 0.0, "{:?}", clear);
+        let expected = [clear.r * (-1.0f32).exp(), clear.g * (-2.0f32).exp(), clear.b];
+        for (got, expected) in [tinted.r, tinted.g, tinted.b].iter().zip(expected.iter()) {
+            assert!((got - expected).abs() < 1e-5, "{:?} {:?}", tinted, expected);
+        }
+    }
+}
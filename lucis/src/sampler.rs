@@ -0,0 +1,260 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f32;
+use wasm_bindgen::prelude::*;
+
+// How the samples of a pixel are spread over it
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplePattern {
+    // Evenly spaced, the sample count is rounded up to fill the grid
+    Grid,
+    // One random sample in each cell of the grid
+    #[default]
+    Jittered,
+    Halton,
+    Sobol,
+}
+
+// Reconstruction filter used to weigh the samples of a pixel
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFilter {
+    #[default]
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+}
+
+// Falloff of the Gaussian filter, larger values give a sharper image
+const GAUSSIAN_ALPHA: f32 = 2.0;
+// Mitchell-Netravali parameters, 1/3 each is their recommended balance of blur and ringing
+const MITCHELL_B: f32 = 1.0 / 3.0;
+const MITCHELL_C: f32 = 1.0 / 3.0;
+// Largest f32 below 1, sample points are kept in [0, 1)
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON;
+
+impl PixelFilter {
+    // Half the width of the filter, in pixels. Samples are spread over this area around the
+    // center of the pixel, so the wider filters blend in some of the neighbouring pixels
+    pub fn radius(self) -> f32 {
+        match self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent => 1.0,
+            PixelFilter::Gaussian => 1.5,
+            PixelFilter::Mitchell => 2.0,
+        }
+    }
+
+    // Weight of a sample offset by (dx, dy) pixels from the center of the pixel. Mitchell
+    // has negative lobes, so weights can be negative
+    pub fn weight(self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(self, d: f32) -> f32 {
+        let radius = self.radius();
+        let d = d.abs();
+        if d > radius {
+            return 0.0;
+        }
+        match self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent => radius - d,
+            PixelFilter::Gaussian => {
+                ((-GAUSSIAN_ALPHA * d * d).exp() - (-GAUSSIAN_ALPHA * radius * radius).exp()).max(0.0)
+            }
+            PixelFilter::Mitchell => mitchell(d / radius * 2.0),
+        }
+    }
+}
+
+// The Mitchell-Netravali cubic over [0, 2]
+fn mitchell(x: f32) -> f32 {
+    let (b, c) = (MITCHELL_B, MITCHELL_C);
+    let x2 = x * x;
+    let x3 = x2 * x;
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)) / 6.0
+    } else {
+        ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c))
+            / 6.0
+    }
+}
+
+// A sample of a pixel, both points are in [0, 1) x [0, 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PixelSample {
+    pub(crate) film: (f32, f32),
+    pub(crate) lens: (f32, f32),
+}
+
+// Generate the samples for one pixel. A single sample goes through the center of the pixel,
+// which matches rendering without anti-aliasing, but a random point of the lens so depth of
+// field still shows up
+pub(crate) fn pixel_samples<R: Rng>(pattern: SamplePattern, count: u32, rng: &mut R) -> Vec<PixelSample> {
+    if count <= 1 {
+        return vec![PixelSample {
+            film: (0.5, 0.5),
+            lens: (rng.gen(), rng.gen()),
+        }];
+    }
+
+    let film = points(pattern, count, rng);
+    // Use the same pattern for the lens but shuffle it, pairing each film sample with the
+    // matching lens sample would tie the blur to the position in the pixel
+    let mut lens = points(pattern, film.len() as u32, rng);
+    lens.shuffle(rng);
+    film.into_iter()
+        .zip(lens)
+        .map(|(film, lens)| PixelSample { film, lens })
+        .collect()
+}
+
+fn points<R: Rng>(pattern: SamplePattern, count: u32, rng: &mut R) -> Vec<(f32, f32)> {
+    match pattern {
+        SamplePattern::Grid => grid(count, |_| (0.5, 0.5)),
+        SamplePattern::Jittered => grid(count, |_| (rng.gen(), rng.gen())),
+        SamplePattern::Halton => rotated(count, rng, |i| (radical_inverse(i, 2), radical_inverse(i, 3))),
+        SamplePattern::Sobol => rotated(count, rng, sobol_2d),
+    }
+}
+
+// One point in each cell of a columns x rows grid, `offset` places it within its cell
+fn grid<F: FnMut(u32) -> (f32, f32)>(count: u32, mut offset: F) -> Vec<(f32, f32)> {
+    let columns = (count as f32).sqrt().ceil() as u32;
+    let rows = count.div_ceil(columns);
+    let mut points = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        for column in 0..columns {
+            let (dx, dy) = offset(row * columns + column);
+            points.push((
+                (column as f32 + dx) / columns as f32,
+                (row as f32 + dy) / rows as f32,
+            ));
+        }
+    }
+    points
+}
+
+// The first `count` points of a low discrepancy sequence, shifted by a random amount
+// (Cranley-Patterson rotation) so neighbouring pixels don't share the same pattern
+fn rotated<R: Rng, F: Fn(u32) -> (f32, f32)>(count: u32, rng: &mut R, sequence: F) -> Vec<(f32, f32)> {
+    let shift: (f32, f32) = (rng.gen(), rng.gen());
+    (0..count)
+        .map(|i| {
+            let (x, y) = sequence(i);
+            (wrap(x + shift.0), wrap(y + shift.1))
+        })
+        .collect()
+}
+
+fn wrap(x: f32) -> f32 {
+    let x = x.fract();
+    // Rounding can land exactly on 1
+    x.min(ONE_MINUS_EPSILON)
+}
+
+// The i-th element of the van der Corput sequence in `base`
+fn radical_inverse(mut i: u32, base: u32) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut inv = inv_base;
+    let mut result = 0.0;
+    while i > 0 {
+        result += (i % base) as f32 * inv;
+        i /= base;
+        inv *= inv_base;
+    }
+    result.min(ONE_MINUS_EPSILON)
+}
+
+// The i-th point of the first two dimensions of the Sobol sequence
+fn sobol_2d(i: u32) -> (f32, f32) {
+    let x = i.reverse_bits();
+    let mut y = 0u32;
+    let mut v = 1u32 << 31;
+    let mut i = i;
+    while i != 0 {
+        if i & 1 != 0 {
+            y ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    (
+        (x as f32 / 4_294_967_296.0).min(ONE_MINUS_EPSILON),
+        (y as f32 / 4_294_967_296.0).min(ONE_MINUS_EPSILON),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.000_001
+    }
+
+    #[test]
+    fn radical_inverse_matches_van_der_corput() {
+        let base2: Vec<f32> = (0..4).map(|i| radical_inverse(i, 2)).collect();
+        assert_eq!(base2, vec![0.0, 0.5, 0.25, 0.75]);
+        let base3 = [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0 / 9.0, 4.0 / 9.0];
+        for (i, expected) in base3.iter().enumerate() {
+            assert!(close(radical_inverse(i as u32, 3), *expected));
+        }
+    }
+
+    #[test]
+    fn sobol_starts_like_the_reference_sequence() {
+        let points: Vec<(f32, f32)> = (0..4).map(sobol_2d).collect();
+        assert_eq!(points, vec![(0.0, 0.0), (0.5, 0.5), (0.25, 0.75), (0.75, 0.25)]);
+    }
+
+    #[test]
+    fn grid_rounds_up_to_fill_its_rows() {
+        assert_eq!(grid(4, |_| (0.5, 0.5)), vec![(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)]);
+        assert_eq!(grid(5, |_| (0.5, 0.5)).len(), 6);
+        assert_eq!(grid(10, |_| (0.5, 0.5)).len(), 12);
+    }
+
+    #[test]
+    fn samples_stay_inside_the_pixel() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        for pattern in [SamplePattern::Grid, SamplePattern::Jittered, SamplePattern::Halton, SamplePattern::Sobol] {
+            for sample in pixel_samples(pattern, 9, &mut rng) {
+                for v in [sample.film.0, sample.film.1, sample.lens.0, sample.lens.1] {
+                    assert!((0.0..1.0).contains(&v), "{:?} gave {}", pattern, v);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn filter_weights_are_not_negative() {
+        for filter in [PixelFilter::Box, PixelFilter::Tent, PixelFilter::Gaussian] {
+            let radius = filter.radius();
+            for step in 0..=40 {
+                let d = -radius * 1.5 + step as f32 * radius * 3.0 / 40.0;
+                assert!(filter.weight(d, 0.0) >= 0.0, "{:?} at {}", filter, d);
+                assert!(filter.weight(d, d) >= 0.0, "{:?} at {}", filter, d);
+            }
+        }
+    }
+
+    #[test]
+    fn filter_weights_vanish_past_the_radius() {
+        for filter in [PixelFilter::Box, PixelFilter::Tent, PixelFilter::Gaussian, PixelFilter::Mitchell] {
+            let radius = filter.radius();
+            assert!(filter.weight(0.0, 0.0) > 0.0);
+            assert_eq!(filter.weight(radius + 0.01, 0.0), 0.0);
+            assert_eq!(filter.weight(0.0, -radius - 0.01), 0.0);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::geometry::{Bvh, Ray};
+use crate::scene::{Intersection, Intersect, Scene, SceneNode};
+use nalgebra::{Affine3, Point3, Vector3};
+use std::f32;
+
+// A node with something to hit, placed in world space
+#[derive(Debug, Clone)]
+struct Instance {
+    node_id: usize,
+    // From the node's object space to world space, through every parent, and back
+    transform: Affine3<f32>,
+    inv_transform: Affine3<f32>,
+}
+
+// The nodes of a scene flattened into world space with a tree of boxes around them, so rays
+// skip the nodes they don't pass near. Built from the scene as it is when a render starts
+#[derive(Debug, Clone)]
+pub(crate) struct SceneBvh {
+    instances: Vec<Instance>,
+    bvh: Bvh,
+}
+
+impl SceneBvh {
+    pub(crate) fn new(scene: &Scene) -> SceneBvh {
+        let nodes = scene.nodes.read().unwrap();
+        let mut instances = vec![];
+        let mut bounds = vec![];
+        if !nodes.is_empty() {
+            collect_instances(
+                &nodes,
+                scene.root_node,
+                &Affine3::identity(),
+                &Affine3::identity(),
+                &mut instances,
+                &mut bounds,
+            );
+        }
+        SceneBvh {
+            instances,
+            bvh: Bvh::new(&bounds),
+        }
+    }
+
+    // The closest hit along the ray, in world space
+    pub(crate) fn intersects(&self, scene: &Scene, ray: &Ray) -> Option<Intersection> {
+        let nodes = scene.nodes.read().unwrap();
+        let mut closest: Option<Intersection> = None;
+        let mut closest_t = f32::MAX;
+        self.bvh.traverse(ray, f32::MAX, |i| {
+            let instance = &self.instances[i];
+            let hit = nodes[instance.node_id]
+                .intersects(&(instance.inv_transform * *ray))?
+                .apply_transform(&instance.transform, &instance.inv_transform);
+            // Distance along the world space ray, which the boxes are measured in
+            let t = (hit.point - ray.src).dot(&ray.dir) / ray.dir.norm_squared();
+            if t < closest_t {
+                closest_t = t;
+                closest = Some(hit);
+                return Some(t);
+            }
+            None
+        });
+        closest
+    }
+}
+
+fn collect_instances(
+    nodes: &[SceneNode],
+    current_node: usize,
+    parent: &Affine3<f32>,
+    parent_inv: &Affine3<f32>,
+    instances: &mut Vec<Instance>,
+    bounds: &mut Vec<(Vector3<f32>, Vector3<f32>)>,
+) {
+    let n = &nodes[current_node];
+    let transform = parent * n.transform;
+    let inv_transform = n.inv_transform * parent_inv;
+    if let Some((min, max)) = n.primitive.bounds() {
+        bounds.push(world_bounds(&transform, &min, &max));
+        instances.push(Instance {
+            node_id: n.id,
+            transform,
+            inv_transform,
+        });
+    }
+    for child in n.children.iter() {
+        collect_instances(nodes, *child, &transform, &inv_transform, instances, bounds);
+    }
+}
+
+// The box in world space around the eight transformed corners of an object space box
+fn world_bounds(transform: &Affine3<f32>, min: &Vector3<f32>, max: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let mut world_min = Vector3::repeat(f32::MAX);
+    let mut world_max = Vector3::repeat(f32::MIN);
+    for corner in 0..8 {
+        let p = Point3::new(
+            if corner & 1 == 0 { min.x } else { max.x },
+            if corner & 2 == 0 { min.y } else { max.y },
+            if corner & 4 == 0 { min.z } else { max.z },
+        );
+        let p = transform * p;
+        world_min = world_min.zip_map(&p.coords, f32::min);
+        world_max = world_max.zip_map(&p.coords, f32::max);
+    }
+    (world_min, world_max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::description::SceneDescription;
+    use serde_json::json;
+
+    #[test]
+    fn hits_what_the_scene_graph_hits() {
+        let json = json!({ "root": { "name": "root", "transforms": [{ "rotate": ["y", 10] }], "children": [
+            { "name": "ball", "primitive": "sphere", "transforms": [{ "translate": [0, 0, -6] }] },
+            { "name": "box", "primitive": "cube", "transforms": [{ "scale": [3, 0.5, 3] }, { "translate": [-1.5, -2, -8] }] },
+            { "name": "arm", "transforms": [{ "rotate": ["z", 45] }, { "translate": [3, 1, -7] }], "children": [
+                { "name": "cone", "primitive": "cone" },
+                { "name": "can", "primitive": "cylinder", "transforms": [{ "translate": [0, -3, 0] }] }
+            ] },
+            { "name": "behind", "primitive": "sphere", "transforms": [{ "translate": [0.5, 0, -9] }] }
+        ] } });
+        let scene = SceneDescription::from_json(&json.to_string()).unwrap().build_scene().unwrap();
+        let bvh = SceneBvh::new(&scene);
+
+        let mut hit_nodes = vec![];
+        for x in -20..=20 {
+            for y in -20..=20 {
+                let ray = Ray::new(Point3::new(0.0, 0.0, -1.0), Vector3::new(x as f32 * 0.06, y as f32 * 0.06, -1.0));
+                let expected = scene.intersects(&ray);
+                let actual = bvh.intersects(&scene, &ray);
+                match (&expected, &actual) {
+                    (Some(e), Some(a)) => {
+                        assert_eq!(e.node_id, a.node_id, "{:?}", ray);
+                        assert!((e.point - a.point).norm() < 1e-3, "{:?}", ray);
+                        hit_nodes.push(a.node_id);
+                    }
+                    (None, None) => {}
+                    _ => panic!("{:?} hit {:?} in the scene but {:?} in the BVH", ray, expected, actual),
+                }
+            }
+        }
+        assert!(hit_nodes.len() > 100);
+        hit_nodes.sort();
+        hit_nodes.dedup();
+        // Everything but the sphere hidden behind the ball
+        assert_eq!(hit_nodes.len(), 4);
+    }
+}
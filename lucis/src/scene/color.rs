@@ -49,10 +49,10 @@ impl Mul<Color> for f32 {
     }
 }
 
-impl<'a, 'b> Mul<&'b Vector3<f32>> for &'a Color {
+impl Mul<&Vector3<f32>> for &Color {
     type Output = Color;
 
-    fn mul(self, rhs: &'b Vector3<f32>) -> Color {
+    fn mul(self, rhs: &Vector3<f32>) -> Color {
         Color {
             r: self.r * rhs.x,
             g: self.g * rhs.y,
@@ -62,7 +62,7 @@ impl<'a, 'b> Mul<&'b Vector3<f32>> for &'a Color {
     }
 }
 
-impl<'a> Mul<f32> for &'a Color {
+impl Mul<f32> for &Color {
     type Output = Color;
 
     fn mul(self, rhs: f32) -> Color {
@@ -88,6 +88,19 @@ impl Div<f32> for Color {
     }
 }
 
+// Unclamped conversions, for sums of light that can go past 1 before they are averaged
+impl From<Color> for Vector3<f32> {
+    fn from(c: Color) -> Vector3<f32> {
+        Vector3::new(c.r, c.g, c.b)
+    }
+}
+
+impl From<Vector3<f32>> for Color {
+    fn from(v: Vector3<f32>) -> Color {
+        Color::new(v.x, v.y, v.z)
+    }
+}
+
 #[wasm_bindgen]
 impl Color {
     #[wasm_bindgen(constructor)]
@@ -96,9 +109,9 @@ impl Color {
     }
 
     pub(crate) fn clamp(mut self) -> Color {
-        self.r = self.r.max(0.0).min(1.0);
-        self.g = self.g.max(0.0).min(1.0);
-        self.b = self.b.max(0.0).min(1.0);
+        self.r = self.r.clamp(0.0, 1.0);
+        self.g = self.g.clamp(0.0, 1.0);
+        self.b = self.b.clamp(0.0, 1.0);
         self
     }
 }
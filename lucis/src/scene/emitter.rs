@@ -0,0 +1,176 @@
+use crate::geometry::Primitive;
+use crate::scene::Color;
+use nalgebra::{Affine3, Matrix3, Point3, Vector3, U3};
+use rand::Rng;
+use std::f32;
+
+// Steps around curved surfaces when adding up their area in world space
+const AREA_STEPS: usize = 256;
+// Points tried before giving up on a sample, far more than even a badly stretched surface needs
+const MAX_TRIES: usize = 64;
+
+// A glowing scene node, sampled as an area light
+#[derive(Debug, Clone)]
+pub(crate) struct Emitter {
+    pub(crate) node_id: usize,
+    pub(crate) primitive: Primitive,
+    // From the node's object space to world space, through every parent
+    pub(crate) transform: Affine3<f32>,
+    // Radiance leaving the surface, from SceneNode::radiance
+    pub(crate) emission: Color,
+    // For taking object space normals to world space
+    inverse_transpose: Matrix3<f32>,
+    determinant: f32,
+    // Area of the surface in world space, and the most any bit of it is stretched by
+    area: f32,
+    max_stretch: f32,
+}
+
+// A point on an emitter in world space
+pub(crate) struct EmitterSample {
+    pub(crate) point: Point3<f32>,
+    pub(crate) normal: Vector3<f32>,
+    // Density of picking this point, per unit of world space area
+    pub(crate) pdf: f32,
+}
+
+impl Emitter {
+    // None when the surface has no area to sample, e.g. when it is squashed flat
+    pub(crate) fn new(node_id: usize, primitive: Primitive, transform: Affine3<f32>, emission: Color) -> Option<Emitter> {
+        let linear = transform.matrix().fixed_resize::<U3, U3>(0.0f32);
+        let inverse_transpose = linear.try_inverse()?.transpose();
+        let determinant = linear.determinant().abs();
+        // The stretch is largest for the normal the inverse transpose lengthens the most
+        let max_stretch = determinant * inverse_transpose.svd(false, false).singular_values.amax();
+        let mut emitter = Emitter {
+            node_id,
+            primitive,
+            transform,
+            emission,
+            inverse_transpose,
+            determinant,
+            area: 0.0,
+            max_stretch,
+        };
+        emitter.area = emitter.world_area(&linear);
+        if emitter.area > 0.0 && emitter.max_stretch > 0.0 {
+            Some(emitter)
+        } else {
+            None
+        }
+    }
+
+    // How much the transform grows the area around a point with this object space normal
+    fn stretch(&self, normal: &Vector3<f32>) -> f32 {
+        self.determinant * (self.inverse_transpose * normal).norm()
+    }
+
+    // Flat parts are measured exactly, curved ones are added up in thin strips
+    fn world_area(&self, linear: &Matrix3<f32>) -> f32 {
+        let pi = f32::consts::PI;
+        let around = |i: usize| {
+            let phi = 2.0 * pi * (i as f32 + 0.5) / AREA_STEPS as f32;
+            (phi.cos(), phi.sin())
+        };
+        match &self.primitive {
+            // Equal steps in z cut the unit sphere into bands of equal area
+            Primitive::Sphere => {
+                let mut sum = 0.0;
+                for i in 0..AREA_STEPS {
+                    let z = 1.0 - 2.0 * (i as f32 + 0.5) / AREA_STEPS as f32;
+                    let r = (1.0 - z * z).sqrt();
+                    for j in 0..AREA_STEPS {
+                        let (c, s) = around(j);
+                        sum += self.stretch(&Vector3::new(r * c, r * s, z));
+                    }
+                }
+                sum * 4.0 * pi / (AREA_STEPS * AREA_STEPS) as f32
+            }
+            Primitive::Cube => (0..3)
+                .map(|axis| 2.0 * self.stretch(&Vector3::from_fn(|i, _| if i == axis { 1.0 } else { 0.0 })))
+                .sum(),
+            Primitive::Cylinder => {
+                let side: f32 = (0..AREA_STEPS).map(|i| self.stretch(&Vector3::new(around(i).0, 0.0, around(i).1))).sum();
+                side * 2.0 * pi / AREA_STEPS as f32 + 2.0 * pi * self.stretch(&Vector3::y())
+            }
+            Primitive::Cone => {
+                let side: f32 = (0..AREA_STEPS)
+                    .map(|i| self.stretch(&(Vector3::new(around(i).0, -1.0, around(i).1) / f32::consts::SQRT_2)))
+                    .sum();
+                side * pi * f32::consts::SQRT_2 / AREA_STEPS as f32
+            }
+            Primitive::Mesh(mesh) => (0..mesh.faces.len())
+                .map(|face| {
+                    let [a, b, c] = mesh.triangle(face);
+                    (linear * (b - a)).cross(&(linear * (c - a))).norm() / 2.0
+                })
+                .sum(),
+            Primitive::None => 0.0,
+        }
+    }
+
+    // Pick a point spread evenly over the emitter's surface in world space. Points are picked
+    // evenly in object space, then kept in proportion to how much the transform stretches the
+    // surface around them
+    pub(crate) fn sample<R: Rng>(&self, rng: &mut R) -> Option<EmitterSample> {
+        for _ in 0..MAX_TRIES {
+            let (point, normal) = self.primitive.sample_surface(rng.gen(), rng.gen(), rng.gen())?;
+            if rng.gen::<f32>() * self.max_stretch > self.stretch(&normal) {
+                continue;
+            }
+            return Some(EmitterSample {
+                point: self.transform * point,
+                normal: (self.inverse_transpose * normal).normalize(),
+                pdf: 1.0 / self.area,
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Matrix4;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    fn emitter(primitive: Primitive, scale: [f32; 3]) -> Emitter {
+        let transform = Affine3::from_matrix_unchecked(Matrix4::new_nonuniform_scaling(&Vector3::from(scale)));
+        Emitter::new(0, primitive, transform, Color::new(1.0, 1.0, 1.0)).unwrap()
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 0.002 * b
+    }
+
+    #[test]
+    fn measures_the_stretched_surface() {
+        let pi = f32::consts::PI;
+        assert!(close(emitter(Primitive::Sphere, [2.0, 2.0, 2.0]).area, 16.0 * pi));
+        assert!(close(emitter(Primitive::Cube, [1.0, 2.0, 3.0]).area, 22.0));
+        assert!(close(emitter(Primitive::Cylinder, [2.0, 1.0, 2.0]).area, 12.0 * pi));
+        // A prolate spheroid, 2 long and 1 across
+        let e = (1.0f32 - 0.25).sqrt();
+        let spheroid = 2.0 * pi * (1.0 + 2.0 / e * e.asin());
+        assert!(close(emitter(Primitive::Sphere, [1.0, 2.0, 1.0]).area, spheroid));
+    }
+
+    #[test]
+    fn spreads_samples_evenly_over_the_world() {
+        // A tall box, its ends are 2 of its 42 units of area
+        let tall = emitter(Primitive::Cube, [1.0, 1.0, 10.0]);
+        let mut rng = SmallRng::seed_from_u64(5);
+        let count = 20000;
+        let mut ends = 0;
+        for _ in 0..count {
+            let sample = tall.sample(&mut rng).unwrap();
+            assert_eq!(sample.pdf, 1.0 / tall.area);
+            if sample.normal.z.abs() > 0.5 {
+                ends += 1;
+            }
+        }
+        let fraction = ends as f32 / count as f32;
+        assert!((fraction - 2.0 / 42.0).abs() < 0.01, "{}", fraction);
+    }
+}
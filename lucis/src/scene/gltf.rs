@@ -0,0 +1,867 @@
+// glTF 2.0 models, as a .gltf JSON file or a binary .glb. The node tree becomes scene nodes
+// with the same transforms, and each material's base color, metallic, roughness and emission
+// become a PBR material along with its base color and normal textures
+
+use crate::geometry::{ByteReader, Endian, Face, Mesh, Primitive};
+use crate::scene::{Color, MaterialWrapper, Scene, SceneNodeRef, Texture};
+use nalgebra::{Matrix4, Quaternion, UnitQuaternion, Vector3};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+// "glTF" at the start of .glb files, and the types of their chunks
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_JSON: u32 = 0x4E4F_534A;
+const GLB_BIN: u32 = 0x004E_4942;
+
+// Primitive modes, points and lines are skipped
+const MODE_TRIANGLES: u32 = 4;
+const MODE_TRIANGLE_STRIP: u32 = 5;
+const MODE_TRIANGLE_FAN: u32 = 6;
+
+// Accessors without a buffer view are all zeros, which only fill in a little missing data. A
+// count past this is taken to be broken rather than allocated
+const MAX_ZERO_FILLED: usize = 1 << 20;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    #[serde(default)]
+    extensions_required: Vec<String>,
+    scene: Option<usize>,
+    #[serde(default)]
+    scenes: Vec<GltfScene>,
+    #[serde(default)]
+    nodes: Vec<Node>,
+    #[serde(default)]
+    meshes: Vec<GltfMesh>,
+    #[serde(default)]
+    materials: Vec<Material>,
+    #[serde(default)]
+    textures: Vec<GltfTexture>,
+    #[serde(default)]
+    images: Vec<Image>,
+    #[serde(default)]
+    accessors: Vec<Accessor>,
+    #[serde(default)]
+    buffer_views: Vec<BufferView>,
+    #[serde(default)]
+    buffers: Vec<Buffer>,
+}
+
+#[derive(Deserialize)]
+struct GltfScene {
+    #[serde(default)]
+    nodes: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    name: Option<String>,
+    #[serde(default)]
+    children: Vec<usize>,
+    mesh: Option<usize>,
+    // Column major
+    matrix: Option<[f32; 16]>,
+    translation: Option<[f32; 3]>,
+    // x, y, z, w
+    rotation: Option<[f32; 4]>,
+    scale: Option<[f32; 3]>,
+}
+
+#[derive(Deserialize)]
+struct GltfMesh {
+    name: Option<String>,
+    primitives: Vec<MeshPrimitive>,
+}
+
+#[derive(Deserialize)]
+struct MeshPrimitive {
+    attributes: Attributes,
+    indices: Option<usize>,
+    material: Option<usize>,
+    #[serde(default = "default_mode")]
+    mode: u32,
+}
+
+#[derive(Deserialize)]
+struct Attributes {
+    #[serde(rename = "POSITION")]
+    position: Option<usize>,
+    #[serde(rename = "NORMAL")]
+    normal: Option<usize>,
+    #[serde(rename = "TEXCOORD_0")]
+    texcoord: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Material {
+    #[serde(default)]
+    pbr_metallic_roughness: PbrMetallicRoughness,
+    normal_texture: Option<TextureInfo>,
+    #[serde(default)]
+    emissive_factor: [f32; 3],
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PbrMetallicRoughness {
+    #[serde(default = "default_base_color")]
+    base_color_factor: [f32; 4],
+    base_color_texture: Option<TextureInfo>,
+    #[serde(default = "default_factor")]
+    metallic_factor: f32,
+    #[serde(default = "default_factor")]
+    roughness_factor: f32,
+}
+
+impl Default for PbrMetallicRoughness {
+    fn default() -> Self {
+        PbrMetallicRoughness {
+            base_color_factor: default_base_color(),
+            base_color_texture: None,
+            metallic_factor: default_factor(),
+            roughness_factor: default_factor(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextureInfo {
+    index: usize,
+    #[serde(default)]
+    tex_coord: usize,
+}
+
+#[derive(Deserialize)]
+struct GltfTexture {
+    source: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Image {
+    uri: Option<String>,
+    buffer_view: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: Option<usize>,
+    #[serde(default)]
+    byte_offset: usize,
+    component_type: u32,
+    #[serde(default)]
+    normalized: bool,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: String,
+    sparse: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    #[serde(default)]
+    byte_offset: usize,
+    byte_length: usize,
+    byte_stride: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Buffer {
+    uri: Option<String>,
+}
+
+fn default_mode() -> u32 {
+    MODE_TRIANGLES
+}
+
+fn default_base_color() -> [f32; 4] {
+    [1.0, 1.0, 1.0, 1.0]
+}
+
+fn default_factor() -> f32 {
+    1.0
+}
+
+// A triangle primitive of a glTF mesh, with the index of its material
+type PrimitiveMesh = (Arc<Mesh>, Option<usize>);
+
+// The parsed file with its buffers loaded
+struct Gltf {
+    document: Document,
+    buffers: Vec<Vec<u8>>,
+}
+
+// Add the model in a .gltf or .glb file under a new node. Buffers and images outside the file
+// are handed to `resolve` by their URI to be read
+pub(crate) fn load<F>(scene: &mut Scene, bytes: &[u8], resolve: F, name: String) -> Result<SceneNodeRef, String>
+where
+    F: Fn(&str) -> Result<Vec<u8>, String>,
+{
+    let gltf = Gltf::parse(bytes, &resolve)?;
+    let document = &gltf.document;
+    if let Some(extension) = document.extensions_required.first() {
+        return Err(format!("the file needs the {} extension, which isn't supported", extension));
+    }
+
+    let materials = document
+        .materials
+        .iter()
+        .enumerate()
+        .map(|(i, m)| gltf.material(m, &resolve).map_err(|e| format!("material {}: {}", i, e)))
+        .collect::<Result<Vec<_>, String>>()?;
+    // Meshes can be used by several nodes, they share the triangles
+    let meshes = document
+        .meshes
+        .iter()
+        .enumerate()
+        .map(|(i, m)| gltf.mesh(m).map_err(|e| format!("mesh {}: {}", i, e)))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let roots = match document.scene {
+        Some(s) => document
+            .scenes
+            .get(s)
+            .ok_or_else(|| format!("scene {} doesn't exist", s))?
+            .nodes
+            .clone(),
+        None if !document.scenes.is_empty() => document.scenes[0].nodes.clone(),
+        // Without scenes, every node that isn't a child is a root
+        None => {
+            let children: HashSet<usize> = document.nodes.iter().flat_map(|n| n.children.iter().cloned()).collect();
+            (0..document.nodes.len()).filter(|i| !children.contains(i)).collect()
+        }
+    };
+
+    let mut root = scene.add_node(Primitive::None, name);
+    let mut visited = HashSet::new();
+    for node in roots {
+        let child = add_node(scene, document, &meshes, &materials, node, &mut visited)?;
+        root.add_child(&child);
+    }
+    Ok(root)
+}
+
+fn add_node(
+    scene: &mut Scene,
+    document: &Document,
+    meshes: &[Vec<PrimitiveMesh>],
+    materials: &[MaterialWrapper],
+    index: usize,
+    visited: &mut HashSet<usize>,
+) -> Result<SceneNodeRef, String> {
+    let node = document.nodes.get(index).ok_or_else(|| format!("node {} doesn't exist", index))?;
+    if !visited.insert(index) {
+        return Err(format!("node {} is used more than once", index));
+    }
+    let name = node.name.clone().unwrap_or_else(|| format!("node {}", index));
+
+    let primitives = match node.mesh {
+        Some(m) => meshes.get(m).ok_or_else(|| format!("node {}: mesh {} doesn't exist", index, m))?.as_slice(),
+        None => &[],
+    };
+    // A node with a single primitive holds it, otherwise each gets a child node
+    let mut scene_node = if primitives.len() == 1 {
+        mesh_node(scene, &primitives[0], materials, name.clone())?
+    } else {
+        scene.add_node(Primitive::None, name.clone())
+    };
+    if primitives.len() > 1 {
+        let mesh_name = node
+            .mesh
+            .and_then(|m| document.meshes[m].name.clone())
+            .unwrap_or_else(|| name.clone());
+        for (i, primitive) in primitives.iter().enumerate() {
+            let child = mesh_node(scene, primitive, materials, format!("{} ({})", mesh_name, i))?;
+            scene_node.add_child(&child);
+        }
+    }
+
+    scene_node.apply_matrix(node_transform(node));
+    for child in node.children.iter() {
+        let child = add_node(scene, document, meshes, materials, *child, visited)?;
+        scene_node.add_child(&child);
+    }
+    Ok(scene_node)
+}
+
+fn mesh_node(
+    scene: &mut Scene,
+    (mesh, material): &PrimitiveMesh,
+    materials: &[MaterialWrapper],
+    name: String,
+) -> Result<SceneNodeRef, String> {
+    let mut node = scene.add_node(Primitive::Mesh(Arc::clone(mesh)), name);
+    // Primitives without a material keep the default one
+    if let Some(m) = material {
+        node.set_material(materials.get(*m).ok_or_else(|| format!("material {} doesn't exist", m))?);
+    }
+    Ok(node)
+}
+
+fn node_transform(node: &Node) -> Matrix4<f32> {
+    if let Some(m) = node.matrix {
+        return Matrix4::from_column_slice(&m);
+    }
+    let t = node.translation.unwrap_or([0.0; 3]);
+    let [x, y, z, w] = node.rotation.unwrap_or([0.0, 0.0, 0.0, 1.0]);
+    let s = node.scale.unwrap_or([1.0; 3]);
+    let rotation = UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z));
+    Matrix4::new_translation(&Vector3::new(t[0], t[1], t[2]))
+        * rotation.to_homogeneous()
+        * Matrix4::new_nonuniform_scaling(&Vector3::new(s[0], s[1], s[2]))
+}
+
+impl Gltf {
+    fn parse<F: Fn(&str) -> Result<Vec<u8>, String>>(bytes: &[u8], resolve: &F) -> Result<Gltf, String> {
+        let mut reader = ByteReader::new(bytes, Endian::Little);
+        let (json, mut bin) = if bytes.len() >= 12 && reader.u32()? == GLB_MAGIC {
+            let version = reader.u32()?;
+            if version != 2 {
+                return Err(format!("only glTF 2.0 is supported, this is version {}", version));
+            }
+            reader.u32()?;
+            let (mut json, mut bin) = (None, None);
+            while reader.position() < bytes.len() {
+                let length = reader.u32()? as usize;
+                let kind = reader.u32()?;
+                let data = reader.take(length)?;
+                match kind {
+                    GLB_JSON => json = Some(data),
+                    GLB_BIN => bin = Some(data.to_vec()),
+                    // Chunks from extensions
+                    _ => {}
+                }
+            }
+            (json.ok_or("the .glb file has no JSON chunk")?, bin)
+        } else {
+            (bytes, None)
+        };
+        let document: Document = serde_json::from_slice(json).map_err(|e| format!("invalid glTF JSON: {}", e))?;
+
+        let buffers = document
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| match &buffer.uri {
+                Some(uri) => read_uri(uri, resolve).map_err(|e| format!("buffer {}: {}", i, e)),
+                // The binary chunk of a .glb file
+                None if i == 0 => bin.take().ok_or_else(|| "buffer 0 has no uri and there is no binary chunk".to_string()),
+                None => Err(format!("buffer {} has no uri", i)),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Gltf { document, buffers })
+    }
+
+    fn buffer_view(&self, index: usize) -> Result<(&[u8], Option<usize>), String> {
+        let view = self
+            .document
+            .buffer_views
+            .get(index)
+            .ok_or_else(|| format!("buffer view {} doesn't exist", index))?;
+        let buffer = self
+            .buffers
+            .get(view.buffer)
+            .ok_or_else(|| format!("buffer {} doesn't exist", view.buffer))?;
+        let bytes = view
+            .byte_offset
+            .checked_add(view.byte_length)
+            .and_then(|end| buffer.get(view.byte_offset..end))
+            .ok_or_else(|| format!("buffer view {} runs past the end of its buffer", index))?;
+        Ok((bytes, view.byte_stride))
+    }
+
+    // The values of an accessor as floats, `width` at a time. Normalized integers are brought
+    // into 0 to 1, or -1 to 1 when signed
+    fn read_accessor(&self, index: usize, width: usize) -> Result<Vec<f32>, String> {
+        let accessor = self
+            .document
+            .accessors
+            .get(index)
+            .ok_or_else(|| format!("accessor {} doesn't exist", index))?;
+        let error = |e: String| format!("accessor {}: {}", index, e);
+        if accessor.sparse.is_some() {
+            return Err(error("sparse accessors aren't supported".to_string()));
+        }
+        let components = match accessor.kind.as_str() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            other => return Err(error(format!("{} values aren't supported", other))),
+        };
+        if components != width {
+            return Err(error(format!("expected {} values each, got {}", width, accessor.kind)));
+        }
+        let size = match accessor.component_type {
+            5120 | 5121 => 1,
+            5122 | 5123 => 2,
+            5125 | 5126 => 4,
+            other => return Err(error(format!("unknown component type {}", other))),
+        };
+        let (bytes, stride) = match accessor.buffer_view {
+            Some(view) => self.buffer_view(view).map_err(error)?,
+            // No buffer view means all zeros
+            None => {
+                check_zero_filled(accessor).map_err(error)?;
+                return Ok(vec![0.0; accessor.count * width]);
+            }
+        };
+        let stride = stride.unwrap_or(size * components);
+        check_range(accessor, bytes.len(), size * components, stride).map_err(error)?;
+        let mut out = Vec::with_capacity(accessor.count * width);
+        for i in 0..accessor.count {
+            let start = accessor.byte_offset + i * stride;
+            let element = bytes
+                .get(start..start + size * components)
+                .ok_or_else(|| error("runs past the end of its buffer view".to_string()))?;
+            let mut reader = ByteReader::new(element, Endian::Little);
+            for _ in 0..components {
+                let n = accessor.normalized;
+                out.push(match accessor.component_type {
+                    5120 if n => (reader.i8()? as f32 / 127.0).max(-1.0),
+                    5120 => reader.i8()? as f32,
+                    5121 if n => reader.u8()? as f32 / 255.0,
+                    5121 => reader.u8()? as f32,
+                    5122 if n => (reader.i16()? as f32 / 32767.0).max(-1.0),
+                    5122 => reader.i16()? as f32,
+                    5123 if n => reader.u16()? as f32 / 65535.0,
+                    5123 => reader.u16()? as f32,
+                    5125 => reader.u32()? as f32,
+                    _ => reader.f32()?,
+                });
+            }
+        }
+        Ok(out)
+    }
+
+    // Indices need all 32 bits, so they aren't read as floats
+    fn read_indices(&self, index: usize) -> Result<Vec<usize>, String> {
+        let accessor = self
+            .document
+            .accessors
+            .get(index)
+            .ok_or_else(|| format!("accessor {} doesn't exist", index))?;
+        if accessor.component_type != 5125 {
+            return Ok(self.read_accessor(index, 1)?.into_iter().map(|i| i as usize).collect());
+        }
+        let (bytes, stride) = match accessor.buffer_view {
+            Some(view) => self.buffer_view(view)?,
+            None => {
+                check_zero_filled(accessor).map_err(|e| format!("accessor {}: {}", index, e))?;
+                return Ok(vec![0; accessor.count]);
+            }
+        };
+        let stride = stride.unwrap_or(4);
+        check_range(accessor, bytes.len(), 4, stride).map_err(|e| format!("accessor {}: {}", index, e))?;
+        (0..accessor.count)
+            .map(|i| {
+                let start = accessor.byte_offset + i * stride;
+                let element = bytes
+                    .get(start..start + 4)
+                    .ok_or_else(|| format!("accessor {}: runs past the end of its buffer view", index))?;
+                Ok(ByteReader::new(element, Endian::Little).u32()? as usize)
+            })
+            .collect()
+    }
+
+    // One lucis mesh for each triangle primitive
+    fn mesh(&self, mesh: &GltfMesh) -> Result<Vec<PrimitiveMesh>, String> {
+        let mut out = vec![];
+        for (i, primitive) in mesh.primitives.iter().enumerate() {
+            if ![MODE_TRIANGLES, MODE_TRIANGLE_STRIP, MODE_TRIANGLE_FAN].contains(&primitive.mode) {
+                continue;
+            }
+            let error = |e: String| format!("primitive {}: {}", i, e);
+            let position = primitive
+                .attributes
+                .position
+                .ok_or_else(|| error("there are no positions".to_string()))?;
+            let vertices: Vec<Vector3<f32>> = self
+                .read_accessor(position, 3)
+                .map_err(error)?
+                .chunks(3)
+                .map(|p| Vector3::new(p[0], p[1], p[2]))
+                .collect();
+            let normals: Vec<Vector3<f32>> = match primitive.attributes.normal {
+                Some(normal) => self
+                    .read_accessor(normal, 3)
+                    .map_err(error)?
+                    .chunks(3)
+                    .map(|n| Vector3::new(n[0], n[1], n[2]))
+                    .collect(),
+                None => vec![],
+            };
+            // glTF measures v down from the top of the image
+            let uvs: Vec<[f32; 2]> = match primitive.attributes.texcoord {
+                Some(uv) => self
+                    .read_accessor(uv, 2)
+                    .map_err(error)?
+                    .chunks(2)
+                    .map(|t| [t[0], 1.0 - t[1]])
+                    .collect(),
+                None => vec![],
+            };
+            let indices = match primitive.indices {
+                Some(indices) => self.read_indices(indices).map_err(error)?,
+                None => (0..vertices.len()).collect(),
+            };
+
+            let triangles: Vec<[usize; 3]> = match primitive.mode {
+                MODE_TRIANGLE_STRIP => (2..indices.len())
+                    .map(|k| {
+                        // Every other triangle is flipped to keep the winding the same
+                        if k % 2 == 0 {
+                            [indices[k - 2], indices[k - 1], indices[k]]
+                        } else {
+                            [indices[k - 1], indices[k - 2], indices[k]]
+                        }
+                    })
+                    .collect(),
+                MODE_TRIANGLE_FAN => (2..indices.len())
+                    .map(|k| [indices[0], indices[k - 1], indices[k]])
+                    .collect(),
+                _ => indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect(),
+            };
+            let has_normals = normals.len() == vertices.len();
+            let has_uvs = uvs.len() == vertices.len();
+            let faces = triangles
+                .into_iter()
+                .map(|t| {
+                    let mut face = Face::new(t);
+                    face.normals = if has_normals { Some(t) } else { None };
+                    face.uvs = if has_uvs { Some(t) } else { None };
+                    face
+                })
+                .collect();
+            let normals = if has_normals { normals } else { vec![] };
+            let uvs = if has_uvs { uvs } else { vec![] };
+            let lucis_mesh = Mesh::from_parts(vertices, normals, uvs, faces).map_err(error)?;
+            out.push((Arc::new(lucis_mesh), primitive.material));
+        }
+        Ok(out)
+    }
+
+    fn material<F: Fn(&str) -> Result<Vec<u8>, String>>(
+        &self,
+        material: &Material,
+        resolve: &F,
+    ) -> Result<MaterialWrapper, String> {
+        let pbr = &material.pbr_metallic_roughness;
+        let [r, g, b, _] = pbr.base_color_factor;
+        let mut wrapper =
+            MaterialWrapper::pbr(&Color::new(r, g, b), pbr.metallic_factor, pbr.roughness_factor.max(0.0));
+        let [r, g, b] = material.emissive_factor;
+        wrapper.set_emitted_radiance(&Color::new(r, g, b));
+        let base_color = match &pbr.base_color_texture {
+            Some(info) => self.texture(info, resolve)?,
+            None => None,
+        };
+        if let Some(texture) = base_color {
+            wrapper.set_diffuse_texture(&texture);
+        }
+        let normal = match &material.normal_texture {
+            Some(info) => self.texture(info, resolve)?,
+            None => None,
+        };
+        if let Some(texture) = normal {
+            wrapper.set_normal_texture(&texture);
+        }
+        Ok(wrapper)
+    }
+
+    // Only the first set of texture coordinates is read, so textures using another set are left
+    // off and the material keeps just its factors
+    fn texture<F: Fn(&str) -> Result<Vec<u8>, String>>(
+        &self,
+        info: &TextureInfo,
+        resolve: &F,
+    ) -> Result<Option<Texture>, String> {
+        if info.tex_coord != 0 {
+            return Ok(None);
+        }
+        let source = self
+            .document
+            .textures
+            .get(info.index)
+            .and_then(|t| t.source)
+            .ok_or_else(|| format!("texture {} has no image", info.index))?;
+        let image = self
+            .document
+            .images
+            .get(source)
+            .ok_or_else(|| format!("image {} doesn't exist", source))?;
+        let bytes = match (&image.uri, image.buffer_view) {
+            (Some(uri), _) => read_uri(uri, resolve)?,
+            (None, Some(view)) => self.buffer_view(view)?.0.to_vec(),
+            (None, None) => return Err(format!("image {} has no uri or buffer view", source)),
+        };
+        Texture::decode(&bytes)
+            .map(Some)
+            .map_err(|e| format!("image {}: {}", source, e))
+    }
+}
+
+fn check_zero_filled(accessor: &Accessor) -> Result<(), String> {
+    if accessor.count > MAX_ZERO_FILLED {
+        return Err(format!(
+            "{} values without a buffer view, at most {} are allowed",
+            accessor.count, MAX_ZERO_FILLED
+        ));
+    }
+    Ok(())
+}
+
+// Make sure every element of an accessor, `size` bytes each and `stride` apart, fits in the
+// `len` bytes of its buffer view before anything is allocated or read
+fn check_range(accessor: &Accessor, len: usize, size: usize, stride: usize) -> Result<(), String> {
+    if stride < size {
+        return Err(format!("a stride of {} is shorter than its {} byte elements", stride, size));
+    }
+    let last = match accessor.count.checked_sub(1) {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+    let end = last
+        .checked_mul(stride)
+        .and_then(|start| start.checked_add(accessor.byte_offset))
+        .and_then(|start| start.checked_add(size));
+    match end {
+        Some(end) if end <= len => Ok(()),
+        _ => Err("runs past the end of its buffer view".to_string()),
+    }
+}
+
+// The bytes of a data URI, or of a file relative to the model read by `resolve`
+fn read_uri<F: Fn(&str) -> Result<Vec<u8>, String>>(uri: &str, resolve: &F) -> Result<Vec<u8>, String> {
+    if uri.starts_with("data:") {
+        let (header, data) = uri.split_once(',').ok_or("a data URI has no data")?;
+        if !header.ends_with(";base64") {
+            return Err("only base64 data URIs are supported".to_string());
+        }
+        return decode_base64(data);
+    }
+    resolve(&decode_percent(uri))
+}
+
+// URIs escape spaces and other characters as %XX
+fn decode_percent(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(b) if bytes[i] == b'%' => {
+                out.push(b);
+                i += 3;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in data.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' | b'\n' | b'\r' | b' ' => continue,
+            _ => return Err(format!("'{}' isn't base64", c as char)),
+        };
+        // Only the bits not yet written out are kept
+        bits = ((bits << 6) | value as u32) & 0xFFFF;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    // One triangle in an embedded buffer, with a material whose texture uses `tex_coord`
+    fn triangle(count: usize, tex_coord: usize) -> Value {
+        json!({
+            "asset": {"version": "2.0"},
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"name": "triangle", "mesh": 0}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "material": 0}]}],
+            "materials": [{"pbrMetallicRoughness": {"baseColorTexture": {"index": 0, "texCoord": tex_coord}}}],
+            "accessors": [{"bufferView": 0, "componentType": 5126, "count": count, "type": "VEC3"}],
+            "bufferViews": [{"buffer": 0, "byteLength": 36}],
+            "buffers": [{"byteLength": 36, "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"}]
+        })
+    }
+
+    // One primitive drawn from `points` in the given mode, for a .glb file to hold the points
+    fn shape(points: &[[f32; 3]], mode: u32) -> (Value, Vec<u8>) {
+        let length = 12 * points.len();
+        let json = json!({
+            "asset": {"version": "2.0"},
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"name": "shape", "mesh": 0}],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "mode": mode}]}],
+            "accessors": [{"bufferView": 0, "componentType": 5126, "count": points.len(), "type": "VEC3"}],
+            "bufferViews": [{"buffer": 0, "byteLength": length}],
+            "buffers": [{"byteLength": length}]
+        });
+        let bin = points.iter().flatten().flat_map(|f| f.to_le_bytes()).collect();
+        (json, bin)
+    }
+
+    // A .glb file with a JSON chunk and a binary chunk, both padded to four bytes
+    fn glb(json: &Value, bin: &[u8]) -> Vec<u8> {
+        let mut json = json.to_string().into_bytes();
+        json.resize(json.len().div_ceil(4) * 4, b' ');
+        let mut bin = bin.to_vec();
+        bin.resize(bin.len().div_ceil(4) * 4, 0);
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        let mut bytes = vec![];
+        for word in [GLB_MAGIC, 2, length as u32, json.len() as u32, GLB_JSON] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend(json);
+        for word in [bin.len() as u32, GLB_BIN] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend(bin);
+        bytes
+    }
+
+    fn load_bytes(bytes: &[u8]) -> Result<Scene, String> {
+        let mut scene = Scene::new();
+        load(&mut scene, bytes, |uri| Err(format!("no file {}", uri)), "model".to_string())?;
+        Ok(scene)
+    }
+
+    fn load_json(json: &Value) -> Result<Scene, String> {
+        load_bytes(json.to_string().as_bytes())
+    }
+
+    // The triangles of the model's only mesh
+    fn triangles(scene: &Scene) -> Vec<[Vector3<f32>; 3]> {
+        match &scene.nodes.read().unwrap()[1].primitive {
+            Primitive::Mesh(mesh) => (0..mesh.faces.len()).map(|face| mesh.triangle(face)).collect(),
+            _ => panic!("expected a mesh"),
+        }
+    }
+
+    #[test]
+    fn loads_embedded_buffer() {
+        // Texture coordinates other than the first are skipped, so the missing texture isn't read
+        let scene = load_json(&triangle(3, 1)).unwrap();
+        let nodes = scene.nodes.read().unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].children, vec![1]);
+        assert_eq!(nodes[1].name, "triangle");
+        match &nodes[1].primitive {
+            Primitive::Mesh(mesh) => {
+                assert_eq!(mesh.faces.len(), 1);
+                assert_eq!(mesh.vertices[1], Vector3::new(1.0, 0.0, 0.0));
+                assert_eq!(mesh.vertices[2], Vector3::new(0.0, 1.0, 0.0));
+            }
+            _ => panic!("expected a mesh"),
+        }
+    }
+
+    #[test]
+    fn loads_binary_chunk() {
+        let (json, bin) = shape(&[[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [0.0, 3.0, 0.0]], MODE_TRIANGLES);
+        let scene = load_bytes(&glb(&json, &bin)).unwrap();
+        let triangles = triangles(&scene);
+        assert_eq!(triangles.len(), 1);
+        assert_eq!(triangles[0][2], Vector3::new(0.0, 3.0, 0.0));
+        // An empty chunk has no positions to read
+        assert!(load_bytes(&glb(&json, &[])).is_err());
+    }
+
+    #[test]
+    fn loads_triangle_strips_and_fans() {
+        // Both cover the unit square in two triangles facing +z
+        let strip = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 1.0, 0.0]];
+        let fan = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+        for (points, mode) in [(strip, MODE_TRIANGLE_STRIP), (fan, MODE_TRIANGLE_FAN)] {
+            let (json, bin) = shape(&points, mode);
+            let triangles = triangles(&load_bytes(&glb(&json, &bin)).unwrap());
+            assert_eq!(triangles.len(), 2, "mode {}", mode);
+            let mut area = 0.0;
+            for [a, b, c] in triangles {
+                let normal = (b - a).cross(&(c - a));
+                assert!(normal.z > 0.0, "mode {}", mode);
+                area += normal.norm() / 2.0;
+            }
+            assert!((area - 1.0).abs() < 1e-6, "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn emissive_factor_is_the_radiance_leaving_the_surface() {
+        let mut json = triangle(3, 1);
+        json["materials"][0]["emissiveFactor"] = json!([1.0, 0.5, 0.0]);
+        let scene = load_json(&json).unwrap();
+        let radiance = scene.nodes.read().unwrap()[1].radiance();
+        for (got, expected) in [radiance.r, radiance.g, radiance.b].iter().zip([1.0, 0.5, 0.0].iter()) {
+            assert!((got - expected).abs() < 1e-6, "{:?}", radiance);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(load_bytes(b"{\"nodes\": [").is_err());
+        // Past the end of the buffer view, and so big the size overflows
+        assert!(load_json(&triangle(4, 1)).is_err());
+        assert!(load_json(&triangle(usize::MAX, 1)).is_err());
+        // Zeros without a buffer view, too many to be real
+        let zeros = |count: usize| {
+            let mut json = triangle(count, 1);
+            json["accessors"][0].as_object_mut().unwrap().remove("bufferView");
+            json
+        };
+        assert!(load_json(&zeros(3)).is_ok());
+        assert!(load_json(&zeros(1 << 40)).is_err());
+        let indices = |count: usize| {
+            let mut json = triangle(3, 1);
+            json["meshes"][0]["primitives"][0]["indices"] = json!(1);
+            json["accessors"].as_array_mut().unwrap().push(json!({"componentType": 5125, "count": count, "type": "SCALAR"}));
+            json
+        };
+        assert!(load_json(&indices(3)).is_ok());
+        assert!(load_json(&indices(1 << 40)).is_err());
+        // The first texture coordinates are read, and the texture doesn't exist
+        assert!(load_json(&triangle(3, 0)).is_err());
+        // A .glb header with nothing after it
+        assert!(load_bytes(b"glTF\x02\0\0\0").is_err());
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("bHVjaXM=").unwrap(), b"lucis");
+        assert_eq!(decode_base64("/wAQ").unwrap(), vec![0xFF, 0x00, 0x10]);
+        // The URL safe alphabet and line breaks
+        assert_eq!(decode_base64("_w\nAQ").unwrap(), vec![0xFF, 0x00, 0x10]);
+        assert_eq!(decode_base64("").unwrap(), Vec::<u8>::new());
+        assert!(decode_base64("bHV*").is_err());
+    }
+}
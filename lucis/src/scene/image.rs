@@ -0,0 +1,207 @@
+// Decoded image textures and the lookups into them
+
+use crate::scene::Color;
+use nalgebra::Vector3;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+// What happens to texture coordinates outside of [0, 1]
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WrapMode {
+    // Tile the image
+    #[default]
+    Repeat,
+    // Stretch the pixels along the edges
+    Clamp,
+    // Tile the image, flipping every other copy so the edges meet
+    Mirror,
+}
+
+// How pixels are blended between their centers
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureFilter {
+    // Blocky, the closest pixel
+    Nearest,
+    // Smooth, a weighted average of the four closest pixels
+    #[default]
+    Bilinear,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Image {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    // Rows from top to bottom
+    pub(crate) pixels: Vec<Color>,
+    // The file the image was read from, so scenes using it can be saved
+    pub(crate) path: Option<String>,
+}
+
+impl Image {
+    // 4 bytes per pixel, alpha is ignored
+    pub(crate) fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> Result<Image, String> {
+        Image::from_channels(width, height, 4, rgba)
+    }
+
+    // Decode a PNG or JPEG file, telling them apart by their first bytes
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Image, String> {
+        if bytes.starts_with(b"\x89PNG") {
+            Image::decode_png(bytes)
+        } else if bytes.starts_with(&[0xff, 0xd8]) {
+            Image::decode_jpeg(bytes)
+        } else {
+            Err("unsupported image format, expected a PNG or JPEG".to_string())
+        }
+    }
+
+    fn decode_png(bytes: &[u8]) -> Result<Image, String> {
+        let mut decoder = png::Decoder::new(bytes);
+        // Palettes, low bit depths and 16 bit channels all become 8 bit channels
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|e| format!("invalid PNG: {}", e))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).map_err(|e| format!("invalid PNG: {}", e))?;
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::Rgb => 3,
+            png::ColorType::Rgba => 4,
+            png::ColorType::Indexed => return Err("invalid PNG: palette wasn't expanded".to_string()),
+        };
+        // Rows can be padded
+        let row = info.width as usize * channels;
+        let data: Vec<u8> = buf
+            .chunks(info.line_size)
+            .take(info.height as usize)
+            .flat_map(|line| line[..row].iter().cloned())
+            .collect();
+        Image::from_channels(info.width, info.height, channels, &data)
+    }
+
+    fn decode_jpeg(bytes: &[u8]) -> Result<Image, String> {
+        let mut decoder = jpeg_decoder::Decoder::new(bytes);
+        let data = decoder.decode().map_err(|e| format!("invalid JPEG: {}", e))?;
+        let info = decoder.info().ok_or("invalid JPEG: missing header")?;
+        let (width, height) = (info.width as u32, info.height as u32);
+        match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => Image::from_channels(width, height, 1, &data),
+            jpeg_decoder::PixelFormat::RGB24 => Image::from_channels(width, height, 3, &data),
+            jpeg_decoder::PixelFormat::L16 => {
+                let data: Vec<u8> = data
+                    .chunks(2)
+                    .map(|c| (u16::from_ne_bytes([c[0], c[1]]) >> 8) as u8)
+                    .collect();
+                Image::from_channels(width, height, 1, &data)
+            }
+            jpeg_decoder::PixelFormat::CMYK32 => {
+                // The decoder leaves the channels inverted, like most files store them
+                let data: Vec<u8> = data
+                    .chunks(4)
+                    .flat_map(|c| {
+                        let k = c[3] as u32;
+                        let channel = |x: u8| (x as u32 * k / 255) as u8;
+                        vec![channel(c[0]), channel(c[1]), channel(c[2])]
+                    })
+                    .collect();
+                Image::from_channels(width, height, 3, &data)
+            }
+        }
+    }
+
+    // Build an image from 8 bit channels. 1 and 2 channels are gray (with alpha), 3 and 4 are
+    // RGB (with alpha)
+    fn from_channels(width: u32, height: u32, channels: usize, data: &[u8]) -> Result<Image, String> {
+        let expected = width as usize * height as usize * channels;
+        if width == 0 || height == 0 || data.len() != expected {
+            return Err(format!(
+                "expected {} bytes for a {}x{} image, got {}",
+                expected,
+                width,
+                height,
+                data.len()
+            ));
+        }
+        let pixels = data
+            .chunks(channels)
+            .map(|p| {
+                let (r, g, b) = if channels < 3 { (p[0], p[0], p[0]) } else { (p[0], p[1], p[2]) };
+                Color::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0)
+            })
+            .collect();
+        Ok(Image {
+            width,
+            height,
+            pixels,
+            path: None,
+        })
+    }
+
+    // Back to 4 bytes per pixel
+    pub(crate) fn to_rgba(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|c| {
+                let channel = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+                vec![channel(c.r), channel(c.g), channel(c.b), 255]
+            })
+            .collect()
+    }
+
+    // The color at (u, v), with v going up the image
+    pub(crate) fn sample(&self, u: f32, v: f32, wrap: WrapMode, filter: TextureFilter) -> Color {
+        let x = u * self.width as f32;
+        let y = (1.0 - v) * self.height as f32;
+        match filter {
+            TextureFilter::Nearest => self.pixel(x.floor() as i64, y.floor() as i64, wrap),
+            TextureFilter::Bilinear => {
+                // Measure from the centers of the pixels
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let top = lerp(
+                    self.pixel(x0, y0, wrap).into(),
+                    self.pixel(x0 + 1, y0, wrap).into(),
+                    fx,
+                );
+                let bottom = lerp(
+                    self.pixel(x0, y0 + 1, wrap).into(),
+                    self.pixel(x0 + 1, y0 + 1, wrap).into(),
+                    fx,
+                );
+                Color::from(lerp(top, bottom, fy))
+            }
+        }
+    }
+
+    fn pixel(&self, x: i64, y: i64, wrap: WrapMode) -> Color {
+        let x = wrap_index(x, self.width as i64, wrap);
+        let y = wrap_index(y, self.height as i64, wrap);
+        self.pixels[y * self.width as usize + x]
+    }
+}
+
+fn lerp(a: Vector3<f32>, b: Vector3<f32>, t: f32) -> Vector3<f32> {
+    a * (1.0 - t) + b * t
+}
+
+// Bring a pixel index outside of 0..size back into the image
+fn wrap_index(i: i64, size: i64, wrap: WrapMode) -> usize {
+    let i = match wrap {
+        WrapMode::Repeat => i.rem_euclid(size),
+        WrapMode::Clamp => i.clamp(0, size - 1),
+        WrapMode::Mirror => {
+            let i = i.rem_euclid(2 * size);
+            if i < size {
+                i
+            } else {
+                2 * size - 1 - i
+            }
+        }
+    };
+    i as usize
+}
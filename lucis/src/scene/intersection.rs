@@ -1,4 +1,4 @@
-use crate::scene::SceneNode;
+use crate::scene::tangent_frame;
 use nalgebra::{Affine3, Point3, Vector3, U3};
 use std::cmp::{Ordering, PartialEq, PartialOrd};
 
@@ -11,6 +11,18 @@ pub struct Intersection {
     pub normal: Vector3<f32>,
     pub u_value: f32,
     pub v_value: f32,
+    // Where the hit is in the space of the node that was hit, unlike `point` it moves along
+    // with the node. Used to place procedural textures
+    pub object_point: Point3<f32>,
+    // How the point moves as u and v grow, dP/du and dP/dv. Not normalized or perpendicular
+    pub tangent: Vector3<f32>,
+    pub bitangent: Vector3<f32>,
+    // The same in the space of the node that was hit, to step texture lookups along the surface
+    pub object_tangent: Vector3<f32>,
+    pub object_bitangent: Vector3<f32>,
+    // True when the ray hit the outside of the surface and is entering the solid, false when
+    // it is leaving. The normal always points out of the solid either way
+    pub entering: bool,
 }
 
 impl PartialEq for Intersection {
@@ -33,7 +45,11 @@ impl Intersection {
         normal: Vector3<f32>,
         u_value: f32,
         v_value: f32,
+        entering: bool,
     ) -> Intersection {
+        // Made in the space of the node that was hit, before any transforms. Any two directions
+        // across the surface will do until the primitive gives its own
+        let (tangent, bitangent) = tangent_frame(&normal.normalize());
         Intersection {
             t_value,
             point,
@@ -41,6 +57,23 @@ impl Intersection {
             normal,
             u_value,
             v_value,
+            object_point: point,
+            tangent,
+            bitangent,
+            object_tangent: tangent,
+            object_bitangent: bitangent,
+            entering,
+        }
+    }
+
+    // Set dP/du and dP/dv, given in the space of the node that was hit
+    pub fn with_tangents(self, tangent: Vector3<f32>, bitangent: Vector3<f32>) -> Intersection {
+        Intersection {
+            tangent,
+            bitangent,
+            object_tangent: tangent,
+            object_bitangent: bitangent,
+            ..self
         }
     }
 
@@ -55,6 +88,8 @@ impl Intersection {
             .transpose();
         let transformed_point = transform * self.point;
         let transformed_normal = (inv_mat3_transpose * self.normal).normalize();
+        // Directions along the surface move with it, unlike the normal
+        let linear = transform.matrix().fixed_resize::<U3, U3>(0.0f32);
         Intersection {
             t_value: self.t_value,
             point: transformed_point,
@@ -62,6 +97,12 @@ impl Intersection {
             normal: transformed_normal,
             u_value: self.u_value,
             v_value: self.v_value,
+            object_point: self.object_point,
+            tangent: linear * self.tangent,
+            bitangent: linear * self.bitangent,
+            object_tangent: self.object_tangent,
+            object_bitangent: self.object_bitangent,
+            entering: self.entering,
         }
     }
 }
@@ -63,7 +63,6 @@ impl Light {
         // Generate an array of random light samples uniformly distributed across the light sphere
         let mut samples = vec![];
         let samples_per_dimension = (num_samples as f32).sqrt() as u32;
-        println!("Samples per dimension: {}", samples_per_dimension);
         assert!(samples_per_dimension > 1);
         let inc = (radius * 2.0) / (samples_per_dimension - 1) as f32;
 
@@ -74,7 +73,6 @@ impl Light {
                 let y_delta = -radius + (y as f32 * inc);
                 //                    let z_delta = -radius + (z as f32 * inc);
                 let sample = Point3::new(x_delta, y_delta, 0.0) + position.coords;
-                println!("Added sample: {}", sample);
                 samples.push(sample);
                 //                }
             }
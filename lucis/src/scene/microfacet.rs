@@ -0,0 +1,162 @@
+// Cook-Torrance microfacet BRDF with the GGX distribution, for metallic/roughness materials
+//
+// All vectors point away from the surface and are normalized, `n` is on the side of `v`.
+
+use crate::scene::Color;
+use nalgebra::Vector3;
+use std::f32;
+
+// Reflectance of non-metals at normal incidence, about right for most of them
+const DIELECTRIC_F0: f32 = 0.04;
+// Perfectly smooth surfaces turn the distribution into a spike nothing can sample
+const MIN_ROUGHNESS: f32 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Microfacet {
+    pub(crate) base_color: Vector3<f32>,
+    pub(crate) metallic: f32,
+    // GGX alpha, the square of the perceptual roughness
+    pub(crate) alpha: f32,
+}
+
+impl Microfacet {
+    pub(crate) fn new(base_color: Color, metallic: f32, roughness: f32) -> Microfacet {
+        let roughness = roughness.clamp(MIN_ROUGHNESS, 1.0);
+        Microfacet {
+            base_color: base_color.into(),
+            metallic: metallic.clamp(0.0, 1.0),
+            alpha: roughness * roughness,
+        }
+    }
+
+    // Reflectance at normal incidence, metals tint their reflections with the base color
+    pub(crate) fn f0(&self) -> Vector3<f32> {
+        let dielectric = Vector3::new(DIELECTRIC_F0, DIELECTRIC_F0, DIELECTRIC_F0);
+        dielectric * (1.0 - self.metallic) + self.base_color * self.metallic
+    }
+
+    // The BRDF for light arriving from `l` and leaving towards `v`
+    pub(crate) fn eval(&self, n: &Vector3<f32>, v: &Vector3<f32>, l: &Vector3<f32>) -> Vector3<f32> {
+        let n_dot_l = n.dot(l);
+        let n_dot_v = n.dot(v);
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+
+        let h = (v + l).normalize();
+        let f = schlick(&self.f0(), v.dot(&h));
+        let specular = f * (self.distribution(n.dot(&h)) * self.geometry(n_dot_l, n_dot_v)
+            / (4.0 * n_dot_l * n_dot_v));
+        // Light that isn't reflected at the surface is scattered inside, except by metals
+        let diffuse = (Vector3::new(1.0, 1.0, 1.0) - f).component_mul(&self.base_color)
+            * ((1.0 - self.metallic) / f32::consts::PI);
+        specular + diffuse
+    }
+
+    // Pick a microfacet normal in proportion to D(h) * cos(theta_h), from two numbers in [0, 1)
+    pub(crate) fn sample_half_vector(&self, n: &Vector3<f32>, u1: f32, u2: f32) -> Vector3<f32> {
+        let a2 = self.alpha * self.alpha;
+        let cos_theta = ((1.0 - u1) / (1.0 + (a2 - 1.0) * u1)).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * f32::consts::PI * u2;
+        let (t, b) = tangent_frame(n);
+        (t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + n * cos_theta).normalize()
+    }
+
+    // Density of `sample_half_vector` reflecting `v` into `l`, over solid angle
+    pub(crate) fn specular_pdf(&self, n: &Vector3<f32>, v: &Vector3<f32>, l: &Vector3<f32>) -> f32 {
+        let h = (v + l).normalize();
+        let v_dot_h = v.dot(&h);
+        if v_dot_h <= 0.0 {
+            return 0.0;
+        }
+        let n_dot_h = n.dot(&h).max(0.0);
+        self.distribution(n_dot_h) * n_dot_h / (4.0 * v_dot_h)
+    }
+
+    // GGX normal distribution
+    fn distribution(&self, n_dot_h: f32) -> f32 {
+        let a2 = self.alpha * self.alpha;
+        let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+        a2 / (f32::consts::PI * d * d)
+    }
+
+    // Separable Smith masking-shadowing for GGX
+    fn geometry(&self, n_dot_l: f32, n_dot_v: f32) -> f32 {
+        self.smith_g1(n_dot_l) * self.smith_g1(n_dot_v)
+    }
+
+    fn smith_g1(&self, n_dot_x: f32) -> f32 {
+        let a2 = self.alpha * self.alpha;
+        2.0 * n_dot_x / (n_dot_x + (a2 + (1.0 - a2) * n_dot_x * n_dot_x).sqrt())
+    }
+}
+
+fn schlick(f0: &Vector3<f32>, cos_theta: f32) -> Vector3<f32> {
+    let m = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+    f0 + (Vector3::new(1.0, 1.0, 1.0) - f0) * m
+}
+
+// Two unit vectors perpendicular to `n` and to each other
+pub(crate) fn tangent_frame(n: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let helper = if n.x.abs() > 0.9 {
+        Vector3::new(0.0, 1.0, 0.0)
+    } else {
+        Vector3::new(1.0, 0.0, 0.0)
+    };
+    let t = helper.cross(n).normalize();
+    let b = n.cross(&t);
+    (t, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn direction(theta: f32, phi: f32) -> Vector3<f32> {
+        Vector3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+    }
+
+    #[test]
+    fn eval_is_reciprocal() {
+        let microfacet = Microfacet::new(Color::new(0.8, 0.3, 0.1), 0.5, 0.4);
+        let n = Vector3::z();
+        let pairs = [((0.1, 0.0), (0.7, 2.0)), ((1.2, 1.0), (0.3, 4.0)), ((0.5, 3.0), (1.4, 0.5))];
+        for ((theta_v, phi_v), (theta_l, phi_l)) in pairs.iter() {
+            let v = direction(*theta_v, *phi_v);
+            let l = direction(*theta_l, *phi_l);
+            let there = microfacet.eval(&n, &v, &l);
+            let back = microfacet.eval(&n, &l, &v);
+            assert!((there - back).norm() < 1e-5 * there.norm(), "{:?} {:?}", there, back);
+        }
+    }
+
+    #[test]
+    fn specular_pdf_integrates_to_one() {
+        let microfacet = Microfacet::new(Color::new(1.0, 1.0, 1.0), 0.0, 0.3);
+        let n = Vector3::z();
+        let v = direction(20.0f32.to_radians(), 0.0);
+        // Midpoint rule over the hemisphere, a little is lost to half vectors that reflect
+        // below the surface
+        let (steps_theta, steps_phi) = (1000, 200);
+        let d_theta = f32::consts::FRAC_PI_2 / steps_theta as f32;
+        let d_phi = 2.0 * f32::consts::PI / steps_phi as f32;
+        let mut sum = 0.0;
+        for i in 0..steps_theta {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..steps_phi {
+                let l = direction(theta, (j as f32 + 0.5) * d_phi);
+                sum += microfacet.specular_pdf(&n, &v, &l) * theta.sin() * d_theta * d_phi;
+            }
+        }
+        assert!(sum > 0.97 && sum < 1.01, "{}", sum);
+    }
+
+    #[test]
+    fn non_metals_reflect_four_percent_head_on() {
+        let microfacet = Microfacet::new(Color::new(0.9, 0.1, 0.1), 0.0, 0.5);
+        assert_eq!(microfacet.f0(), Vector3::new(0.04, 0.04, 0.04));
+        let metal = Microfacet::new(Color::new(0.9, 0.1, 0.1), 1.0, 0.5);
+        assert_eq!(metal.f0(), Vector3::new(0.9, 0.1, 0.1));
+    }
+}
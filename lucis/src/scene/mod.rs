@@ -3,12 +3,28 @@
 // - texturing
 // - scene trees
 
+mod bvh;
 mod color;
+mod emitter;
+mod gltf;
+mod image;
 mod intersection;
 mod light;
+mod microfacet;
+mod mtl;
+mod noise;
 mod node;
+mod texture;
 
+pub(crate) use self::bvh::SceneBvh;
 pub use self::color::Color;
+pub use self::image::{TextureFilter, WrapMode};
+pub(crate) use self::emitter::Emitter;
+pub(crate) use self::gltf::load as load_gltf;
 pub use self::intersection::Intersection;
 pub use self::light::Light;
-pub use self::node::{Intersect, Material, SceneNode, Scene};
+pub(crate) use self::microfacet::{tangent_frame, Microfacet};
+pub(crate) use self::mtl::parse as parse_mtl;
+pub use self::node::{Intersect, Material, MaterialWrapper, SceneNode, SceneNodeRef, Scene};
+pub(crate) use self::texture::{MaterialTextures, Pattern, TextureKind};
+pub use self::texture::{Texture, TextureSpace};
@@ -0,0 +1,231 @@
+// Wavefront MTL files, the materials OBJ files refer to with `usemtl`
+
+use crate::scene::{Color, MaterialWrapper, Texture};
+
+// Shininess of materials that don't give Ns
+const DEFAULT_SHININESS: f32 = 10.0;
+// Index of refraction of transparent materials that don't give Ni
+const DEFAULT_IOR: f32 = 1.5;
+// How high white is above black in bump maps, in world units, before -bm scales it
+const BUMP_HEIGHT: f32 = 0.05;
+
+// The statements of one newmtl block, before it is turned into a material
+struct MtlMaterial {
+    name: String,
+    kd: Color,
+    ks: Color,
+    ke: Color,
+    shininess: f32,
+    ior: Option<f32>,
+    // From d, or 1 - Tr
+    opacity: f32,
+    transmission: Option<Color>,
+    illum: u32,
+    // The PBR extension, any of these makes a metallic/roughness material
+    roughness: Option<f32>,
+    metallic: Option<f32>,
+    diffuse_map: Option<Texture>,
+    specular_map: Option<Texture>,
+    roughness_map: Option<Texture>,
+    metallic_map: Option<Texture>,
+    bump_map: Option<(Texture, f32)>,
+    normal_map: Option<Texture>,
+}
+
+impl MtlMaterial {
+    fn new(name: String) -> MtlMaterial {
+        MtlMaterial {
+            name,
+            kd: Color::new(0.8, 0.8, 0.8),
+            ks: Color::new(0.0, 0.0, 0.0),
+            ke: Color::new(0.0, 0.0, 0.0),
+            shininess: DEFAULT_SHININESS,
+            ior: None,
+            opacity: 1.0,
+            transmission: None,
+            illum: 2,
+            roughness: None,
+            metallic: None,
+            diffuse_map: None,
+            specular_map: None,
+            roughness_map: None,
+            metallic_map: None,
+            bump_map: None,
+            normal_map: None,
+        }
+    }
+
+    fn build(self) -> (String, MaterialWrapper) {
+        // illum 4, 6, 7 and 9 are the glass models
+        let transparent = self.opacity < 1.0 || [4, 6, 7, 9].contains(&self.illum);
+        let mut material = if transparent {
+            // The transmission filter is the color let through, what is left is absorbed
+            let absorption = self.transmission.map_or(Color::new(0.0, 0.0, 0.0), |tf| {
+                Color::new(1.0 - tf.r, 1.0 - tf.g, 1.0 - tf.b)
+            });
+            MaterialWrapper::dielectric(self.ior.unwrap_or(DEFAULT_IOR), &absorption)
+        } else if self.roughness.is_some() || self.metallic.is_some() {
+            MaterialWrapper::pbr(&self.kd, self.metallic.unwrap_or(0.0), self.roughness.unwrap_or(0.5))
+        } else {
+            let mut material = MaterialWrapper::new(&self.kd, &self.ks, self.shininess);
+            // illum 3 and 5 turn on ray traced reflections
+            if self.illum == 3 || self.illum == 5 {
+                material.set_reflectivity(&self.ks);
+            }
+            material
+        };
+        material.set_emitted_radiance(&self.ke);
+        if let Some(t) = &self.diffuse_map {
+            material.set_diffuse_texture(t);
+        }
+        if let Some(t) = &self.specular_map {
+            material.set_specular_texture(t);
+        }
+        if let Some(t) = &self.roughness_map {
+            material.set_roughness_texture(t);
+        }
+        if let Some(t) = &self.metallic_map {
+            material.set_metallic_texture(t);
+        }
+        if let Some((t, strength)) = &self.bump_map {
+            material.set_bump_texture(t, *strength);
+        }
+        if let Some(t) = &self.normal_map {
+            material.set_normal_texture(t);
+        }
+        (self.name, material)
+    }
+}
+
+// Read the materials of an MTL file by name. Texture maps are handed to `load_texture` by
+// file name, which can give back None to leave them out
+pub(crate) fn parse<F>(text: &str, load_texture: F) -> Result<Vec<(String, MaterialWrapper)>, String>
+where
+    F: Fn(&str) -> Result<Option<Texture>, String>,
+{
+    let mut materials = vec![];
+    let mut current: Option<MtlMaterial> = None;
+
+    for (n, line) in text.lines().enumerate() {
+        let error = |e: String| format!("line {}: {}", n + 1, e);
+        let line = line.split('#').next().unwrap();
+        let mut parts = line.split_whitespace();
+        let first = match parts.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        let rest: Vec<&str> = parts.collect();
+        if first == "newmtl" {
+            if rest.is_empty() {
+                return Err(error("newmtl needs a material name".to_string()));
+            }
+            if let Some(m) = current.take() {
+                materials.push(m.build());
+            }
+            current = Some(MtlMaterial::new(rest.join(" ")));
+            continue;
+        }
+        let m = match current.as_mut() {
+            Some(m) => m,
+            None => return Err(error(format!("'{}' comes before any newmtl", first))),
+        };
+        match first {
+            "Kd" => m.kd = color(&rest).map_err(error)?,
+            "Ks" => m.ks = color(&rest).map_err(error)?,
+            "Ke" => m.ke = color(&rest).map_err(error)?,
+            "Tf" => m.transmission = Some(color(&rest).map_err(error)?),
+            "Ns" => m.shininess = number(&rest).map_err(error)?,
+            "Ni" => m.ior = Some(number(&rest).map_err(error)?),
+            "d" => m.opacity = number(&rest).map_err(error)?,
+            "Tr" => m.opacity = 1.0 - number(&rest).map_err(error)?,
+            "illum" => m.illum = number(&rest).map_err(error)? as u32,
+            "Pr" => m.roughness = Some(number(&rest).map_err(error)?),
+            "Pm" => m.metallic = Some(number(&rest).map_err(error)?),
+            "map_Kd" => m.diffuse_map = texture(&rest, &load_texture).map_err(error)?.map(|(t, _)| t),
+            "map_Ks" => m.specular_map = texture(&rest, &load_texture).map_err(error)?.map(|(t, _)| t),
+            "map_Pr" => m.roughness_map = texture(&rest, &load_texture).map_err(error)?.map(|(t, _)| t),
+            "map_Pm" => m.metallic_map = texture(&rest, &load_texture).map_err(error)?.map(|(t, _)| t),
+            "bump" | "map_Bump" | "map_bump" => m.bump_map = texture(&rest, &load_texture).map_err(error)?,
+            "norm" | "map_Kn" => m.normal_map = texture(&rest, &load_texture).map_err(error)?.map(|(t, _)| t),
+            // Ambient colors and maps lucis has no use for
+            _ => {}
+        }
+    }
+    if let Some(m) = current.take() {
+        materials.push(m.build());
+    }
+    Ok(materials)
+}
+
+fn number(parts: &[&str]) -> Result<f32, String> {
+    let part = parts.first().ok_or("missing a value")?;
+    part.parse().map_err(|_| format!("expected a number, got '{}'", part))
+}
+
+// A color is given as r g b, or a single value for gray
+fn color(parts: &[&str]) -> Result<Color, String> {
+    if parts.first() == Some(&"spectral") || parts.first() == Some(&"xyz") {
+        return Err(format!("{} colors aren't supported", parts[0]));
+    }
+    let r = number(parts)?;
+    if parts.len() < 3 {
+        return Ok(Color::new(r, r, r));
+    }
+    Ok(Color::new(r, number(&parts[1..])?, number(&parts[2..])?))
+}
+
+// A texture map, options come before the file name. Only -bm, the strength of bump maps, is
+// used. Gives the texture and the bump strength
+fn texture<F>(parts: &[&str], load_texture: &F) -> Result<Option<(Texture, f32)>, String>
+where
+    F: Fn(&str) -> Result<Option<Texture>, String>,
+{
+    let mut strength = BUMP_HEIGHT;
+    let mut i = 0;
+    while i < parts.len() && parts[i].starts_with('-') {
+        let option = parts[i];
+        // The number of values each option takes
+        let values = match option {
+            "-blendu" | "-blendv" | "-cc" | "-clamp" | "-imfchan" | "-texres" | "-bm" | "-boost" => 1,
+            "-mm" => 2,
+            "-o" | "-s" | "-t" => 3,
+            _ => return Err(format!("unknown texture option '{}'", option)),
+        };
+        if option == "-bm" {
+            strength = BUMP_HEIGHT * number(&parts[i + 1..])?;
+        }
+        // -o, -s and -t can leave out their last values
+        i += 1;
+        let mut taken = 0;
+        while taken < values && i < parts.len() && parts[i].parse::<f32>().is_ok() {
+            i += 1;
+            taken += 1;
+        }
+        if values == 1 && taken == 0 && i < parts.len() {
+            // Values like "on" and "off"
+            i += 1;
+        }
+    }
+    let file = parts[i..].join(" ");
+    if file.is_empty() {
+        return Err("missing the texture's file name".to_string());
+    }
+    Ok(load_texture(&file)?.map(|t| (t, strength)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ke_is_the_radiance_leaving_the_surface() {
+        let materials = parse("newmtl lamp\nKe 1 0.5 0\n", |_| Ok(None)).unwrap();
+        let (name, lamp) = &materials[0];
+        assert_eq!(name, "lamp");
+        let pi = std::f32::consts::PI;
+        let radiance = [lamp.emission.r * pi, lamp.emission.g * pi, lamp.emission.b * pi];
+        for (got, expected) in radiance.iter().zip([1.0, 0.5, 0.0].iter()) {
+            assert!((got - expected).abs() < 1e-6, "{:?}", radiance);
+        }
+    }
+}
@@ -1,15 +1,18 @@
-use crate::geometry::{Primitive, Ray};
-use crate::scene::{Color, Intersection};
+use crate::description::SceneDescription;
+use crate::geometry::{Mesh, Primitive, PrimitiveKind, Ray};
+use crate::scene::{load_gltf, parse_mtl, Color, Emitter, Intersection, Light, MaterialTextures, Microfacet, Texture};
 use crate::Raytracer;
-use nalgebra::{clamp, distance_squared, Affine3, Matrix4, Vector3};
-use std::cell::{Ref, RefCell};
-use std::rc::Rc;
+use nalgebra::{clamp, distance_squared, Affine3, Matrix4, Point3, Vector3};
+use std::sync::{Arc, RwLock};
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen(js_name = Material)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct MaterialWrapper {
     pub(crate) base: Material,
+    // Light given off by the surface, on top of whatever it reflects
+    pub(crate) emission: Color,
+    pub(crate) textures: MaterialTextures,
 }
 
 #[wasm_bindgen(js_class = Material)]
@@ -17,11 +20,103 @@ impl MaterialWrapper {
     #[wasm_bindgen(constructor)]
     pub fn new(kd: &Color, ks: &Color, shininess: f32) -> MaterialWrapper {
         MaterialWrapper {
-            base: Material::PhongMaterial {
-                kd: *kd,
-                ks: *ks,
-                shininess: shininess,
-            }
+            base: Material::phong(*kd, *ks, shininess),
+            emission: Color::new(0.0, 0.0, 0.0),
+            textures: MaterialTextures::default(),
+        }
+    }
+
+    // A transparent material like glass or water. `ior` is the index of refraction and
+    // `absorption` how much of each channel is absorbed per unit of distance travelled
+    // inside, which tints thick parts more than thin ones
+    pub fn dielectric(ior: f32, absorption: &Color) -> MaterialWrapper {
+        MaterialWrapper {
+            base: Material::Dielectric {
+                ior,
+                absorption: *absorption,
+            },
+            emission: Color::new(0.0, 0.0, 0.0),
+            textures: MaterialTextures::default(),
+        }
+    }
+
+    // A physically based material using the metallic/roughness parameters of most other
+    // tools. `metallic` and `roughness` go from 0 to 1
+    pub fn pbr(base_color: &Color, metallic: f32, roughness: f32) -> MaterialWrapper {
+        MaterialWrapper {
+            base: Material::Pbr {
+                base_color: *base_color,
+                metallic,
+                roughness,
+            },
+            emission: Color::new(0.0, 0.0, 0.0),
+            textures: MaterialTextures::default(),
+        }
+    }
+
+    // Make the surface glow. Nodes with an emissive material light the scene like an area
+    // light, with soft shadows that follow their shape. Like the colors of lights, emission
+    // leaves out the 1 / pi of diffuse surfaces, so it glows pi times as bright as given
+    pub fn set_emission(&mut self, emission: &Color) {
+        self.emission = *emission;
+    }
+
+    // Make the surface glow with this much radiance, which is how model files give emission
+    pub(crate) fn set_emitted_radiance(&mut self, radiance: &Color) {
+        let pi = std::f32::consts::PI;
+        self.emission = Color::new(radiance.r / pi, radiance.g / pi, radiance.b / pi);
+    }
+
+    // Take the diffuse color from a texture, kd for Phong materials and the base color for
+    // PBR ones
+    pub fn set_diffuse_texture(&mut self, texture: &Texture) {
+        self.textures.diffuse = Some(texture.clone());
+    }
+
+    // Take ks of a Phong material from a texture
+    pub fn set_specular_texture(&mut self, texture: &Texture) {
+        self.textures.specular = Some(texture.clone());
+    }
+
+    // Take the reflectivity of a Phong material from a texture
+    pub fn set_reflectivity_texture(&mut self, texture: &Texture) {
+        self.textures.reflectivity = Some(texture.clone());
+    }
+
+    // Take the metallic value of a PBR material from the brightness of a texture
+    pub fn set_metallic_texture(&mut self, texture: &Texture) {
+        self.textures.metallic = Some(texture.clone());
+    }
+
+    // Take the roughness of a PBR material from the brightness of a texture
+    pub fn set_roughness_texture(&mut self, texture: &Texture) {
+        self.textures.roughness = Some(texture.clone());
+    }
+
+    // Make the surface look raised where the texture is bright, `strength` is how high white
+    // is above black in world units. The shape itself doesn't change, only its shading
+    pub fn set_bump_texture(&mut self, texture: &Texture, strength: f32) {
+        self.textures.bump = Some((texture.clone(), strength));
+    }
+
+    // Bend the normals with a tangent space normal map, the usual blueish kind where red
+    // follows the texture's u direction and green its v direction
+    pub fn set_normal_texture(&mut self, texture: &Texture) {
+        self.textures.normal = Some(texture.clone());
+    }
+
+    // Multiply the emission by a texture, so the surface only glows where the texture is
+    // bright. Without an emission of its own nothing glows
+    pub fn set_emission_texture(&mut self, texture: &Texture) {
+        self.textures.emission = Some(texture.clone());
+    }
+
+    // How much of the scene the surface mirrors, per channel. Black, the default, turns
+    // reflections off. Dielectric and PBR materials ignore this, their reflections follow
+    // from the Fresnel equations
+    pub fn set_reflectivity(&mut self, reflectivity: &Color) {
+        if let Material::PhongMaterial { reflectivity: r, .. } = &mut self.base {
+            *r = *reflectivity;
         }
     }
 }
@@ -32,6 +127,18 @@ pub enum Material {
         kd: Color,
         ks: Color,
         shininess: f32,
+        reflectivity: Color,
+    },
+    // Cook-Torrance with the GGX distribution, Smith geometry term and Schlick's Fresnel
+    Pbr {
+        base_color: Color,
+        metallic: f32,
+        roughness: f32,
+    },
+    // Reflects and refracts, lit entirely by the rays the Raytracer follows from it
+    Dielectric {
+        ior: f32,
+        absorption: Color,
     },
     None,
 }
@@ -40,31 +147,22 @@ fn calculate_phong_lighting(
     kd: &Color,
     ks: &Color,
     shininess: f32,
-    _ray: &Ray,
+    ray: &Ray,
     raytracer: &Raytracer,
     intersect: &Intersection,
 ) -> Color {
     let intersect_point = intersect.point;
     let n = intersect.normal.normalize();
-    let v = (raytracer.eye - intersect_point).normalize();
+    let v = -ray.dir.normalize();
 
     let mut final_color = *kd * raytracer.ambient;
 
     for light in raytracer.lights.iter() {
-        let total_shadow_rays = light.num_samples;
-        let mut shadow_rays_hit = 0;
-        for p in light.light_samples.iter() {
-            let shadow_ray = Ray::new_from_points(intersect_point, *p);
-            if raytracer.scene.intersects(&shadow_ray).is_none() {
-                shadow_rays_hit += 1;
-            }
-        }
-        if shadow_rays_hit == 0 {
+        let shadow_multiplier = light_visibility(light, &intersect_point, raytracer);
+        if shadow_multiplier == 0.0 {
             continue;
         }
 
-        let shadow_multiplier = shadow_rays_hit as f32 / total_shadow_rays as f32;
-
         let mut l = light.position - intersect_point;
         let l_norm = l.norm();
         l = l.normalize();
@@ -81,24 +179,135 @@ fn calculate_phong_lighting(
     final_color
 }
 
+// Fraction of the light's shadow rays that reach it from `point`
+fn light_visibility(light: &Light, point: &Point3<f32>, raytracer: &Raytracer) -> f32 {
+    // The grid of samples can hold fewer points than were asked for
+    let total_shadow_rays = light.light_samples.len().max(1);
+    let mut shadow_rays_hit = 0;
+    for p in light.light_samples.iter() {
+        let shadow_ray = Ray::new_from_points(*point, *p);
+        if raytracer.intersects(&shadow_ray).is_none() {
+            shadow_rays_hit += 1;
+        }
+    }
+    shadow_rays_hit as f32 / total_shadow_rays as f32
+}
+
+fn calculate_pbr_lighting(
+    microfacet: &Microfacet,
+    ray: &Ray,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+) -> Color {
+    let v = -ray.dir.normalize();
+    let mut n = intersect.normal.normalize();
+    if n.dot(&v) < 0.0 {
+        n = -n;
+    }
+
+    // Metals have no diffuse part for the ambient light to reach
+    let ambient: Vector3<f32> = raytracer.ambient.into();
+    let mut final_color = microfacet.base_color.component_mul(&ambient) * (1.0 - microfacet.metallic);
+
+    for light in raytracer.lights.iter() {
+        let shadow_multiplier = light_visibility(light, &intersect.point, raytracer);
+        if shadow_multiplier == 0.0 {
+            continue;
+        }
+
+        let l = light.position - intersect.point;
+        let l_norm = l.norm();
+        let l = l / l_norm;
+        let ldotn = l.dot(&n);
+        if ldotn <= 0.0 {
+            continue;
+        }
+
+        let attenuation =
+            light.falloff[0] + (light.falloff[1] * l_norm) + (light.falloff[2] * l_norm * l_norm);
+        // Light colors leave out the 1 / pi of a Lambert surface, like the Phong lighting
+        let f = microfacet.eval(&n, &v, &l) * std::f32::consts::PI;
+        let light_color: Vector3<f32> = light.color.into();
+        final_color += f.component_mul(&light_color) * (ldotn * shadow_multiplier / attenuation);
+    }
+
+    Color::from(final_color).clamp()
+}
+
 impl Material {
     pub fn phong(kd: Color, ks: Color, shininess: f32) -> Material {
-        Material::PhongMaterial { kd, ks, shininess }
+        Material::PhongMaterial {
+            kd,
+            ks,
+            shininess,
+            reflectivity: Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // The BRDF for light arriving from `wi` and leaving towards `wo`, with `n` on their side.
+    // None for materials that only scatter in perfectly specular directions
+    pub(crate) fn brdf(&self, n: &Vector3<f32>, wo: &Vector3<f32>, wi: &Vector3<f32>) -> Option<Vector3<f32>> {
+        match self {
+            // Normalized Phong, a Lambert lobe plus a glossy lobe around the mirror direction
+            Material::PhongMaterial { kd, ks, shininess, .. } => {
+                let r = 2.0 * wi.dot(n) * n - wi;
+                let cos_alpha = r.dot(wo).max(0.0);
+                let glossy = (shininess + 2.0) / (2.0 * std::f32::consts::PI) * cos_alpha.powf(*shininess);
+                Some(Vector3::from(*kd) / std::f32::consts::PI + Vector3::from(*ks) * glossy)
+            }
+            Material::Pbr {
+                base_color,
+                metallic,
+                roughness,
+            } => Some(Microfacet::new(*base_color, *metallic, *roughness).eval(n, wo, wi)),
+            Material::Dielectric { .. } | Material::None => None,
+        }
+    }
+
+    // Fraction of the light arriving from the mirror direction that is reflected
+    pub fn reflectivity(&self) -> Color {
+        match self {
+            Material::PhongMaterial { reflectivity, .. } => *reflectivity,
+            // Without the path tracer there is no glossy reflection, so smooth surfaces
+            // mirror the scene instead, fading out as they get rougher
+            Material::Pbr {
+                base_color,
+                metallic,
+                roughness,
+            } => {
+                let f0 = Microfacet::new(*base_color, *metallic, *roughness).f0();
+                let smoothness = (1.0 - roughness.clamp(0.0, 1.0)).powi(2);
+                Color::from(f0 * smoothness)
+            }
+            Material::Dielectric { .. } | Material::None => Color::new(0.0, 0.0, 0.0),
+        }
     }
 
     pub fn get_color(&self, ray: &Ray, raytracer: &Raytracer, intersect: &Intersection) -> Color {
         match self {
-            Material::PhongMaterial { kd, ks, shininess } => {
+            Material::PhongMaterial { kd, ks, shininess, .. } => {
                 calculate_phong_lighting(kd, ks, *shininess, ray, raytracer, intersect)
             }
-            Material::None => Color::new(0.0, 0.0, 0.0),
+            Material::Pbr {
+                base_color,
+                metallic,
+                roughness,
+            } => calculate_pbr_lighting(
+                &Microfacet::new(*base_color, *metallic, *roughness),
+                ray,
+                raytracer,
+                intersect,
+            ),
+            Material::Dielectric { .. } | Material::None => Color::new(0.0, 0.0, 0.0),
         }
     }
 }
 
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct Scene {
-    pub(crate) nodes: Rc<RefCell<Vec<SceneNode>>>,
+    // Shared with every SceneNodeRef, and safe to read from several render threads at once
+    pub(crate) nodes: Arc<RwLock<Vec<SceneNode>>>,
     // Index in the nodes vec of the root node
     pub(crate) root_node: usize,
 }
@@ -106,25 +315,40 @@ pub struct Scene {
 #[wasm_bindgen]
 pub struct SceneNodeRef {
     id: usize,
-    parent: Rc<RefCell<Vec<SceneNode>>>,
+    parent: Arc<RwLock<Vec<SceneNode>>>,
 }
 
 #[wasm_bindgen]
 impl SceneNodeRef {
     pub fn add_child(&mut self, child: &SceneNodeRef) {
-        self.parent.borrow_mut()[self.id].add_child_id(child.id);
+        self.parent.write().unwrap()[self.id].add_child_id(child.id);
     }
     pub fn scale(&mut self, x: f32, y: f32, z: f32) {
-        self.parent.borrow_mut()[self.id].scale(x, y, z);
+        self.parent.write().unwrap()[self.id].scale(x, y, z);
     }
     pub fn translate(&mut self, x: f32, y: f32, z: f32) {
-        self.parent.borrow_mut()[self.id].translate(x, y, z);
+        self.parent.write().unwrap()[self.id].translate(x, y, z);
     }
     pub fn rotate(&mut self, axis: &str, angle: f32) {
-        self.parent.borrow_mut()[self.id].rotate(axis, angle);
+        self.parent.write().unwrap()[self.id].rotate(axis, angle);
     }
     pub fn set_material(&mut self, material: &MaterialWrapper) {
-        self.parent.borrow_mut()[self.id].material = material.base;
+        let mut nodes = self.parent.write().unwrap();
+        nodes[self.id].material = material.base;
+        nodes[self.id].emission = material.emission;
+        nodes[self.id].textures = material.textures.clone();
+    }
+}
+
+impl SceneNodeRef {
+    pub(crate) fn apply_matrix(&mut self, t: Matrix4<f32>) {
+        self.parent.write().unwrap()[self.id].apply_transform(t);
+    }
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Scene::new()
     }
 }
 
@@ -133,29 +357,160 @@ impl Scene {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Scene {
         Scene {
-            nodes: Rc::new(RefCell::new(Vec::new())),
+            nodes: Arc::new(RwLock::new(Vec::new())),
             root_node: 0,
         }
     }
 
-    pub fn create_node(&mut self, primitive: Primitive, name: String) -> SceneNodeRef {
-        let id = self.nodes.borrow().len();
+    pub fn create_node(&mut self, primitive: PrimitiveKind, name: String) -> SceneNodeRef {
+        self.add_node(primitive.into(), name)
+    }
+
+    // A node drawn as a triangle mesh, the mesh is copied so it can be freed afterwards
+    pub fn create_mesh_node(&mut self, mesh: &Mesh, name: String) -> SceneNodeRef {
+        self.add_node(Primitive::Mesh(Arc::new(mesh.clone())), name)
+    }
+
+    // A node for an OBJ file along with the materials in the text of its MTL file. Each group
+    // and material in the file becomes a child node, texture maps in the MTL file are left
+    // out since their files can't be read here
+    pub fn create_obj_node(&mut self, obj: &str, mtl: Option<String>, name: String) -> Result<SceneNodeRef, JsValue> {
+        let mesh = Mesh::from_obj_str(obj).map_err(|e| JsValue::from_str(&format!("OBJ {}", e)))?;
+        let materials = match mtl {
+            Some(mtl) => parse_mtl(&mtl, |_| Ok(None)).map_err(|e| JsValue::from_str(&format!("MTL {}", e)))?,
+            None => vec![],
+        };
+        Ok(self.add_mesh_nodes(mesh, &materials, None, name))
+    }
+
+    // A node for a glTF model from the bytes of a .glb file, or a .gltf file with its buffers
+    // and images embedded. The nodes of the model are added under it with their transforms
+    pub fn create_gltf_node(&mut self, bytes: &[u8], name: String) -> Result<SceneNodeRef, JsValue> {
+        let resolve = |uri: &str| {
+            Err(format!(
+                "can't read '{}' in the browser, use a .glb file or embed it in the .gltf file",
+                uri
+            ))
+        };
+        load_gltf(self, bytes, resolve, name).map_err(|e| JsValue::from_str(&format!("glTF {}", e)))
+    }
+
+    // Load a scene graph from the JSON scene format. Only the root node and its descendants
+    // are read, the camera, lights and render settings are ignored
+    pub fn from_json(json: &str) -> Result<Scene, JsValue> {
+        SceneDescription::from_json(json)
+            .and_then(|description| description.build_scene())
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Save the nodes reachable from the root, with their transforms, primitives and materials
+    pub fn to_json(&self) -> String {
+        SceneDescription::from_scene(self).to_json()
+    }
+
+    pub(crate) fn add_node(&mut self, primitive: Primitive, name: String) -> SceneNodeRef {
+        let id = self.nodes.read().unwrap().len();
         let mut node = SceneNode::new(id, name);
         node.primitive = primitive;
         // rt.material({0.9, 0.8, 0.4}, {0.8, 0.8, 0.4}, 25)
         node.material = Material::phong(Color::new(0.96, 0.37, 0.1), Color::new(0.7, 0.7, 0.7), 6.0);
-        self.nodes.borrow_mut().push(node);
-        return SceneNodeRef {
-            id: id,
-            parent: Rc::clone(&self.nodes),
+        self.nodes.write().unwrap().push(node);
+        SceneNodeRef {
+            id,
+            parent: Arc::clone(&self.nodes),
+        }
+    }
+
+    // Add a mesh, split into a child node for each of its groups and materials when there is
+    // more than one. Faces without a material, or with one that isn't in `materials`, get
+    // `fallback` or else the default material
+    pub(crate) fn add_mesh_nodes(
+        &mut self,
+        mesh: Mesh,
+        materials: &[(String, MaterialWrapper)],
+        fallback: Option<&MaterialWrapper>,
+        name: String,
+    ) -> SceneNodeRef {
+        let material_for = |material: &Option<String>| {
+            material
+                .as_ref()
+                .and_then(|m| materials.iter().find(|(name, _)| name == m))
+                .map(|(_, m)| m)
+                .or(fallback)
         };
+        let mut parts = mesh.parts();
+        if parts.len() <= 1 {
+            let material = parts.pop().and_then(|p| material_for(&p.material).cloned());
+            let mut node = self.add_node(Primitive::Mesh(Arc::new(mesh)), name);
+            if let Some(material) = material {
+                node.set_material(&material);
+            }
+            return node;
+        }
+
+        let mut root = self.add_node(Primitive::None, name);
+        for part in parts {
+            let part_name = match &part.material {
+                Some(material) => format!("{} ({})", part.group, material),
+                None => part.group.clone(),
+            };
+            let material = material_for(&part.material).cloned();
+            let mut node = self.add_node(Primitive::Mesh(Arc::new(part.mesh)), part_name);
+            if let Some(material) = material {
+                node.set_material(&material);
+            }
+            root.add_child(&node);
+        }
+        root
+    }
+
+    // Copy of the material at a hit with its textures looked up, so the scene lock isn't
+    // held while shading with it. Also gives back the hit with its normal bent by any bump or
+    // normal map
+    pub(crate) fn surface_at(&self, hit: &Intersection) -> (Material, Intersection) {
+        let nodes = self.nodes.read().unwrap();
+        let node = &nodes[hit.node_id];
+        let mut hit = *hit;
+        hit.normal = node.textures.shading_normal(&hit);
+        (node.textures.apply(node.material, &hit), hit)
+    }
+
+    // Radiance leaving the surface at a hit
+    pub(crate) fn emission(&self, hit: &Intersection) -> Color {
+        let nodes = self.nodes.read().unwrap();
+        let node = &nodes[hit.node_id];
+        node.textures.emission(node.radiance(), hit)
+    }
+
+    // Every node that glows, with its transform into world space as it is right now
+    pub(crate) fn emitters(&self) -> Vec<Emitter> {
+        let mut emitters = vec![];
+        let nodes = self.nodes.read().unwrap();
+        if !nodes.is_empty() {
+            Scene::collect_emitters(&nodes, self.root_node, &Affine3::identity(), &mut emitters);
+        }
+        emitters
+    }
+
+    fn collect_emitters(nodes: &[SceneNode], current_node: usize, parent: &Affine3<f32>, emitters: &mut Vec<Emitter>) {
+        let n = &nodes[current_node];
+        let transform = parent * n.transform;
+        let e = n.emission;
+        if e.r > 0.0 || e.g > 0.0 || e.b > 0.0 {
+            if let Some(emitter) = Emitter::new(n.id, n.primitive.clone(), transform, n.radiance()) {
+                emitters.push(emitter);
+            }
+        }
+        for child in n.children.iter() {
+            Scene::collect_emitters(nodes, *child, &transform, emitters);
+        }
     }
 
     pub(crate) fn intersects(&self, ray: &Ray) -> Option<Intersection> {
-        Scene::intersects_recursive(self.nodes.borrow(), self.root_node, ray)
+        Scene::intersects_recursive(&self.nodes.read().unwrap(), self.root_node, ray)
     }
 
-    fn intersects_recursive(nodes: Ref<Vec<SceneNode>>, current_node: usize, ray: &Ray) -> Option<Intersection> {
+    fn intersects_recursive(nodes: &[SceneNode], current_node: usize, ray: &Ray) -> Option<Intersection> {
         let n = &nodes[current_node];
         let transformed_ray = n.inv_transform * *ray;
 
@@ -164,9 +519,7 @@ impl Scene {
         let min = n
             .children
             .iter()
-            .map(|c_id| Scene::intersects_recursive(Ref::clone(&nodes), *c_id, &transformed_ray))
-            .filter(|child| child.is_some())
-            .map(|child| child.unwrap())
+            .filter_map(|c_id| Scene::intersects_recursive(nodes, *c_id, &transformed_ray))
             .fold(None, |min, child| match min {
                 None => Some(child),
                 Some(cmin) => Some(
@@ -208,6 +561,8 @@ pub struct SceneNode {
 
     // Material and Primitive
     pub material: Material,
+    pub emission: Color,
+    pub(crate) textures: MaterialTextures,
     pub primitive: Primitive,
 }
 
@@ -220,9 +575,19 @@ impl SceneNode {
             inv_transform: Affine3::identity(),
             name,
             material: Material::None,
+            emission: Color::new(0.0, 0.0, 0.0),
+            textures: MaterialTextures::default(),
             primitive: Primitive::None,
         }
     }
+
+    // Radiance leaving the surface. Emission is scaled by pi here like lights are, and only
+    // here, so an emitter lights a surface like a light of the same color and looks as
+    // bright seen directly or in a mirror as the light it gives off
+    pub(crate) fn radiance(&self) -> Color {
+        let pi = std::f32::consts::PI;
+        Color::new(self.emission.r * pi, self.emission.g * pi, self.emission.b * pi)
+    }
 }
 
 impl Intersect for SceneNode {
@@ -230,18 +595,26 @@ impl Intersect for SceneNode {
         let mut t_value: f32 = 0.0;
         let mut normal = Vector3::new(0.0f32, 0.0, 0.0);
         let mut uv = [0.0, 0.0];
+        let mut tangents = None;
         if self
             .primitive
-            .collides(&ray, &mut t_value, &mut normal, &mut uv)
+            .collides(ray, &mut t_value, &mut normal, &mut uv, &mut tangents)
         {
-            Some(Intersection::new(
-                t_value,
-                ray.src + (t_value * ray.dir.normalize()),
-                self.id,
-                normal,
-                uv[0],
-                uv[1],
-            ))
+            let point = ray.src + (t_value * ray.dir.normalize());
+            let (tangent, bitangent) = tangents.unwrap_or_else(|| self.primitive.tangents(&point, &normal));
+            Some(
+                Intersection::new(
+                    t_value,
+                    point,
+                    self.id,
+                    normal,
+                    uv[0],
+                    uv[1],
+                    // Primitives give the outward normal, which faces the ray on the way in
+                    ray.dir.dot(&normal) < 0.0,
+                )
+                .with_tangents(tangent, bitangent),
+            )
         } else {
             None
         }
@@ -256,21 +629,12 @@ impl SceneNode {
         self.children.push(child);
     }
     pub fn scale(&mut self, x: f32, y: f32, z: f32) {
-        println!("Applying scaling to {} of ({}, {}, {})", self.name, x, y, z);
         self.apply_transform(Matrix4::new_nonuniform_scaling(&Vector3::new(x, y, z)));
     }
     pub fn translate(&mut self, x: f32, y: f32, z: f32) {
-        println!(
-            "Applying translation to {} of ({}, {}, {})",
-            self.name, x, y, z
-        );
         self.apply_transform(Matrix4::new_translation(&Vector3::new(x, y, z)));
     }
     pub fn rotate(&mut self, axis: &str, angle: f32) {
-        println!(
-            "Applying rotation to {} of ({}, {})",
-            self.name, axis, angle
-        );
         let axis = match axis {
             "x" | "X" => Vector3::x_axis(),
             "y" | "Y" => Vector3::y_axis(),
@@ -282,7 +646,7 @@ impl SceneNode {
         };
         self.apply_transform(Matrix4::from_axis_angle(&axis, angle.to_radians()));
     }
-    fn apply_transform(&mut self, t: Matrix4<f32>) {
+    pub(crate) fn apply_transform(&mut self, t: Matrix4<f32>) {
         let ta: Affine3<f32> = Affine3::from_matrix_unchecked(t);
         self.transform = ta * self.transform;
         self.inv_transform = self.transform.inverse();
@@ -0,0 +1,94 @@
+// Gradient noise for procedural textures
+
+use nalgebra::{Point3, Vector3};
+
+// Perlin's improved noise, smooth and random looking with features about one unit apart.
+// Mostly in [-1, 1], and 0 at every integer point
+pub(crate) fn perlin(p: &Point3<f32>) -> f32 {
+    let cell = Point3::new(p.x.floor(), p.y.floor(), p.z.floor());
+    let f = p - cell;
+    let (x, y, z) = (cell.x as i32, cell.y as i32, cell.z as i32);
+    let (u, v, w) = (fade(f.x), fade(f.y), fade(f.z));
+
+    // Blend the gradients of the 8 corners of the cell
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let offset = Vector3::new(f.x - dx as f32, f.y - dy as f32, f.z - dz as f32);
+        gradient(hash(x + dx, y + dy, z + dz), &offset)
+    };
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+// Fractional Brownian motion, octaves of noise at doubling frequencies and halving amplitudes.
+// In [-1, 1] like the noise itself
+pub(crate) fn fbm(p: &Point3<f32>, octaves: u32) -> f32 {
+    octave_sum(p, octaves, perlin)
+}
+
+// Like fBm but folding each octave, which gives sharp creases. In [0, 1]
+pub(crate) fn turbulence(p: &Point3<f32>, octaves: u32) -> f32 {
+    octave_sum(p, octaves, |p| perlin(p).abs())
+}
+
+fn octave_sum<F: Fn(&Point3<f32>) -> f32>(p: &Point3<f32>, octaves: u32, noise: F) -> f32 {
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves.max(1) {
+        sum += amplitude * noise(&Point3::from(p.coords * frequency));
+        total_amplitude += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total_amplitude
+}
+
+// 6t^5 - 15t^4 + 10t^3, flat at both ends so the cells join smoothly
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// Scramble the corner of a cell, instead of looking it up in a permutation table
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^ (h >> 15)
+}
+
+// Dot product of `offset` with one of the 12 directions to the edges of a cube
+fn gradient(hash: u32, offset: &Vector3<f32>) -> f32 {
+    let (x, y, z) = (offset.x, offset.y, offset.z);
+    match hash % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
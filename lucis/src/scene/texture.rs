@@ -0,0 +1,424 @@
+use crate::scene::image::{Image, TextureFilter, WrapMode};
+use crate::scene::noise::{fbm, perlin, turbulence};
+use crate::scene::{Color, Intersection, Material};
+use nalgebra::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::f32;
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+use web_sys::ImageData;
+
+// How far turbulence pushes the veins of marble
+const MARBLE_DISTORTION: f32 = 4.0;
+const MARBLE_OCTAVES: u32 = 6;
+// How much noise bends the rings of wood
+const WOOD_GRAIN: f32 = 0.3;
+const WOOD_OCTAVES: u32 = 4;
+// Step in texture coordinates used to measure the slope of bump maps
+const BUMP_DELTA: f32 = 0.0005;
+
+// A color that varies over a surface. Coordinates go from (0, 0) at the bottom left of the
+// texture to (1, 1) at the top right, procedural textures can also follow the point on the
+// object in 3D. Textures can be scaled, moved and mixed together
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct Texture {
+    pub(crate) kind: TextureKind,
+    // Applied to the coordinates before looking up this texture and the ones it mixes
+    pub(crate) scale: Vector3<f32>,
+    pub(crate) offset: Vector3<f32>,
+}
+
+// The coordinates procedural textures are evaluated at
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureSpace {
+    // The texture coordinates of the surface, wrapped around it like an image
+    Uv,
+    // The hit point in the space of the node, like carving the object out of a solid block
+    Object,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum TextureKind {
+    Solid(Color),
+    Image {
+        // Shared, cloning a material shouldn't copy its pixels
+        image: Arc<Image>,
+        wrap: WrapMode,
+        filter: TextureFilter,
+    },
+    // Goes between two colors by the value of a pattern, 0 is all `a` and 1 all `b`
+    Pattern {
+        pattern: Pattern,
+        space: TextureSpace,
+        a: Color,
+        b: Color,
+    },
+    // Mixes two textures by the brightness of a third
+    Mix {
+        a: Arc<Texture>,
+        b: Arc<Texture>,
+        mask: Arc<Texture>,
+    },
+}
+
+// Textures computed from the coordinates instead of stored, each gives a value in [0, 1]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Pattern {
+    // Alternating unit cubes
+    Checker,
+    // Alternating bands one unit wide, across x
+    Stripes,
+    // From 0 to 1 as x goes from 0 to 1
+    Gradient,
+    Noise,
+    Fbm(u32),
+    Turbulence(u32),
+    // Bands across x, bent by turbulence
+    Marble,
+    // Rings around the y axis, bent by noise
+    Wood,
+}
+
+// Where a texture is looked up, both are moved by the scale and offset of every texture on
+// the way down
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TextureCoords {
+    // (u, v, 0)
+    uv: Point3<f32>,
+    point: Point3<f32>,
+}
+
+impl TextureCoords {
+    // Move along the surface by `du` and `dv`
+    fn step(&self, hit: &Intersection, du: f32, dv: f32) -> TextureCoords {
+        TextureCoords {
+            uv: self.uv + Vector3::new(du, dv, 0.0),
+            point: self.point + hit.object_tangent * du + hit.object_bitangent * dv,
+        }
+    }
+}
+
+impl<'a> From<&'a Intersection> for TextureCoords {
+    fn from(hit: &'a Intersection) -> TextureCoords {
+        TextureCoords {
+            uv: Point3::new(hit.u_value, hit.v_value, 0.0),
+            point: hit.object_point,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Texture {
+    // The same color everywhere
+    pub fn solid(color: &Color) -> Texture {
+        Texture::new(TextureKind::Solid(*color))
+    }
+
+    // A width x height image, `rgba` holds 4 bytes per pixel with rows from top to bottom.
+    // Alpha is ignored
+    pub fn image(width: u32, height: u32, rgba: &[u8]) -> Result<Texture, JsValue> {
+        Texture::from_rgba(width, height, rgba).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Decode the contents of a PNG or JPEG file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Texture, JsValue> {
+        Texture::decode(bytes).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Use the pixels of a canvas, from getImageData
+    pub fn from_image_data(data: &ImageData) -> Result<Texture, JsValue> {
+        Texture::image(data.width(), data.height(), &data.data())
+    }
+
+    // A checkerboard of `even` and `odd` squares, with `scale` squares across the texture
+    pub fn checker(even: &Color, odd: &Color, scale: f32) -> Texture {
+        let mut texture = Texture::pattern(Pattern::Checker, TextureSpace::Uv, even, odd);
+        texture.set_scale(scale, scale, scale);
+        texture
+    }
+
+    // Upright bands of `a` and `b`, one unit wide
+    pub fn stripes(a: &Color, b: &Color) -> Texture {
+        Texture::pattern(Pattern::Stripes, TextureSpace::Uv, a, b)
+    }
+
+    // Fades from `a` on the left of the texture to `b` on the right
+    pub fn gradient(a: &Color, b: &Color) -> Texture {
+        Texture::pattern(Pattern::Gradient, TextureSpace::Uv, a, b)
+    }
+
+    // Smooth blobs of `a` and `b`, about one unit across
+    pub fn noise(a: &Color, b: &Color) -> Texture {
+        Texture::pattern(Pattern::Noise, TextureSpace::Object, a, b)
+    }
+
+    // Noise with finer and finer detail added by each octave
+    pub fn fbm(a: &Color, b: &Color, octaves: u32) -> Texture {
+        Texture::pattern(Pattern::Fbm(octaves), TextureSpace::Object, a, b)
+    }
+
+    // Billowy noise with sharp creases of `a`
+    pub fn turbulence(a: &Color, b: &Color, octaves: u32) -> Texture {
+        Texture::pattern(Pattern::Turbulence(octaves), TextureSpace::Object, a, b)
+    }
+
+    // Veins of `b` running through `a`
+    pub fn marble(a: &Color, b: &Color) -> Texture {
+        Texture::pattern(Pattern::Marble, TextureSpace::Object, a, b)
+    }
+
+    // Growth rings around the y axis, fading from `a` to `b` across each one
+    pub fn wood(a: &Color, b: &Color) -> Texture {
+        Texture::pattern(Pattern::Wood, TextureSpace::Object, a, b)
+    }
+
+    // `a` and `b` mixed evenly everywhere, an amount of 0 is all `a` and 1 all `b`
+    pub fn blend(a: &Texture, b: &Texture, amount: f32) -> Texture {
+        let amount = Texture::solid(&Color::new(amount, amount, amount));
+        Texture::masked(a, b, &amount)
+    }
+
+    // `a` where `mask` is dark and `b` where it is bright, like a checker between two images
+    pub fn masked(a: &Texture, b: &Texture, mask: &Texture) -> Texture {
+        Texture::new(TextureKind::Mix {
+            a: Arc::new(a.clone()),
+            b: Arc::new(b.clone()),
+            mask: Arc::new(mask.clone()),
+        })
+    }
+
+    // Stretch the coordinates, larger scales repeat the texture more often
+    pub fn set_scale(&mut self, x: f32, y: f32, z: f32) {
+        self.scale = Vector3::new(x, y, z);
+    }
+
+    // Slide the texture, the offset is added to the coordinates after scaling them
+    pub fn set_offset(&mut self, x: f32, y: f32, z: f32) {
+        self.offset = Vector3::new(x, y, z);
+    }
+
+    // Set the coordinates a procedural texture follows, other textures ignore this
+    pub fn set_space(&mut self, space: TextureSpace) {
+        if let TextureKind::Pattern { space: s, .. } = &mut self.kind {
+            *s = space;
+        }
+    }
+
+    // Set what happens past the edges of an image, other textures ignore this
+    pub fn set_wrap(&mut self, mode: WrapMode) {
+        if let TextureKind::Image { wrap, .. } = &mut self.kind {
+            *wrap = mode;
+        }
+    }
+
+    // Set how an image is blended between its pixels, other textures ignore this
+    pub fn set_filter(&mut self, mode: TextureFilter) {
+        if let TextureKind::Image { filter, .. } = &mut self.kind {
+            *filter = mode;
+        }
+    }
+}
+
+impl Texture {
+    pub fn from_rgba(width: u32, height: u32, rgba: &[u8]) -> Result<Texture, String> {
+        Image::from_rgba(width, height, rgba).map(Texture::from_image)
+    }
+
+    // Decode the contents of a PNG or JPEG file
+    pub fn decode(bytes: &[u8]) -> Result<Texture, String> {
+        Image::decode(bytes).map(Texture::from_image)
+    }
+
+    // Read and decode a PNG or JPEG file
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(path: &str) -> Result<Texture, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("couldn't read texture '{}': {}", path, e))?;
+        let mut image = Image::decode(&bytes).map_err(|e| format!("texture '{}': {}", path, e))?;
+        image.path = Some(path.to_string());
+        Ok(Texture::from_image(image))
+    }
+
+    fn new(kind: TextureKind) -> Texture {
+        Texture {
+            kind,
+            scale: Vector3::new(1.0, 1.0, 1.0),
+            offset: Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    fn from_image(image: Image) -> Texture {
+        Texture::new(TextureKind::Image {
+            image: Arc::new(image),
+            wrap: WrapMode::default(),
+            filter: TextureFilter::default(),
+        })
+    }
+
+    fn pattern(pattern: Pattern, space: TextureSpace, a: &Color, b: &Color) -> Texture {
+        Texture::new(TextureKind::Pattern {
+            pattern,
+            space,
+            a: *a,
+            b: *b,
+        })
+    }
+
+    pub(crate) fn sample(&self, coords: &TextureCoords) -> Color {
+        let coords = TextureCoords {
+            uv: Point3::from(coords.uv.coords.component_mul(&self.scale) + self.offset),
+            point: Point3::from(coords.point.coords.component_mul(&self.scale) + self.offset),
+        };
+        match &self.kind {
+            TextureKind::Solid(color) => *color,
+            TextureKind::Image { image, wrap, filter } => image.sample(coords.uv.x, coords.uv.y, *wrap, *filter),
+            TextureKind::Pattern { pattern, space, a, b } => {
+                let p = match space {
+                    TextureSpace::Uv => coords.uv,
+                    TextureSpace::Object => coords.point,
+                };
+                mix(*a, *b, pattern.value(&p))
+            }
+            TextureKind::Mix { a, b, mask } => {
+                mix(a.sample(&coords), b.sample(&coords), brightness(&mask.sample(&coords)))
+            }
+        }
+    }
+}
+
+impl Pattern {
+    fn value(&self, p: &Point3<f32>) -> f32 {
+        match self {
+            Pattern::Checker => {
+                let cell = p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+                cell.rem_euclid(2) as f32
+            }
+            Pattern::Stripes => (p.x.floor() as i64).rem_euclid(2) as f32,
+            Pattern::Gradient => p.x.clamp(0.0, 1.0),
+            Pattern::Noise => (0.5 + 0.5 * perlin(p)).clamp(0.0, 1.0),
+            Pattern::Fbm(octaves) => (0.5 + 0.5 * fbm(p, *octaves)).clamp(0.0, 1.0),
+            Pattern::Turbulence(octaves) => turbulence(p, *octaves).clamp(0.0, 1.0),
+            Pattern::Marble => {
+                let x = p.x + MARBLE_DISTORTION * turbulence(p, MARBLE_OCTAVES);
+                0.5 + 0.5 * (x * f32::consts::PI).sin()
+            }
+            Pattern::Wood => {
+                let r = (p.x * p.x + p.z * p.z).sqrt() + WOOD_GRAIN * fbm(p, WOOD_OCTAVES);
+                r - r.floor()
+            }
+        }
+    }
+}
+
+fn mix(a: Color, b: Color, t: f32) -> Color {
+    let a: Vector3<f32> = a.into();
+    let b: Vector3<f32> = b.into();
+    Color::from(a * (1.0 - t) + b * t)
+}
+
+// Average of the channels, for textures that stand in for a single number
+fn brightness(c: &Color) -> f32 {
+    (c.r + c.g + c.b) / 3.0
+}
+
+// Textures a material's channels come from, in place of its constant values
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MaterialTextures {
+    pub(crate) diffuse: Option<Texture>,
+    pub(crate) specular: Option<Texture>,
+    pub(crate) reflectivity: Option<Texture>,
+    pub(crate) metallic: Option<Texture>,
+    pub(crate) roughness: Option<Texture>,
+    // A height map and how high white is above black, in world units
+    pub(crate) bump: Option<(Texture, f32)>,
+    // Tangent space normals, stored as colors
+    pub(crate) normal: Option<Texture>,
+    // Multiplies the emission, so only parts of the surface glow
+    pub(crate) emission: Option<Texture>,
+}
+
+impl MaterialTextures {
+    // The material as it is at a hit. Diffuse textures replace kd or the base color, specular
+    // ones ks, and the brightness of metallic and roughness textures is used for those
+    pub(crate) fn apply(&self, material: Material, hit: &Intersection) -> Material {
+        let coords = TextureCoords::from(hit);
+        let color = |texture: &Option<Texture>, value: Color| texture.as_ref().map_or(value, |t| t.sample(&coords));
+        let scalar = |texture: &Option<Texture>, value: f32| {
+            texture.as_ref().map_or(value, |t| brightness(&t.sample(&coords)))
+        };
+        match material {
+            Material::PhongMaterial {
+                kd,
+                ks,
+                shininess,
+                reflectivity,
+            } => Material::PhongMaterial {
+                kd: color(&self.diffuse, kd),
+                ks: color(&self.specular, ks),
+                shininess,
+                reflectivity: color(&self.reflectivity, reflectivity),
+            },
+            Material::Pbr {
+                base_color,
+                metallic,
+                roughness,
+            } => Material::Pbr {
+                base_color: color(&self.diffuse, base_color),
+                metallic: scalar(&self.metallic, metallic),
+                roughness: scalar(&self.roughness, roughness),
+            },
+            Material::Dielectric { .. } | Material::None => material,
+        }
+    }
+
+    // Light given off at a hit, the emission times the emission texture
+    pub(crate) fn emission(&self, emission: Color, hit: &Intersection) -> Color {
+        match &self.emission {
+            Some(texture) => {
+                let c = texture.sample(&TextureCoords::from(hit));
+                Color::new(emission.r * c.r, emission.g * c.g, emission.b * c.b)
+            }
+            None => emission,
+        }
+    }
+
+    // The normal to shade a hit with, bent by the normal and bump maps. Left as is when there
+    // are neither
+    pub(crate) fn shading_normal(&self, hit: &Intersection) -> Vector3<f32> {
+        let coords = TextureCoords::from(hit);
+        let mut n = hit.normal.normalize();
+
+        if let Some(texture) = &self.normal {
+            // Colors in [0, 1] hold directions in [-1, 1], blue points away from the surface
+            let c: Vector3<f32> = texture.sample(&coords).into();
+            let m = c * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+            let t = (hit.tangent - n * n.dot(&hit.tangent)).normalize();
+            let mut b = n.cross(&t);
+            if b.dot(&hit.bitangent) < 0.0 {
+                b = -b;
+            }
+            let bent = t * m.x + b * m.y + n * m.z;
+            if bent.norm() > 0.0 {
+                n = bent.normalize();
+            }
+        }
+
+        if let Some((texture, strength)) = &self.bump {
+            let height = |c: &TextureCoords| brightness(&texture.sample(c)) * strength;
+            let h = height(&coords);
+            let dh_du = (height(&coords.step(hit, BUMP_DELTA, 0.0)) - h) / BUMP_DELTA;
+            let dh_dv = (height(&coords.step(hit, 0.0, BUMP_DELTA)) - h) / BUMP_DELTA;
+            // Raise the surface along the normal and take the normal of the raised surface
+            let dp_du = hit.tangent + n * dh_du;
+            let dp_dv = hit.bitangent + n * dh_dv;
+            let bumped = dp_du.cross(&dp_dv);
+            if bumped.norm() > 0.0 {
+                let bumped = bumped.normalize();
+                n = if bumped.dot(&n) < 0.0 { -bumped } else { bumped };
+            }
+        }
+        n
+    }
+}
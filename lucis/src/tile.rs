@@ -0,0 +1,373 @@
+use crate::framebuffer::Framebuffer;
+use crate::scene::Color;
+use crate::Raytracer;
+use std::convert::TryFrom;
+use wasm_bindgen::prelude::*;
+use web_sys::HtmlCanvasElement;
+
+// The order tiles are handed out in during a tiled render
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileOrder {
+    // Left to right, top to bottom
+    Scanline,
+    // Outwards from the center of the image, so the subject shows up first
+    Spiral,
+    // Along a Hilbert curve, keeping consecutive tiles close together
+    Hilbert,
+}
+
+// A rectangular region of the image, in pixels
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[wasm_bindgen]
+impl Tile {
+    #[wasm_bindgen(constructor)]
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Tile {
+        Tile {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+// Split a width x height image into tiles of at most tile_size x tile_size pixels
+pub fn generate_tiles(width: u32, height: u32, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let cols = width.div_ceil(tile_size);
+    let rows = height.div_ceil(tile_size);
+
+    let cells = match order {
+        TileOrder::Scanline => scanline_order(cols, rows),
+        TileOrder::Spiral => spiral_order(cols, rows),
+        TileOrder::Hilbert => hilbert_order(cols, rows),
+    };
+
+    cells
+        .into_iter()
+        .map(|(col, row)| {
+            let x = col * tile_size;
+            let y = row * tile_size;
+            Tile::new(x, y, tile_size.min(width - x), tile_size.min(height - y))
+        })
+        .collect()
+}
+
+fn scanline_order(cols: u32, rows: u32) -> Vec<(u32, u32)> {
+    (0..rows)
+        .flat_map(|row| (0..cols).map(move |col| (col, row)))
+        .collect()
+}
+
+fn spiral_order(cols: u32, rows: u32) -> Vec<(u32, u32)> {
+    let total = cols as usize * rows as usize;
+    let mut cells = Vec::with_capacity(total);
+    let (mut x, mut y) = (((cols as i64) - 1) / 2, ((rows as i64) - 1) / 2);
+    // Walk right, down, left, up with run lengths 1, 1, 2, 2, 3, 3, ...
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut run = 1;
+    let mut d = 0;
+    while cells.len() < total {
+        for _ in 0..2 {
+            let (dx, dy) = directions[d % 4];
+            for _ in 0..run {
+                if x >= 0 && y >= 0 && x < cols as i64 && y < rows as i64 {
+                    cells.push((x as u32, y as u32));
+                }
+                x += dx;
+                y += dy;
+            }
+            d += 1;
+        }
+        run += 1;
+    }
+    cells
+}
+
+fn hilbert_order(cols: u32, rows: u32) -> Vec<(u32, u32)> {
+    let n = cols.max(rows).max(1).next_power_of_two();
+    (0..n as u64 * n as u64)
+        .map(|d| hilbert_d2xy(n, d))
+        .filter(|&(x, y)| x < cols && y < rows)
+        .collect()
+}
+
+// Convert a distance along a Hilbert curve filling an n x n grid into grid coordinates
+fn hilbert_d2xy(n: u32, d: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = (1 & (t / 2)) as u32;
+        let ry = (1 & (t ^ rx as u64)) as u32;
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+// A render in progress, traced one tile at a time so callers can show partial results.
+// Tiles can also be handed out with `take_tile`, traced elsewhere (e.g. on a worker)
+// with `trace_tile` and handed back with `complete_tile`.
+#[wasm_bindgen]
+pub struct TiledRender {
+    raytracer: Raytracer,
+    tiles: Vec<Tile>,
+    // Index of the next tile to be handed out
+    next_tile: usize,
+    // Pass the next tile belongs to, the tiles are handed out again for every pass
+    pass: u32,
+    passes: u32,
+    // Number of tiles that have been written into the framebuffer, over every pass
+    tiles_done: usize,
+    // Running sum of the unclamped RGB radiance of every pass and the number of passes
+    // summed, for each pixel
+    accumulation: Vec<f32>,
+    pixel_passes: Vec<u32>,
+    framebuffer: Framebuffer,
+}
+
+impl TiledRender {
+    pub fn new(
+        raytracer: Raytracer,
+        width: u32,
+        height: u32,
+        tile_size: u32,
+        order: TileOrder,
+    ) -> Result<TiledRender, String> {
+        let framebuffer = Framebuffer::new(width, height)?;
+        // Can't overflow, the framebuffer already holds four bytes for each pixel
+        let pixel_count = width as usize * height as usize;
+        Ok(TiledRender {
+            tiles: generate_tiles(width, height, tile_size, order),
+            next_tile: 0,
+            pass: 0,
+            passes: raytracer.passes,
+            tiles_done: 0,
+            accumulation: vec![0.0; pixel_count * 3],
+            pixel_passes: vec![0; pixel_count],
+            framebuffer,
+            raytracer,
+        })
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    pub fn as_framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    // Add the radiance of a tile traced elsewhere, after checking it covers part of the image
+    // and has a color for each of its pixels
+    pub(crate) fn add_tile(&mut self, tile: &Tile, radiance: &[f32]) -> Result<(), String> {
+        self.check_tile(tile)?;
+        let expected = 3 * tile.width as usize * tile.height as usize;
+        if radiance.len() != expected {
+            return Err(format!(
+                "a {}x{} tile needs {} radiance values, got {}",
+                tile.width,
+                tile.height,
+                expected,
+                radiance.len()
+            ));
+        }
+        self.accumulate(tile, radiance);
+        Ok(())
+    }
+
+    // Add the radiance of a traced tile to the running average and show the new average.
+    // Only the framebuffer is clamped, so bright samples still count fully in the average
+    fn accumulate(&mut self, tile: &Tile, radiance: &[f32]) {
+        let width = self.framebuffer.width() as usize;
+        for y in 0..tile.height {
+            for x in 0..tile.width {
+                let pixel = (tile.y + y) as usize * width + (tile.x + x) as usize;
+                let offset = (y as usize * tile.width as usize + x as usize) * 3;
+                self.pixel_passes[pixel] += 1;
+                let passes = self.pixel_passes[pixel] as f32;
+                let sum = &mut self.accumulation[pixel * 3..pixel * 3 + 3];
+                for (s, r) in sum.iter_mut().zip(&radiance[offset..offset + 3]) {
+                    *s += r;
+                }
+                let average = Color::new(sum[0] / passes, sum[1] / passes, sum[2] / passes);
+                self.framebuffer.set_pixel(tile.x + x, tile.y + y, average);
+            }
+        }
+        self.tiles_done += 1;
+    }
+}
+
+#[wasm_bindgen]
+impl TiledRender {
+    // Render the next tile and return it, or None once every tile has been handed out
+    pub fn step(&mut self) -> Option<Tile> {
+        let tile = self.take_tile()?;
+        let radiance = self.raytracer.trace_tile(&tile, self.framebuffer.width(), self.framebuffer.height());
+        self.accumulate(&tile, &radiance);
+        Some(tile)
+    }
+
+    // Hand out the next tile without rendering it
+    pub fn take_tile(&mut self) -> Option<Tile> {
+        if self.next_tile >= self.tiles.len() && self.pass + 1 < self.passes {
+            self.pass += 1;
+            self.next_tile = 0;
+        }
+        let tile = *self.tiles.get(self.next_tile)?;
+        self.next_tile += 1;
+        Some(tile)
+    }
+
+    // Trace a tile and return the RGB radiance of its pixels, without touching this render's
+    // framebuffer. This shows up in JS as a Float32Array. Tiles that don't fit the image are
+    // refused
+    pub fn trace_tile(&self, tile: &Tile) -> Result<Vec<f32>, JsValue> {
+        self.trace(tile).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Trace a tile after checking it covers part of the image
+    pub(crate) fn trace(&self, tile: &Tile) -> Result<Vec<f32>, String> {
+        self.check_tile(tile)?;
+        Ok(self
+            .raytracer
+            .trace_tile(tile, self.framebuffer.width(), self.framebuffer.height()))
+    }
+
+    fn check_tile(&self, tile: &Tile) -> Result<(), String> {
+        let (width, height) = (self.framebuffer.width(), self.framebuffer.height());
+        let inside = |start: u32, len: u32, end: u32| start.checked_add(len).is_some_and(|e| e <= end);
+        if !inside(tile.x, tile.width, width) || !inside(tile.y, tile.height, height) {
+            return Err(format!(
+                "a {}x{} tile at ({}, {}) doesn't fit in the {}x{} image",
+                tile.width, tile.height, tile.x, tile.y, width, height
+            ));
+        }
+        Ok(())
+    }
+
+    // Add the radiance of a tile traced with `trace_tile` into the framebuffer. Tiles that
+    // don't fit the image, e.g. from a misbehaving worker, are refused
+    pub fn complete_tile(&mut self, tile: &Tile, radiance: Vec<f32>) -> Result<(), JsValue> {
+        self.add_tile(tile, &radiance).map_err(|e| JsValue::from_str(&e))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.tiles_done >= self.total_tiles()
+    }
+
+    fn total_tiles(&self) -> usize {
+        self.tiles.len() * self.passes as usize
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn tiles_done(&self) -> u32 {
+        u32::try_from(self.tiles_done).unwrap_or(u32::MAX)
+    }
+
+    // Number of tiles to trace over every pass
+    #[wasm_bindgen(getter)]
+    pub fn tile_count(&self) -> u32 {
+        u32::try_from(self.total_tiles()).unwrap_or(u32::MAX)
+    }
+
+    // The pass tiles are being handed out for, counting from 0
+    #[wasm_bindgen(getter)]
+    pub fn pass(&self) -> u32 {
+        self.pass
+    }
+
+    // Fraction of tiles rendered so far, between 0 and 1
+    pub fn progress(&self) -> f32 {
+        if self.tiles.is_empty() {
+            1.0
+        } else {
+            self.tiles_done as f32 / self.total_tiles() as f32
+        }
+    }
+
+    // Copy of the image rendered so far, tiles that are not done yet are black
+    pub fn framebuffer(&self) -> Framebuffer {
+        self.framebuffer.clone()
+    }
+
+    pub fn present(&self, canvas: &HtmlCanvasElement) -> Result<(), JsValue> {
+        self.framebuffer.present(canvas)
+    }
+
+    pub fn present_to(&self, canvas_id: &str) -> Result<(), JsValue> {
+        self.framebuffer.present_to(canvas_id)
+    }
+
+    // Render any remaining tiles and return the finished image
+    pub fn finish(mut self) -> Framebuffer {
+        while self.step().is_some() {}
+        self.framebuffer
+    }
+}
+
+impl Iterator for TiledRender {
+    type Item = Tile;
+
+    fn next(&mut self) -> Option<Tile> {
+        self.step()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Primitive;
+    use crate::scene::Scene;
+
+    fn render() -> TiledRender {
+        let mut scene = Scene::new();
+        scene.add_node(Primitive::None, "root".to_string());
+        TiledRender::new(Raytracer::new(scene), 4, 4, 2, TileOrder::Scanline).unwrap()
+    }
+
+    #[test]
+    fn adds_tiles_inside_the_image() {
+        let mut render = render();
+        assert!(render.add_tile(&Tile::new(2, 2, 2, 2), &[0.5; 12]).is_ok());
+        assert_eq!(render.tiles_done(), 1);
+        assert_eq!(render.as_framebuffer().as_bytes()[4 * 10], 128);
+    }
+
+    #[test]
+    fn refuses_tiles_outside_the_image() {
+        let mut render = render();
+        assert!(render.add_tile(&Tile::new(3, 0, 2, 2), &[0.0; 12]).is_err());
+        assert!(render.add_tile(&Tile::new(0, u32::MAX, 1, 2), &[0.0; 6]).is_err());
+        assert_eq!(render.tiles_done(), 0);
+        assert!(render.trace(&Tile::new(3, 0, 2, 2)).is_err());
+        assert!(render.trace(&Tile::new(0, u32::MAX, 1, 2)).is_err());
+        assert_eq!(render.trace(&Tile::new(2, 2, 2, 2)).unwrap().len(), 12);
+    }
+
+    #[test]
+    fn refuses_tiles_with_the_wrong_amount_of_radiance() {
+        let mut render = render();
+        assert!(render.add_tile(&Tile::new(0, 0, 2, 2), &[0.0; 11]).is_err());
+        assert_eq!(render.tiles_done(), 0);
+    }
+}